@@ -0,0 +1,123 @@
+use std::f64::consts::PI;
+
+use crate::{format_c67::PCM_MIDDLE_C_RATE, song::{FMPatch, Sample}};
+
+/// The five OPL rhythm mode instruments, as S3M AdLib instrument types 3-7
+/// and channel settings 25-29.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Drum {
+    Bass,
+    Snare,
+    Tom,
+    Cymbal,
+    HiHat,
+}
+
+impl Drum {
+    pub fn from_instrument_type(instrument_type: u8) -> Option<Self> {
+        match instrument_type {
+            3 => Some(Drum::Bass),
+            4 => Some(Drum::Snare),
+            5 => Some(Drum::Tom),
+            6 => Some(Drum::Cymbal),
+            7 => Some(Drum::HiHat),
+            _ => None,
+        }
+    }
+}
+
+/// Registers of a modulator that turns a carrier into noise: the highest
+/// frequency multiplier, full output and maximum feedback.
+const NOISE_MODULATOR: (u8, u8, u8, u8, u8) = (0x0F, 0x00, 0xF0, 0x0F, 0x00);
+
+/// Rebuilds a drum as a two operator melodic patch CDFM can play on any FM
+/// voice. The bass drum already is one. The other drums only sound one
+/// operator in rhythm mode, which becomes the carrier: the hi-hat and tom
+/// live in modulator slots, the snare and cymbal in carrier slots. The hi-hat,
+/// snare and cymbal mix in noise, imitated with a noisy modulator, while the
+/// tom is a pure tone.
+pub fn melodic_patch(instrument: &FMPatch, drum: Drum) -> FMPatch {
+    let mut patch = instrument.clone();
+    patch.drum = None;
+    if drum == Drum::Bass {
+        return patch;
+    }
+
+    let registers = &mut patch.registers;
+    if matches!(drum, Drum::Tom | Drum::HiHat) {
+        for register in (0..10).step_by(2) {
+            registers[register+1] = instrument.registers[register];
+        }
+    }
+
+    let modulator = if drum == Drum::Tom {
+        // Modulator at the lowest output level
+        (0x00, 0x3F, 0xF0, 0x0F, 0x00)
+    } else {
+        NOISE_MODULATOR
+    };
+    (registers[0], registers[2], registers[4], registers[6], registers[8]) = modulator;
+    registers[10] = if drum == Drum::Tom { 0 } else { 0x0E };
+
+    patch
+}
+
+/// Time an OPL envelope takes to fall through all 96dB at each rate, in seconds.
+const DECAY_TIMES: [f64;16] = [
+    f64::INFINITY, 39.28, 19.64, 9.82, 4.91, 2.455, 1.227, 0.614,
+    0.307, 0.153, 0.0767, 0.0384, 0.0192, 0.0096, 0.0048, 0.0024,
+];
+
+/// How long a melodic patch sounds after key on, in seconds. Sustaining
+/// envelopes hold until the next note.
+pub fn patch_duration(patch: &FMPatch) -> f64 {
+    let registers = &patch.registers;
+    if registers[1] & 0x20 != 0 {
+        return f64::INFINITY;
+    }
+
+    // Decay runs down to the sustain level in 3dB steps, release the rest of the way
+    let sustain_level = (registers[7] >> 4) as f64 * 3.0 / 96.0;
+    DECAY_TIMES[(registers[5] & 0xF) as usize] * sustain_level
+        + DECAY_TIMES[(registers[7] & 0xF) as usize] * (1.0 - sustain_level)
+}
+
+/// A sample standing in for a drum when no FM voice is free. It plays its
+/// intended pitch at C-4, at CDFM's middle C rate.
+pub fn render_sample(instrument: &FMPatch, drum: Drum) -> Sample {
+    let rate = PCM_MIDDLE_C_RATE as f64;
+    // (start and end frequency in Hz, share of noise, decay time constant in seconds)
+    let (start, end, noise, decay) = match drum {
+        Drum::Bass => (150.0, 50.0, 0.0, 0.12),
+        Drum::Snare => (200.0, 180.0, 0.7, 0.07),
+        Drum::Tom => (130.0, 100.0, 0.0, 0.12),
+        Drum::Cymbal => (400.0, 400.0, 0.9, 0.3),
+        Drum::HiHat => (800.0, 800.0, 0.95, 0.03),
+    };
+
+    // Long enough to fade below 8-bit resolution
+    let length = (decay * 6.0 * rate) as usize;
+    let mut seed = 0x1234_5678u32;
+    let mut phase = 0.0;
+    let audio = (0..length)
+        .map(|index| {
+            let time = index as f64 / rate;
+            let frequency = end + (start - end) * (-time / 0.04).exp();
+            phase += 2.0 * PI * frequency / rate;
+
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            let white = (seed >> 16) as f64 / 32768.0 - 1.0;
+
+            let value = (phase.sin() * (1.0 - noise) + white * noise) * (-time / decay).exp();
+            (value * 30000.0) as i16
+        })
+        .collect::<Vec<i16>>();
+
+    Sample {
+        filename: instrument.filename,
+        volume: instrument.volume,
+        c4speed: PCM_MIDDLE_C_RATE,
+        audio,
+        sample_loop: None,
+    }
+}
@@ -0,0 +1,169 @@
+use std::{collections::HashMap, fs, io, path::{Path, PathBuf}, sync::atomic::{AtomicUsize, Ordering}, thread};
+
+use s3m2c67::{convert, diagnostics::{Category, Diagnostics, Severity}, load, InputFormat};
+
+use crate::{capture_warnings, convert_options, Arguments};
+
+/// What became of one song, as written to the summary. The dropped
+/// channels and instruments are counted from 1, like trackers show them,
+/// the diagnostics from 0.
+#[derive(Debug, serde::Serialize)]
+pub struct FileSummary {
+    pub input: PathBuf,
+    pub output: PathBuf,
+    pub success: bool,
+    pub error: Option<String>,
+    pub dropped_notes: usize,
+    pub dropped_channels: Vec<usize>,
+    pub dropped_instruments: Vec<usize>,
+    /// Problems loading the song.
+    pub warnings: Vec<String>,
+    /// What the conversion lost.
+    pub diagnostics: Diagnostics,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct BatchSummary {
+    pub converted: usize,
+    pub failed: usize,
+    pub files: Vec<FileSummary>,
+}
+
+/// Songs in a supported format anywhere below `directory`, in a stable order.
+pub fn find_songs(directory: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut songs: Vec<PathBuf> = Vec::new();
+    let mut directories = vec![directory.to_path_buf()];
+    while let Some(directory) = directories.pop() {
+        for entry in fs::read_dir(&directory)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                directories.push(path);
+            } else if path.extension().and_then(|extension| extension.to_str()).and_then(InputFormat::from_extension).is_some() {
+                songs.push(path);
+            }
+        }
+    }
+    songs.sort();
+
+    Ok(songs)
+}
+
+/// Converts `songs`, found below the input directory, to the same places
+/// below `output` on `jobs` threads. Failures are recorded and the rest
+/// carry on.
+pub fn convert_tree(arguments: &Arguments, output: &Path, songs: &[PathBuf], jobs: usize) -> BatchSummary {
+    let outputs = output_paths(&arguments.input, output, songs);
+    let next = AtomicUsize::new(0);
+    let mut files: Vec<(usize, FileSummary)> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..jobs.max(1))
+            .map(|_| scope.spawn(|| {
+                let mut done: Vec<(usize, FileSummary)> = Vec::new();
+                loop {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(song) = songs.get(index) else {
+                        break;
+                    };
+                    done.push((index, convert_file(arguments, song, &outputs[index])));
+                }
+                done
+            }))
+            .collect();
+        workers.into_iter().flat_map(|worker| worker.join().unwrap()).collect()
+    });
+    files.sort_by_key(|(index, _)| *index);
+
+    let files: Vec<FileSummary> = files.into_iter().map(|(_, file)| file).collect();
+    BatchSummary {
+        converted: files.iter().filter(|file| file.success).count(),
+        failed: files.iter().filter(|file| !file.success).count(),
+        files,
+    }
+}
+
+/// Where each of `songs`, found below `input`, is written below `output`.
+/// Songs that would land on the same C67, such as song.s3m and song.mod,
+/// keep their own extension in front of it instead.
+fn output_paths(input: &Path, output: &Path, songs: &[PathBuf]) -> Vec<PathBuf> {
+    let mirrored = |song: &PathBuf| output.join(song.strip_prefix(input).unwrap_or(song));
+    let mut paths: Vec<PathBuf> = songs.iter().map(|song| mirrored(song).with_extension("c67")).collect();
+    let mut kept_extension = vec![false; songs.len()];
+    loop {
+        let mut counts: HashMap<&Path, usize> = HashMap::new();
+        for path in &paths {
+            *counts.entry(path).or_default() += 1;
+        }
+        let colliding: Vec<usize> = (0..songs.len())
+            .filter(|index| !kept_extension[*index] && counts[paths[*index].as_path()] > 1)
+            .collect();
+        if colliding.is_empty() {
+            return paths;
+        }
+
+        for index in colliding {
+            let mut path = mirrored(&songs[index]).into_os_string();
+            path.push(".c67");
+            paths[index] = path.into();
+            kept_extension[index] = true;
+        }
+    }
+}
+
+fn convert_file(arguments: &Arguments, input: &Path, output: &Path) -> FileSummary {
+    let (result, warnings) = capture_warnings(|| -> Result<_, String> {
+        let format = InputFormat::from_path(input);
+        let file = fs::File::open(input).map_err(|error| error.to_string())?;
+        let module = load(file, format).map_err(|error| error.to_string())?;
+        let (converted, report) = convert(&module, &convert_options(arguments, format));
+
+        if let Some(directory) = output.parent() {
+            fs::create_dir_all(directory).map_err(|error| error.to_string())?;
+        }
+        fs::write(output, converted.serialize()).map_err(|error| error.to_string())?;
+        Ok(report)
+    });
+
+    // Printed rather than logged, the capture already took them in once
+    if log::log_enabled!(log::Level::Warn) {
+        for warning in &warnings {
+            eprintln!("warning: {}: {}", input.display(), warning);
+        }
+    }
+    let mut summary = FileSummary {
+        input: input.to_path_buf(),
+        output: output.to_path_buf(),
+        success: result.is_ok(),
+        error: None,
+        dropped_notes: 0,
+        dropped_channels: Vec::new(),
+        dropped_instruments: Vec::new(),
+        warnings,
+        diagnostics: Diagnostics::default(),
+    };
+    match result {
+        Ok(report) => {
+            log::info!("{}: converted to {}", input.display(), output.display());
+            let diagnostics = report.diagnostics;
+            summary.dropped_notes = diagnostics.count(Category::DroppedNote);
+            summary.dropped_channels = diagnostics.of(Category::DroppedChannel)
+                .filter_map(|diagnostic| diagnostic.channel)
+                .map(|channel| channel + 1)
+                .collect();
+            // Drums without a PCM copy still play as long as there are FM voices
+            summary.dropped_instruments = diagnostics.of(Category::DroppedInstrument)
+                .filter(|diagnostic| diagnostic.severity == Severity::Error)
+                .filter_map(|diagnostic| diagnostic.instrument)
+                .map(|instrument| instrument + 1)
+                .collect();
+            for diagnostic in &diagnostics {
+                log::debug!("{}: {}", input.display(), diagnostic);
+            }
+            summary.diagnostics = diagnostics;
+        },
+        Err(error) => {
+            log::error!("{}: {}", input.display(), error);
+            summary.error = Some(error);
+        },
+    }
+
+    summary
+}
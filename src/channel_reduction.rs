@@ -0,0 +1,78 @@
+use crate::{diagnostics::{Category, Diagnostic, Diagnostics, Severity}, flow::walk_orders, format_s3m::S3MModule};
+
+/// C67's PCM voices.
+const PCM_VOICES: usize = 4;
+
+/// Which sample channels of a module with more than C67's 4 get to play.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum ChannelSelection {
+    /// The channels playing the most notes.
+    #[default]
+    NoteDensity,
+    /// These channels (0-31), in any order.
+    Manual(Vec<usize>),
+}
+
+/// Mutes all sample channels but at most 4, picked by `selection`, and
+/// returns the channels left playing in channel order, along with what was
+/// wrong with the selection. AdLib channels are left alone. Without a
+/// reduction the voice allocator shares the 4 voices out note by note, which
+/// suits songs using few channels at a time but garbles dense ones.
+pub fn reduce_channels(module: &mut S3MModule, selection: &ChannelSelection) -> (Vec<usize>, Diagnostics) {
+    let mut diagnostics = Diagnostics::default();
+    let pcm_channels: Vec<usize> = (0..32)
+        .filter(|channel| module.channel_settings[*channel] < 16)
+        .collect();
+
+    let mut kept: Vec<usize> = match selection {
+        ChannelSelection::NoteDensity => {
+            let counts = note_counts(module);
+            let mut ranked = pcm_channels.clone();
+            // Stable, so ties go to earlier channels
+            ranked.sort_by_key(|channel| std::cmp::Reverse(counts[*channel]));
+            ranked.truncate(PCM_VOICES);
+            ranked
+        },
+        ChannelSelection::Manual(channels) => {
+            for channel in channels.iter().filter(|channel| !pcm_channels.contains(channel)) {
+                diagnostics.push(
+                    Diagnostic::new(Severity::Warning, Category::Option, "Not a sample channel, ignoring it").on_channel(*channel)
+                );
+            }
+            if channels.len() > PCM_VOICES {
+                diagnostics.push(Diagnostic::new(
+                    Severity::Warning,
+                    Category::Option,
+                    format!("More than {} channels chosen, notes may be dropped", PCM_VOICES),
+                ));
+            }
+            pcm_channels.iter().copied().filter(|channel| channels.contains(channel)).collect()
+        },
+    };
+    kept.sort();
+
+    for channel in pcm_channels {
+        if !kept.contains(&channel) {
+            module.channel_settings[channel] |= 0x80;
+        }
+    }
+
+    (kept, diagnostics)
+}
+
+/// Notes each channel plays over the whole song, counting repeated orders
+/// as often as they play.
+fn note_counts(module: &S3MModule) -> [usize;32] {
+    let mut counts = [0usize;32];
+    for segment in walk_orders(module).segments {
+        let pattern = &module.patterns[segment.pattern];
+        for row in &pattern[segment.rows.clone()] {
+            for (channel, col) in row.iter().enumerate() {
+                if col.note < 254 {
+                    counts[channel] += 1;
+                }
+            }
+        }
+    }
+    counts
+}
@@ -1,86 +1,121 @@
-use std::{array, collections::HashMap};
-
-use crate::{format_c67::{self, serialize_pattern, C67FMRegisters, C67Module, C67SampleMetadata, Channel, PlayNoteCommand, SetVolumeCommand}, format_s3m::{S3MAdlibInstrument, S3MInstrument, S3MModule, S3MPattern, S3MSample}};
-
-pub struct Converter<'m> {
-    module: &'m S3MModule,
-    pcm_channel_remap_table: HashMap<u8, u8>,
-    pcm_instrument_remap_table: HashMap<u8, u8>,
-    adlib_instrument_remap_table: HashMap<u8, u8>,
+use std::{array, collections::{HashMap, HashSet}};
+
+use crate::{adlib_drums::{melodic_patch, patch_duration, render_sample}, diagnostics::{Category, Diagnostic, Diagnostics, Severity}, format_c67::{self, s3m_volume, serialize_pattern, swap_key_scale_level, C67FMRegisters, Channel, FM_VOLUMES, C67Module, C67PatternCommand, C67SampleMetadata, PlayNoteCommand, SetVolumeCommand, NO_LOOP, PATTERN_ROWS, PCM_MIDDLE_C_OCTAVE, PCM_MIDDLE_C_RATE}, resample::{resample, ResampleQuality}, song::{Event, FMPatch, Instrument, Sample, Song}, timing::{TempoMap, TimingPlan, TimingReport}, voice_allocation::{VoiceAllocationOptions, VoiceAllocator, VoicePool}};
+
+pub struct Converter<'s> {
+    song: &'s Song,
+    voice_allocation: VoiceAllocationOptions,
+    resample_quality: ResampleQuality,
+    pcm_instrument_remap_table: HashMap<usize, u8>,
+    adlib_instrument_remap_table: HashMap<usize, u8>,
+    /// PCM instruments rendered from AdLib drums, for when FM voices run out
+    drum_sample_remap_table: HashMap<usize, u8>,
+    /// Instruments there was no C67 slot left for
+    diagnostics: Diagnostics,
+
+    pcm_instruments: Vec<Sample>,
+    adlib_instruments: Vec<FMPatch>
+}
 
-    pcm_instruments: Vec<S3MSample>,
-    adlib_instruments: Vec<S3MAdlibInstrument>
+impl From<&Song> for C67Module {
+    fn from(song: &Song) -> Self {
+        Converter::new(song).convert().0
+    }
 }
 
 impl<'a> Converter<'a> {
-    pub fn new(module: &'a S3MModule) -> Self {
-        // PCM channel remap table
-        let mut pcm_channel_remap_table: HashMap<u8, u8> = HashMap::new();
-        let mut pcm_channel_remap_index = 0u8;
-        for (index, channel_setting) in module.channel_settings.iter().enumerate() {
-            if channel_setting & 0x80 != 1 {
-                // Ignore muted channels
-                continue;
-            }
-
-            if channel_setting & 0x7F <= 15 {
-                pcm_channel_remap_table.insert(index as u8, pcm_channel_remap_index);
-                pcm_channel_remap_index += 1;
-            }
-
-            if pcm_channel_remap_index >= 4 {
-                println!("More than 4 PCM channels detected, discarding the rest");
-                break;
-            }
-        }
-
+    pub fn new(song: &'a Song) -> Self {
         // PCM+AdLib instrument remap table
-        let mut pcm_instrument_remap_table: HashMap<u8, u8> = HashMap::new();
+        let mut pcm_instrument_remap_table: HashMap<usize, u8> = HashMap::new();
         let mut pcm_instrument_remap_index = 0u8;
-        let mut adlib_instrument_remap_table: HashMap<u8, u8> = HashMap::new();
+        let mut adlib_instrument_remap_table: HashMap<usize, u8> = HashMap::new();
         let mut adlib_instrument_remap_index = 0u8;
-        let mut pcm_instruments: Vec<S3MSample> = Vec::new();
-        let mut adlib_instruments: Vec<S3MAdlibInstrument> = Vec::new();
-        for (index, instrument) in module.instruments.iter().enumerate() {
-            if pcm_instrument_remap_index >= 32 {
-                println!("More than 32 PCM instruments detected, discarding");
-                continue;
-            }
-
-            if adlib_instrument_remap_index >= 32 {
-                println!("More than 32 AdLib instruments detected, discarding");
-                continue;
-            }
-
+        let mut pcm_instruments: Vec<Sample> = Vec::new();
+        let mut adlib_instruments: Vec<FMPatch> = Vec::new();
+        let mut diagnostics = Diagnostics::default();
+        for (index, instrument) in song.instruments.iter().enumerate() {
             match instrument {
-                S3MInstrument::Sample(sample) => {
+                Instrument::Sample(_) if pcm_instrument_remap_index >= 32 => {
+                    diagnostics.push(
+                        Diagnostic::new(Severity::Error, Category::DroppedInstrument, "More than 32 PCM instruments, discarding")
+                            .with_instrument(index)
+                    );
+                },
+                Instrument::FM(_) if adlib_instrument_remap_index >= 32 => {
+                    diagnostics.push(
+                        Diagnostic::new(Severity::Error, Category::DroppedInstrument, "More than 32 AdLib instruments, discarding")
+                            .with_instrument(index)
+                    );
+                },
+                Instrument::Sample(sample) => {
                     pcm_instruments.push(sample.clone());
-                    pcm_instrument_remap_table.insert(index as u8, pcm_instrument_remap_index);
+                    pcm_instrument_remap_table.insert(index, pcm_instrument_remap_index);
                     pcm_instrument_remap_index += 1;
                 },
-                S3MInstrument::Adlib(instrument) => {
-                    adlib_instruments.push(instrument.clone());
-                    adlib_instrument_remap_table.insert(index as u8, adlib_instrument_remap_index);
+                Instrument::FM(patch) => {
+                    // CDFM has no rhythm mode, drums become melodic patches
+                    adlib_instruments.push(match patch.drum {
+                        Some(drum) => melodic_patch(patch, drum),
+                        None => patch.clone(),
+                    });
+                    adlib_instrument_remap_table.insert(index, adlib_instrument_remap_index);
                     adlib_instrument_remap_index += 1;
                 },
             }
         }
 
+        let played: HashSet<usize> = song.patterns.iter()
+            .flat_map(|pattern| pattern.channels.iter().flatten())
+            .filter_map(|timed| match timed.event {
+                Event::Note { instrument, .. } => Some(instrument),
+                _ => None,
+            })
+            .collect();
+        let mut drum_sample_remap_table: HashMap<usize, u8> = HashMap::new();
+        for (index, instrument) in song.instruments.iter().enumerate() {
+            let Instrument::FM(patch) = instrument else {
+                continue;
+            };
+            let Some(drum) = patch.drum.filter(|_| played.contains(&index)) else {
+                continue;
+            };
+            if pcm_instruments.len() >= 32 {
+                diagnostics.push(
+                    Diagnostic::new(Severity::Warning, Category::DroppedInstrument, "No room for a PCM copy of the drum, it is dropped when FM voices run out")
+                        .with_instrument(index)
+                );
+                continue;
+            }
+
+            drum_sample_remap_table.insert(index, pcm_instruments.len() as u8);
+            pcm_instruments.push(render_sample(patch, drum));
+        }
+
         Self {
-            module,
-            pcm_channel_remap_table,
+            song,
+            voice_allocation: VoiceAllocationOptions::default(),
+            resample_quality: ResampleQuality::default(),
             pcm_instrument_remap_table,
             adlib_instrument_remap_table,
+            drum_sample_remap_table,
+            diagnostics,
             pcm_instruments,
             adlib_instruments,
         }
     }
 
-    pub fn convert(&self) -> C67Module {
-        let mut module = C67Module::default();
+    pub fn with_voice_allocation(mut self, options: VoiceAllocationOptions) -> Self {
+        self.voice_allocation = options;
+        self
+    }
+
+    pub fn with_resample_quality(mut self, quality: ResampleQuality) -> Self {
+        self.resample_quality = quality;
+        self
+    }
 
-        module.header.speed = self.module.initial_speed;
-        module.header.loop_order = 0;
+    pub fn convert(&self) -> (C67Module, ConversionReport) {
+        let mut module = C67Module::default();
 
         // Instrument filenames
         let mut pcm_instrument_filenames = [0u8;13*32];
@@ -105,73 +140,176 @@ impl<'a> Converter<'a> {
         // Instrument metadata
         let mut pcm_instrument_meta: [C67SampleMetadata;32] = array::from_fn(|_| C67SampleMetadata::default());
         let mut adlib_instrument_meta: [C67FMRegisters;32] = array::from_fn(|_| C67FMRegisters::default());
-        for (index, sample) in self.pcm_instruments.iter().enumerate() {
-            let meta = &mut pcm_instrument_meta[index];
-            if sample.flags & 1 != 0 {
-                meta.loop_start = sample.loop_begin;
-                meta.loop_end = sample.loop_end;
-            }
-
-            meta.sample_length = sample.audio.len() as u32;
-        }
         for (index, instrument) in self.adlib_instruments.iter().enumerate() {
             let meta = &mut adlib_instrument_meta[index];
+            let registers = &instrument.registers;
 
-            meta.feedback_connection = instrument.d0a;
-
-            meta.modulator_characteristics = instrument.d00;
-            meta.modulator_scale_and_output_level = instrument.d02 & 0x3F;
-            meta.modulator_scale_and_output_level |= (instrument.d02 >> 6).reverse_bits() << 6;
-            meta.modulator_attack_decay_level = instrument.d04;
-            meta.modulator_sustain_release_level = instrument.d06;
-            meta.modulator_wave_select = instrument.d08;
-
-            meta.carrier_characteristics = instrument.d01;
-            meta.carrier_scale_and_output_level = instrument.d03 & 0x3F;
-            meta.carrier_scale_and_output_level |= (instrument.d03 >> 6).reverse_bits() << 6;
-            meta.carrier_attack_decay_level = instrument.d05;
-            meta.carrier_sustain_release_level = instrument.d07;
-            meta.carrier_wave_select = instrument.d09;
+            meta.feedback_connection = registers[10];
+
+            meta.modulator_characteristics = registers[0];
+            meta.modulator_scale_and_output_level = swap_key_scale_level(registers[2]);
+            meta.modulator_attack_decay_level = registers[4];
+            meta.modulator_sustain_release_level = registers[6];
+            meta.modulator_wave_select = registers[8];
+
+            meta.carrier_characteristics = registers[1];
+            meta.carrier_scale_and_output_level = swap_key_scale_level(registers[3]);
+            meta.carrier_attack_decay_level = registers[5];
+            meta.carrier_sustain_release_level = registers[7];
+            meta.carrier_wave_select = registers[9];
         }
 
+        // Lay the song out on the song clock, turning pitch changes into notes
+        let tempo_map = &self.song.tempo_map;
+        let mut converted_segments: Vec<ConvertedSegment> = Vec::new();
+        let order_ticks = self.song.order_ticks();
+        let mut sounding: HashMap<usize, (usize, u8)> = HashMap::new();
+        for (order, start) in self.song.orders.iter().zip(&order_ticks) {
+            let pattern = &self.song.patterns[order.pattern];
+            let mut converted = ConvertedSegment {
+                order: order.source_order,
+                pattern: order.source_pattern,
+                row_ticks: pattern.row_ticks.iter().map(|tick| start + tick).collect(),
+                end_tick: start + pattern.length,
+                events: Vec::new(),
+                commands: Vec::new(),
+            };
+
+            for (channel, timed) in pattern.events() {
+                let event = match timed.event {
+                    Event::Note { instrument, semitone, volume } => {
+                        sounding.insert(channel, (instrument, volume));
+                        ChannelEvent::Note { instrument, semitone, volume }
+                    },
+                    Event::Volume(volume) => {
+                        if let Some((_, sounding_volume)) = sounding.get_mut(&channel) {
+                            *sounding_volume = volume;
+                        }
+                        ChannelEvent::Volume(volume)
+                    },
+                    // C67 can only change a voice's pitch by playing a new note
+                    Event::Pitch(semitone) => {
+                        let Some((instrument, volume)) = sounding.get(&channel).copied() else {
+                            continue;
+                        };
+                        ChannelEvent::Note { instrument, semitone, volume }
+                    },
+                    Event::Off => ChannelEvent::Off,
+                };
+                converted.events.push(TimedEvent { tick: start + timed.tick, row: timed.row, channel: channel as u8, event });
+            }
+
+            converted_segments.push(converted);
+        }
+        let tick = self.song.length();
+        let sample_plans = self.plan_samples(&converted_segments);
+        let mut diagnostics = self.diagnostics.clone();
+        diagnostics.extend(self.allocate_voices(&mut converted_segments, tempo_map, &sample_plans));
+
+        // Notes starting or stopping within a row need C67 rows of their own too
+        let mut boundary_ticks: Vec<u32> = converted_segments.iter()
+            .flat_map(|segment| segment.row_ticks.iter().copied().chain(
+                segment.events.iter()
+                    .filter(|timed| matches!(timed.event, ChannelEvent::Note { .. } | ChannelEvent::Off))
+                    .map(|timed| timed.tick)
+            ))
+            .chain(std::iter::once(tick))
+            .collect();
+        boundary_ticks.sort();
+        let mut boundaries: Vec<f64> = boundary_ticks.iter().map(|tick| tempo_map.seconds(*tick)).collect();
+        boundaries.dedup();
+        let plan = TimingPlan::choose(&boundaries);
+        module.header.speed = plan.speed;
+
+        // Quantizing against the song clock keeps timing exact, but repeated
+        // orders then rarely come out identical. Fall back to quantizing each
+        // order on its own when the shared patterns would not fit.
+        let mut quantized = quantize_segments(&converted_segments, tempo_map, plan, true);
+        if quantized.distinct_patterns() > 128 {
+            quantized = quantize_segments(&converted_segments, tempo_map, plan, false);
+        }
+
+        let timing = TimingReport {
+            speed: plan.speed,
+            source_duration: tempo_map.seconds(tick),
+            converted_duration: quantized.duration,
+            max_deviation: quantized.max_deviation,
+        };
+
+        // Share identical patterns between orders
         module.header.playlist.fill(0xFF);
+        let mut serialized_patterns: Vec<Vec<u8>> = Vec::new();
+        let mut pattern_lookup: HashMap<Vec<u8>, usize> = HashMap::new();
         let mut order_index = 0usize;
-        for order in &self.module.orders {
-            if *order == 254 {
-                // Ignore separators
-                continue;
+        'segments: for (segment_index, (segment, chunks)) in converted_segments.iter().zip(quantized.patterns).enumerate() {
+            let truncated = |message: &str| Diagnostic {
+                order: Some(segment.order),
+                pattern: Some(segment.pattern),
+                ..Diagnostic::new(Severity::Error, Category::TruncatedSong, message)
+            };
+            if segment_index == self.song.loop_order && order_index < 255 {
+                module.header.loop_order = order_index as u8;
+            }
+
+            for serialized_pattern in chunks {
+                if order_index >= 255 {
+                    diagnostics.push(truncated("Song is longer than 255 orders, discarding the rest"));
+                    break 'segments;
+                }
+
+                let pattern_index = match pattern_lookup.get(&serialized_pattern) {
+                    Some(index) => *index,
+                    None => {
+                        if serialized_patterns.len() >= 128 {
+                            diagnostics.push(truncated("More than 128 patterns needed, discarding the rest of the song"));
+                            break 'segments;
+                        }
+                        pattern_lookup.insert(serialized_pattern.clone(), serialized_patterns.len());
+                        serialized_patterns.push(serialized_pattern);
+                        serialized_patterns.len() - 1
+                    },
+                };
+                module.header.playlist[order_index] = pattern_index as u8;
+                order_index += 1;
             }
-            module.header.playlist[order_index] = *order;
-            order_index += 1;
         }
 
         let mut pattern_data: Vec<u8> = Vec::new();
         let mut pattern_offsets = [0u32;128];
         let mut pattern_lengths = [0u32;128];
-        let mut pattern_index = 0usize;
-        for pattern in &self.module.patterns {
-            let converted_pattern = self.convert_pattern(&pattern);
-            let mut serialized_pattern = serialize_pattern(&converted_pattern);
-            let serialized_pattern_length = serialized_pattern.len();
-            pattern_data.append(&mut serialized_pattern);
-            let offset = pattern_data.len()-serialized_pattern_length;
-            pattern_lengths[pattern_index] = serialized_pattern_length as u32;
-            pattern_offsets[pattern_index] = offset as u32;
-
-            pattern_index += 1;
-        }
-        for index in pattern_index..128 {
-            let mut serialized_pattern = serialize_pattern(&self.generate_empty_pattern());
+        for index in 0..128 {
+            let mut serialized_pattern = match serialized_patterns.get_mut(index) {
+                Some(pattern) => std::mem::take(pattern),
+                None => serialize_pattern(&self.generate_empty_pattern()),
+            };
             let serialized_pattern_length = serialized_pattern.len();
             pattern_data.append(&mut serialized_pattern);
             let offset = pattern_data.len()-serialized_pattern_length;
             pattern_lengths[index] = serialized_pattern_length as u32;
             pattern_offsets[index] = offset as u32;
-
         }
 
-        for sample in &self.pcm_instruments {
-            for v in &sample.audio {
+        // Resample PCM instruments so middle C plays at CDFM's rate
+        for (index, (sample, plan)) in self.pcm_instruments.iter().zip(&sample_plans).enumerate() {
+            let sample_loop = sample.sample_loop;
+            let audio = if plan.ratio == 1.0 {
+                sample.audio.clone()
+            } else {
+                resample(&sample.audio, plan.ratio, sample_loop, self.resample_quality)
+            };
+
+            let meta = &mut pcm_instrument_meta[index];
+            meta.sample_length = audio.len() as u32;
+            if let Some((loop_start, loop_end)) = sample_loop {
+                let loop_start = (loop_start as f64 * plan.ratio).round() as u32;
+                let loop_end = ((loop_end as f64 * plan.ratio).round() as u32).min(meta.sample_length);
+                // A loop rounded down to nothing is left out
+                if loop_start < loop_end {
+                    (meta.loop_start, meta.loop_end) = (loop_start, loop_end);
+                }
+            }
+
+            for v in &audio {
                 module.sample_data.push(((v/256) + 128) as u8);
             }
         }
@@ -184,136 +322,397 @@ impl<'a> Converter<'a> {
         module.header.pattern_pointers = format_c67::Plist { list: pattern_offsets };
         module.pattern_data = pattern_data;
 
-        module
+        let report = ConversionReport {
+            timing,
+            diagnostics,
+            kept_channels: None,
+        };
+        (module, report)
     }
 
-    pub fn generate_empty_pattern(&self) -> Vec<format_c67::C67PatternCommand> {
-        let mut commands: Vec<format_c67::C67PatternCommand> = Vec::new();
-        commands.push(format_c67::C67PatternCommand::Delay(64));
-        commands.push(format_c67::C67PatternCommand::End);
-        commands
+    pub fn generate_empty_pattern(&self) -> Vec<C67PatternCommand> {
+        vec![
+            C67PatternCommand::Delay(64),
+            C67PatternCommand::End,
+        ]
     }
 
-    pub fn convert_pattern(&self, pattern: &S3MPattern) -> Vec<format_c67::C67PatternCommand> {
-        let mut commands: Vec<format_c67::C67PatternCommand> = Vec::new();
-
-        for (row_index, row) in pattern.iter().enumerate() {
-            let mut saved_instrument: u8 = 0;
-            if row_index == 63 {
-                commands.push(format_c67::C67PatternCommand::Delay(1));
-                commands.push(format_c67::C67PatternCommand::End);
-                break;
+    /// Picks a transposition and resampling ratio for every PCM instrument,
+    /// keeping the notes it plays within CDFM's eight octaves.
+    fn plan_samples(&self, segments: &[ConvertedSegment]) -> Vec<SamplePlan> {
+        let mut note_ranges: HashMap<u8, (i32, i32)> = HashMap::new();
+        for event in segments.iter().flat_map(|segment| &segment.events) {
+            if let ChannelEvent::Note { instrument, semitone, .. } = event.event {
+                let index = self.pcm_instrument_remap_table.get(&instrument)
+                    .or_else(|| self.drum_sample_remap_table.get(&instrument));
+                if let Some(index) = index {
+                    let range = note_ranges.entry(*index).or_insert((semitone, semitone));
+                    *range = (range.0.min(semitone), range.1.max(semitone));
+                }
             }
+        }
 
-            for (channel_index, col) in row.iter().enumerate() {
-                if col.instrument != 0 {saved_instrument = col.instrument;}
-                if col.note < 254 {
-                    let octave = col.note >> 4;
-                    let pitch = col.note & 0xF;
-                    // let actual_note = octave*12+pitch+12;
-
-                    let channel: Channel;
-                    let mut volume: u8;
-
-                    match &self.module.instruments[(saved_instrument-1) as usize] {
-                        S3MInstrument::Adlib(instrument) => {
-                            volume = instrument.volume;
-                            let channel_setting = self.module.channel_settings[channel_index] & 0x7F;
-                            if channel_setting > 26 {
-                                // Drum adlib channel
-                                continue;
-                            }
-                            channel = Channel::FM(channel_setting-16);
+        self.pcm_instruments.iter()
+            .enumerate()
+            .map(|(index, sample)| plan_sample(sample, note_ranges.get(&(index as u8)).copied()))
+            .collect()
+    }
+
+    /// Moves every note onto a C67 voice, in playing order, noting the notes
+    /// dropped and the pitches and volumes C67 cannot play.
+    fn allocate_voices(&self, segments: &mut [ConvertedSegment], tempo_map: &TempoMap, sample_plans: &[SamplePlan]) -> Diagnostics {
+        let mut allocator = VoiceAllocator::new(self.voice_allocation);
+        let mut diagnostics = Diagnostics::default();
+        // Last volume given to each voice, to skip slide steps CDFM cannot tell apart
+        let mut voice_volumes: HashMap<Channel, u8> = HashMap::new();
+
+        for segment in segments.iter_mut() {
+            for event in &segment.events {
+                let time = tempo_map.seconds(event.tick);
+                let diagnostic = |severity: Severity, category: Category, message: &str| {
+                    Diagnostic::new(severity, category, message)
+                        .at(segment.order, segment.pattern, event.row)
+                        .on_channel(event.channel as usize)
+                };
+
+                let command = match event.event {
+                    ChannelEvent::Note { instrument, semitone, volume } => {
+                        let (pool, remapped_instrument, busy_until) = match &self.song.instruments[instrument] {
+                            Instrument::Sample(sample) => {
+                                let busy_until = time + sample_duration(sample, semitone);
+                                (VoicePool::PCM, self.pcm_instrument_remap_table.get(&instrument), busy_until)
+                            },
+                            Instrument::FM(patch) => {
+                                let remapped_instrument = self.adlib_instrument_remap_table.get(&instrument);
+                                let busy_until = match (patch.drum, remapped_instrument) {
+                                    // Drums die away by themselves
+                                    (Some(_), Some(index)) => time + patch_duration(&self.adlib_instruments[*index as usize]),
+                                    _ => f64::INFINITY,
+                                };
+                                (VoicePool::FM, remapped_instrument, busy_until)
+                            },
+                        };
+                        let Some(mut remapped_instrument) = remapped_instrument else {
+                            diagnostics.tally(
+                                diagnostic(Severity::Warning, Category::DroppedNote, "Instrument has no C67 slot, discarding the note")
+                                    .with_instrument(instrument)
+                            );
+                            continue;
+                        };
+
+                        let mut pool = pool;
+                        let mut channel = allocator.note_on(pool, event.channel, time, busy_until, volume);
+                        if let (None, Some(sample_index)) = (channel, self.drum_sample_remap_table.get(&instrument)) {
+                            // Drums fall back to rendered samples once the FM voices run out
+                            let busy_until = time + sample_duration(&self.pcm_instruments[*sample_index as usize], semitone);
+                            channel = allocator.note_on(VoicePool::PCM, event.channel, time, busy_until, volume);
+                            (pool, remapped_instrument) = (VoicePool::PCM, sample_index);
                         }
-                        S3MInstrument::Sample(sample) => {
-                            volume = sample.volume;
-                            let channel_setting = self.module.channel_settings[channel_index] & 0x7F;
-                            let remapped_channel_num = self.pcm_channel_remap_table.get(&channel_setting);
-
-                            let mut formatted_channel: String;
-                            if channel_setting >= 8 {
-                                formatted_channel = ((channel_setting - 8) + 1).to_string();
-                                formatted_channel.push('R');
-                            } else {
-                                formatted_channel = (channel_setting + 1).to_string();
-                                formatted_channel.push('L');
-                            }
-
-                            if remapped_channel_num.is_none() {
-                                println!("Discarding note in channel {} as it is not mapped", formatted_channel);
-                                continue;
-                            }
-                            channel = Channel::PCM(*self.pcm_channel_remap_table.get(&channel_setting).unwrap());
-                        },
-                    }
-
-                    if col.vol <= 64 {
-                        volume = col.vol;
-                    }
-
-                    let instrument: u8;
-                    match &self.module.instruments[(saved_instrument as usize)-1] {
-                        S3MInstrument::Sample(_) => {
-                            let remapped_instrument = self.pcm_instrument_remap_table.get(&(saved_instrument-1));
-                            if remapped_instrument.is_none() {
-                                println!("Discarding note with instrument {} as it is not mapped", saved_instrument);
-                                continue;
-                            }
-                            instrument = *remapped_instrument.unwrap();
-                        },
-                        S3MInstrument::Adlib(_) => {
-                            let remapped_instrument = self.adlib_instrument_remap_table.get(&(saved_instrument-1));
-                            if remapped_instrument.is_none() {
-                                println!("Discarding note with instrument {} as it is not mapped", saved_instrument);
-                                continue;
-                            }
-                            instrument = *remapped_instrument.unwrap();
-                        },
-                    }
-
-                    commands.push(format_c67::C67PatternCommand::PlayNote(PlayNoteCommand {
-                        channel,
-                        octave: octave & 7,
-                        note: pitch,
-                        instrument,
-                        volume: (volume/4).clamp(0, 15),
-                    }));
-                } else if col.note == 254 {
-                    // added even though volume 0 is not silent :)
-                    let channel: Channel;
-                    let channel_setting = self.module.channel_settings[channel_index] & 0x7F;
-                    if channel_setting <= 15 { // is PCM channel
-                        channel = Channel::PCM(*self.pcm_channel_remap_table.get(&channel_setting).unwrap());
-                    } else {
-                        channel = Channel::FM(channel_setting-16);
-                    }
-
-                    commands.push(format_c67::C67PatternCommand::SetVolume(SetVolumeCommand {
-                        channel,
-                        volume: 0,
-                    }));
-                } else if col.vol <= 64 {
-                    let channel: Channel;
-                    let channel_setting = self.module.channel_settings[channel_index] & 0x7F;
-                    if channel_setting <= 15 { // is PCM channel
-                        channel = Channel::PCM(*self.pcm_channel_remap_table.get(&channel_setting).unwrap());
-                    } else {
-                        channel = Channel::FM(channel_setting-16);
-                    }
-
-                    commands.push(format_c67::C67PatternCommand::SetVolume(SetVolumeCommand {
-                        channel,
-                        volume: (col.vol/4).clamp(0, 15),
-                    }));
-                }
+                        let Some((channel, stolen_from)) = channel else {
+                            diagnostics.push(
+                                diagnostic(Severity::Warning, Category::DroppedNote, "No free voice for the note, discarding it")
+                                    .with_instrument(instrument)
+                            );
+                            continue;
+                        };
+                        if let Some(stolen_from) = stolen_from {
+                            diagnostics.push(
+                                Diagnostic::new(Severity::Warning, Category::DroppedNote, format!("Note cut off to free its voice for channel {}", event.channel + 1))
+                                    .at(segment.order, segment.pattern, event.row)
+                                    .on_channel(stolen_from as usize)
+                            );
+                        }
+
+                        let semitone = match pool {
+                            VoicePool::PCM => semitone + PCM_OCTAVE_OFFSET * 12 + sample_plans[*remapped_instrument as usize].transpose,
+                            VoicePool::FM => semitone,
+                        };
+                        if !(0..96).contains(&semitone) {
+                            diagnostics.tally(
+                                diagnostic(Severity::Warning, Category::ClampedPitch, "Note is outside C67's eight octaves, moving it into them")
+                                    .with_instrument(instrument)
+                            );
+                        }
+                        let semitone = semitone.clamp(0, 95);
+                        let (octave, note) = ((semitone / 12) as u8, (semitone % 12) as u8);
+
+                        if volume < s3m_volume(channel, 0) {
+                            diagnostics.tally(diagnostic(Severity::Warning, Category::ClampedVolume, QUIET_VOLUME));
+                        }
+                        let volume = c67_volume(channel, volume);
+                        voice_volumes.insert(channel, volume);
+                        C67PatternCommand::PlayNote(PlayNoteCommand {
+                            channel,
+                            octave,
+                            note,
+                            instrument: *remapped_instrument,
+                            volume,
+                        })
+                    },
+                    ChannelEvent::Volume(volume) => {
+                        let Some(channel) = allocator.voice(event.channel) else {
+                            continue;
+                        };
+                        allocator.set_volume(event.channel, volume);
+
+                        if volume < s3m_volume(channel, 0) {
+                            diagnostics.tally(diagnostic(Severity::Warning, Category::ClampedVolume, QUIET_VOLUME));
+                        }
+                        let volume = c67_volume(channel, volume);
+                        if voice_volumes.insert(channel, volume) == Some(volume) {
+                            continue;
+                        }
+                        C67PatternCommand::SetVolume(SetVolumeCommand { channel, volume })
+                    },
+                    ChannelEvent::Off => {
+                        let Some(channel) = allocator.voice(event.channel) else {
+                            continue;
+                        };
+                        allocator.release(event.channel);
+                        voice_volumes.insert(channel, 0);
+
+                        // added even though volume 0 is not silent :)
+                        C67PatternCommand::SetVolume(SetVolumeCommand {
+                            channel,
+                            volume: 0,
+                        })
+                    },
+                };
+
+                segment.commands.push(TimedCommand { tick: event.tick, command });
             }
+        }
 
-            commands.push(format_c67::C67PatternCommand::Delay(1));
-            
+        diagnostics
+    }
+}
+
+/// What happens on a source channel, independent of which C67 voice plays it.
+#[derive(Debug, Clone, Copy)]
+enum ChannelEvent {
+    Note {
+        instrument: usize,
+        semitone: i32,
+        volume: u8,
+    },
+    Volume(u8),
+    Off,
+}
+
+struct TimedEvent {
+    tick: u32,
+    row: usize,
+    channel: u8,
+    event: ChannelEvent,
+}
+
+struct TimedCommand {
+    tick: u32,
+    command: C67PatternCommand,
+}
+
+struct ConvertedSegment {
+    /// Source order and pattern, for reporting.
+    order: usize,
+    pattern: usize,
+    row_ticks: Vec<u32>,
+    end_tick: u32,
+    events: Vec<TimedEvent>,
+    commands: Vec<TimedCommand>,
+}
+
+impl ConvertedSegment {
+    fn start_tick(&self) -> u32 {
+        self.row_ticks.first().copied().unwrap_or(self.end_tick)
+    }
+}
+
+/// Serialized patterns, a run of them per segment, and how well they keep time.
+struct QuantizedSong {
+    patterns: Vec<Vec<Vec<u8>>>,
+    duration: f64,
+    max_deviation: f64,
+}
+
+impl QuantizedSong {
+    fn distinct_patterns(&self) -> usize {
+        let mut patterns: Vec<&Vec<u8>> = self.patterns.iter().flatten().collect();
+        patterns.sort();
+        patterns.dedup();
+        patterns.len()
+    }
+}
+
+/// Places every segment's commands on C67 rows. With `anchored`, positions
+/// are rounded against the song clock so errors never accumulate; otherwise
+/// each segment is rounded from its own start, so equal segments encode
+/// equally wherever they are played. Segments longer than a C67 pattern
+/// are split into as many as they need.
+fn quantize_segments(segments: &[ConvertedSegment], tempo_map: &TempoMap, plan: TimingPlan, anchored: bool) -> QuantizedSong {
+    let mut song = QuantizedSong {
+        patterns: Vec::new(),
+        duration: 0.0,
+        max_deviation: 0.0,
+    };
+    let mut position = 0u32;
+
+    for segment in segments {
+        let origin = tempo_map.seconds(segment.start_tick());
+        let rows_at = |tick: u32| {
+            if anchored {
+                plan.rows(tempo_map.seconds(tick)) - position
+            } else {
+                plan.rows(tempo_map.seconds(tick) - origin)
+            }
+        };
+
+        let length = rows_at(segment.end_tick);
+        for tick in &segment.row_ticks {
+            let converted = plan.seconds(position + rows_at(*tick));
+            song.max_deviation = song.max_deviation.max((converted - tempo_map.seconds(*tick)).abs());
+        }
+
+        let timeline: Vec<(u32, C67PatternCommand)> = segment.commands.iter()
+            .map(|timed| (rows_at(timed.tick).min(length.saturating_sub(1)), timed.command.clone()))
+            .collect();
+        let mut chunks: Vec<Vec<u8>> = Vec::new();
+        let mut start = 0u32;
+        loop {
+            let end = (start + PATTERN_ROWS).min(length);
+            let chunk: Vec<(u32, C67PatternCommand)> = timeline.iter()
+                .filter(|(row, _)| *row >= start && (*row < end || end == length))
+                .map(|(row, command)| (row - start, command.clone()))
+                .collect();
+            chunks.push(serialize_pattern(&encode_timeline(chunk, end - start)));
+            start = end;
+            if start >= length {
+                break;
+            }
         }
+        song.patterns.push(chunks);
+        position += length;
+    }
+    song.duration = plan.seconds(position);
+
+    song
+}
+
+#[derive(Debug, Default, serde::Serialize)]
+pub struct ConversionReport {
+    pub timing: TimingReport,
+    /// Everything the C67 leaves out or plays differently.
+    pub diagnostics: Diagnostics,
+    /// Sample channels left playing when the song was reduced to 4.
+    pub kept_channels: Option<Vec<usize>>,
+}
+
+const QUIET_VOLUME: &str = "Volume is below the quietest the voice can play";
+
+/// C67 octave playing the same pitch as an S3M octave, before any transposition.
+const PCM_OCTAVE_OFFSET: i32 = PCM_MIDDLE_C_OCTAVE as i32 - 4;
+
+/// How a PCM instrument is adapted to CDFM's fixed middle C rate.
+#[derive(Debug, Clone, Copy)]
+struct SamplePlan {
+    /// Semitones added to every note played with the instrument.
+    transpose: i32,
+    /// Output samples per source sample.
+    ratio: f64,
+}
+
+/// Transposes as little as possible while keeping `notes` (lowest and highest
+/// semitone played) in range and the resampled sample within CDFM's size limit.
+fn plan_sample(sample: &Sample, notes: Option<(i32, i32)>) -> SamplePlan {
+    let (lowest, highest) = notes.unwrap_or((48, 48));
+    let lowest_transpose = -(lowest + PCM_OCTAVE_OFFSET * 12);
+    let highest_transpose = 95 - (highest + PCM_OCTAVE_OFFSET * 12);
+
+    let mut transpose = if lowest_transpose <= highest_transpose {
+        0.clamp(lowest_transpose, highest_transpose)
+    } else {
+        // Too wide to fit, centre it
+        (lowest_transpose + highest_transpose) / 2
+    };
+
+    let ratio = |transpose: i32| {
+        PCM_MIDDLE_C_RATE as f64 * 2f64.powf(transpose as f64 / 12.0) / sample.c4speed as f64
+    };
+    while sample.audio.len() as f64 * ratio(transpose) > NO_LOOP as f64 {
+        transpose -= 12;
+    }
 
-        //dbg!(&commands);
+    let ratio = if sample.audio.is_empty() { 1.0 } else { ratio(transpose) };
+    SamplePlan { transpose, ratio }
+}
+
+/// Nearest C67 volume to a song volume (0-64) on a voice, the inverse of
+/// `format_c67::s3m_volume`.
+fn c67_volume(channel: Channel, volume: u8) -> u8 {
+    match channel {
+        Channel::PCM(_) => ((volume as i32 - 2) / 4).clamp(0, 15) as u8,
+        Channel::FM(_) => (0..16u8)
+            .min_by_key(|index| (FM_VOLUMES[*index as usize] as i32 - volume as i32).abs())
+            .unwrap(),
+    }
+}
+
+/// How long a sample plays a note for before falling silent, in seconds.
+fn sample_duration(sample: &Sample, semitone: i32) -> f64 {
+    if sample.sample_loop.is_some() {
+        return f64::INFINITY;
+    }
+
+    let rate = sample.c4speed as f64 * 2f64.powf((semitone - 48) as f64 / 12.0);
+    sample.audio.len() as f64 / rate
+}
 
-        commands
+/// Turns commands positioned in C67 rows into a pattern lasting `length` rows.
+fn encode_timeline(mut timeline: Vec<(u32, C67PatternCommand)>, length: u32) -> Vec<C67PatternCommand> {
+    let mut commands: Vec<C67PatternCommand> = Vec::new();
+    let mut position = 0u32;
+
+    timeline.sort_by_key(|(row, _)| *row);
+
+    // Only the last volume a voice gets within a row is heard
+    let superseded: Vec<bool> = timeline.iter()
+        .enumerate()
+        .map(|(index, (row, command))| {
+            let C67PatternCommand::SetVolume(set_volume) = command else {
+                return false;
+            };
+            timeline[index+1..].iter()
+                .take_while(|(later_row, _)| later_row == row)
+                .any(|(_, later)| command_channel(later) == Some(set_volume.channel))
+        })
+        .collect();
+    let timeline = timeline.into_iter()
+        .zip(superseded)
+        .filter(|(_, superseded)| !superseded)
+        .map(|(entry, _)| entry);
+
+    for (row, command) in timeline {
+        push_delay(&mut commands, row - position);
+        position = row;
+        commands.push(command);
     }
-}
\ No newline at end of file
+    push_delay(&mut commands, length - position);
+    commands.push(C67PatternCommand::End);
+
+    commands
+}
+
+fn command_channel(command: &C67PatternCommand) -> Option<Channel> {
+    match command {
+        C67PatternCommand::PlayNote(play_note) => Some(play_note.channel),
+        C67PatternCommand::SetVolume(set_volume) => Some(set_volume.channel),
+        _ => None,
+    }
+}
+
+fn push_delay(commands: &mut Vec<C67PatternCommand>, mut rows: u32) {
+    while rows > 0 {
+        let step = rows.min(255);
+        commands.push(C67PatternCommand::Delay(step as u8));
+        rows -= step;
+    }
+}
@@ -0,0 +1,179 @@
+use std::fmt;
+
+/// How much of the song a diagnostic costs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    /// Nothing is lost, but the song plays differently than it might.
+    Info,
+    /// Part of the song is lost or sounds different.
+    Warning,
+    /// Whole instruments or stretches of the song are missing.
+    Error,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Category {
+    /// A note that found no C67 voice or instrument to play on, or one cut
+    /// off early to give its voice to another.
+    DroppedNote,
+    /// A sample channel muted to fit C67's 4 PCM voices.
+    DroppedChannel,
+    /// An instrument there is no C67 slot for, or part of one the loaded
+    /// song has no room for.
+    DroppedInstrument,
+    /// An effect the conversion ignores.
+    UnsupportedEffect,
+    /// A volume C67 cannot play, replaced by the nearest one it can.
+    ClampedVolume,
+    /// A note outside C67's eight octaves, moved into them.
+    ClampedPitch,
+    /// Orders or patterns past what C67 holds.
+    TruncatedSong,
+    /// A conversion option that did not apply as given.
+    Option,
+    /// A part of the input file that is damaged or runs past its end,
+    /// repaired or left out while loading.
+    DamagedFile,
+}
+
+/// Something the conversion could not carry over faithfully, and where.
+/// Orders, patterns, rows, channels and instruments are counted from 0.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub category: Category,
+    pub message: String,
+    pub order: Option<usize>,
+    pub pattern: Option<usize>,
+    pub row: Option<usize>,
+    pub channel: Option<usize>,
+    pub instrument: Option<usize>,
+    /// Times the same thing happened, the location being the first.
+    pub count: usize,
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, category: Category, message: impl Into<String>) -> Self {
+        Self {
+            severity,
+            category,
+            message: message.into(),
+            order: None,
+            pattern: None,
+            row: None,
+            channel: None,
+            instrument: None,
+            count: 1,
+        }
+    }
+
+    /// Places the diagnostic at a row of the song.
+    pub fn at(mut self, order: usize, pattern: usize, row: usize) -> Self {
+        (self.order, self.pattern, self.row) = (Some(order), Some(pattern), Some(row));
+        self
+    }
+
+    pub fn on_channel(mut self, channel: usize) -> Self {
+        self.channel = Some(channel);
+        self
+    }
+
+    pub fn with_instrument(mut self, instrument: usize) -> Self {
+        self.instrument = Some(instrument);
+        self
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    /// Shows the message after its location, counting from 1 the way
+    /// trackers show channels and instruments.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut location: Vec<String> = Vec::new();
+        if let (Some(order), Some(pattern)) = (self.order, self.pattern) {
+            location.push(format!("order {} (pattern {})", order, pattern));
+        }
+        if let Some(row) = self.row {
+            location.push(format!("row {}", row));
+        }
+        if let Some(channel) = self.channel {
+            location.push(format!("channel {}", channel + 1));
+        }
+        if let Some(instrument) = self.instrument {
+            location.push(format!("instrument {}", instrument + 1));
+        }
+
+        if !location.is_empty() {
+            write!(f, "{}: ", location.join(", "))?;
+        }
+        write!(f, "{}", self.message)?;
+        if self.count > 1 {
+            write!(f, " ({} times)", self.count)?;
+        }
+        Ok(())
+    }
+}
+
+/// Diagnostics gathered over a conversion, in the order they came up.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize)]
+#[serde(transparent)]
+pub struct Diagnostics(Vec<Diagnostic>);
+
+impl Diagnostics {
+    pub fn push(&mut self, diagnostic: Diagnostic) {
+        self.0.push(diagnostic);
+    }
+
+    /// Adds `diagnostic`, or counts it against an earlier one with the same
+    /// message about the same channel and instrument.
+    pub fn tally(&mut self, diagnostic: Diagnostic) {
+        let earlier = self.0.iter_mut().find(|earlier| {
+            (earlier.category, &earlier.message, earlier.channel, earlier.instrument)
+                == (diagnostic.category, &diagnostic.message, diagnostic.channel, diagnostic.instrument)
+        });
+        match earlier {
+            Some(earlier) => earlier.count += 1,
+            None => self.0.push(diagnostic),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Diagnostic> {
+        self.0.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn of(&self, category: Category) -> impl Iterator<Item = &Diagnostic> {
+        self.0.iter().filter(move |diagnostic| diagnostic.category == category)
+    }
+
+    /// Occurrences in `category`, repeats included.
+    pub fn count(&self, category: Category) -> usize {
+        self.of(category).map(|diagnostic| diagnostic.count).sum()
+    }
+
+    /// The most severe diagnostic's severity, if there are any.
+    pub fn worst(&self) -> Option<Severity> {
+        self.0.iter().map(|diagnostic| diagnostic.severity).max()
+    }
+
+    pub fn extend(&mut self, other: Diagnostics) {
+        self.0.extend(other.0);
+    }
+}
+
+impl<'a> IntoIterator for &'a Diagnostics {
+    type Item = &'a Diagnostic;
+    type IntoIter = std::slice::Iter<'a, Diagnostic>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
@@ -0,0 +1,108 @@
+use std::{collections::HashSet, ops::Range};
+
+use crate::format_s3m::{S3MModule, EFFECT_BREAK, EFFECT_JUMP};
+
+/// A stretch of one pattern played from one order position.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub order: usize,
+    pub pattern: usize,
+    pub rows: Range<usize>,
+}
+
+/// The song as ST3 plays it: every segment in playing order, and the segment
+/// playback returns to once the end is reached.
+#[derive(Debug, Default)]
+pub struct SongFlow {
+    pub segments: Vec<Segment>,
+    pub loop_segment: usize,
+}
+
+/// Follows the order list like ST3 does, honouring position jumps (Bxx) and
+/// pattern breaks (Cxx), until playback reaches a row it has played before.
+pub fn walk_orders(module: &S3MModule) -> SongFlow {
+    let mut flow = SongFlow::default();
+    let mut visited: HashSet<(usize, usize)> = HashSet::new();
+
+    let Some(mut order) = next_playable_order(module, 0) else {
+        return flow;
+    };
+    let mut row = 0usize;
+
+    while !visited.contains(&(order, row)) {
+        let pattern = module.orders[order] as usize;
+        let start = row;
+        let mut next: Option<(usize, usize)> = None;
+
+        while row < 64 {
+            visited.insert((order, row));
+
+            let mut jump: Option<usize> = None;
+            let mut pattern_break: Option<usize> = None;
+            for col in &module.patterns[pattern][row] {
+                match col.effect {
+                    EFFECT_JUMP => jump = Some(col.effect_value as usize),
+                    EFFECT_BREAK => {
+                        // Row numbers are stored as BCD
+                        let target = (col.effect_value >> 4) as usize * 10 + (col.effect_value & 0xF) as usize;
+                        pattern_break = Some(if target < 64 { target } else { 0 });
+                    },
+                    _ => {},
+                }
+            }
+            row += 1;
+
+            if jump.is_some() || pattern_break.is_some() {
+                next = Some((jump.unwrap_or(order + 1), pattern_break.unwrap_or(0)));
+                break;
+            }
+            if row < 64 && visited.contains(&(order, row)) {
+                // Ran into rows an earlier entry into this order already played
+                break;
+            }
+        }
+
+        flow.segments.push(Segment { order, pattern, rows: start..row });
+
+        if next.is_none() && row < 64 {
+            continue;
+        }
+        let (target_order, target_row) = next.unwrap_or((order + 1, 0));
+        (order, row) = match next_playable_order(module, target_order) {
+            Some(order) => (order, target_row),
+            // The end of the song restarts it from the first order
+            None => (next_playable_order(module, 0).unwrap(), 0),
+        };
+    }
+
+    // Loop back to the segment containing the row playback returned to,
+    // splitting it if the loop enters it part way
+    let index = flow.segments.iter()
+        .position(|segment| segment.order == order && segment.rows.contains(&row))
+        .unwrap();
+    if flow.segments[index].rows.start == row {
+        flow.loop_segment = index;
+    } else {
+        let mut tail = flow.segments[index].clone();
+        tail.rows.start = row;
+        flow.segments[index].rows.end = row;
+        flow.segments.insert(index + 1, tail);
+        flow.loop_segment = index + 1;
+    }
+
+    flow
+}
+
+/// First order at or after `order` that plays a pattern, skipping separators.
+/// Returns `None` once the end of the song is reached.
+fn next_playable_order(module: &S3MModule, mut order: usize) -> Option<usize> {
+    while order < module.orders.len() {
+        match module.orders[order] {
+            255 => return None,
+            pattern if pattern == 254 || pattern as usize >= module.patterns.len() => order += 1,
+            _ => return Some(order),
+        }
+    }
+
+    None
+}
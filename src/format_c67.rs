@@ -1,5 +1,48 @@
+use std::{fmt, io};
+
+use byteorder::{LittleEndian, ReadBytesExt};
 use serde_big_array::BigArray;
 
+use crate::pitch::NOTE_NAMES;
+
+/// Size of the fixed CDFM header. Pattern pointers are relative to the end of it.
+pub const HEADER_SIZE: usize = 0xBA2;
+
+/// CDFM's player ticks at the rate ST3 uses for tempo 143.
+pub const TICKS_PER_SECOND: f64 = 143.0 * 2.0 / 5.0;
+
+/// PCM notes in this octave play samples at PCM_MIDDLE_C_RATE.
+pub const PCM_MIDDLE_C_OCTAVE: u8 = 2;
+pub const PCM_MIDDLE_C_RATE: u32 = 8287;
+
+/// Rows in a CDFM pattern. Its editor and other C67 players expect no more,
+/// so longer stretches of song are split across several patterns.
+pub const PATTERN_ROWS: u32 = 64;
+
+/// Loop end value used by CDFM for samples that do not loop.
+pub const NO_LOOP: u32 = 0xFFFFF;
+
+/// The S3M volume each C67 FM volume sounds like. CDFM scales FM output
+/// levels linearly, so quiet FM volumes are much louder than in ST3.
+pub const FM_VOLUMES: [u8;16] = [0x08, 0x10, 0x18, 0x20, 0x28, 0x2C, 0x30, 0x34, 0x36, 0x38, 0x3A, 0x3C, 0x3D, 0x3E, 0x3F, 0x40];
+
+/// The S3M volume (0-64) a C67 volume plays at on a voice. PCM volume v
+/// plays like S3M volume 4 + 4v.
+pub fn s3m_volume(channel: Channel, volume: u8) -> u8 {
+    match channel {
+        Channel::PCM(_) => 4 + 4 * (volume & 0xF),
+        Channel::FM(_) => FM_VOLUMES[(volume & 0xF) as usize],
+    }
+}
+
+/// S3M and CDFM store the two key scale level bits of a scale and output
+/// level register in opposite order. Swapping them converts either way.
+pub fn swap_key_scale_level(scale_and_output_level: u8) -> u8 {
+    let level = scale_and_output_level & 0x3F;
+    let key_scale = scale_and_output_level >> 6;
+    level | ((key_scale & 1) << 7) | ((key_scale & 2) << 5)
+}
+
 /* stupid serde bullshit */
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct Plist {
@@ -46,7 +89,7 @@ impl Default for C67ModuleHeader {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 #[repr(C)]
 pub struct C67Module {
     pub header: C67ModuleHeader,
@@ -54,43 +97,128 @@ pub struct C67Module {
     pub sample_data: Vec<u8>,
 }
 
-impl Default for C67Module {
-    fn default() -> Self {
-        Self {
-            header: Default::default(),
-            pattern_data: Default::default(),
-            sample_data: Default::default()
-        }
-    }
-}
-
 impl C67Module {
     pub fn serialize(&self) -> Vec<u8> {
         let mut data: Vec<u8> = Vec::new();
 
         data.push(self.header.speed);
-        //dbg!("{}", data.len());
         data.push(self.header.loop_order);
-        //dbg!("{}", data.len());
         data.extend_from_slice(&self.header.instrument_filenames);
-        //dbg!("{}", data.len());
         data.append(&mut bincode::serialize(&self.header.instrument_meta).unwrap());
-        //dbg!("{}", data.len());
         data.extend_from_slice(&self.header.adlib_instrument_filenames);
-        //dbg!("{}", data.len());
         data.append(&mut bincode::serialize(&self.header.adlib_instrument_meta).unwrap());
-        //dbg!("{}", data.len());
         data.extend_from_slice(&self.header.playlist);
-        //dbg!("{}", data.len());
-        data.extend_from_slice(&mut bincode::serialize(&self.header.pattern_pointers).unwrap());
-        //dbg!("{}", data.len());
-        data.extend_from_slice(&mut bincode::serialize(&self.header.pattern_lengths).unwrap());
-        //dbg!("{}", data.len());
+        data.extend_from_slice(&bincode::serialize(&self.header.pattern_pointers).unwrap());
+        data.extend_from_slice(&bincode::serialize(&self.header.pattern_lengths).unwrap());
         data.extend_from_slice(&self.pattern_data);
         data.extend_from_slice(&self.sample_data);
 
         data
     }
+
+    pub fn load(mut reader: impl io::Read) -> Result<C67Module, C67LoadError> {
+        let mut module = C67Module::default();
+        let header = &mut module.header;
+
+        // HEADER START
+        header.speed = reader.read_u8().map_err(|e| truncated(e, 0))?;
+        header.loop_order = reader.read_u8().map_err(|e| truncated(e, 1))?;
+        reader.read_exact(&mut header.instrument_filenames).map_err(|e| truncated(e, 2))?;
+        for (index, meta) in header.instrument_meta.iter_mut().enumerate() {
+            let offset = 0x1A2 + index as u64 * 16;
+            meta._unused = reader.read_u32::<LittleEndian>().map_err(|e| truncated(e, offset))?;
+            meta.sample_length = reader.read_u32::<LittleEndian>().map_err(|e| truncated(e, offset + 4))?;
+            meta.loop_start = reader.read_u32::<LittleEndian>().map_err(|e| truncated(e, offset + 8))?;
+            meta.loop_end = reader.read_u32::<LittleEndian>().map_err(|e| truncated(e, offset + 12))?;
+        }
+        reader.read_exact(&mut header.adlib_instrument_filenames).map_err(|e| truncated(e, 0x3A2))?;
+        for (index, registers) in header.adlib_instrument_meta.iter_mut().enumerate() {
+            let mut raw = [0u8;11];
+            reader.read_exact(&mut raw).map_err(|e| truncated(e, 0x542 + index as u64 * 11))?;
+            *registers = C67FMRegisters::from_bytes(&raw);
+        }
+        reader.read_exact(&mut header.playlist).map_err(|e| truncated(e, 0x6A2))?;
+        reader.read_u32_into::<LittleEndian>(&mut header.pattern_pointers.list).map_err(|e| truncated(e, 0x7A2))?;
+        reader.read_u32_into::<LittleEndian>(&mut header.pattern_lengths.list).map_err(|e| truncated(e, 0x9A2))?;
+        // HEADER END
+
+        if header.speed == 0 || header.speed > 15 {
+            return Err(C67LoadError::InvalidSpeed(header.speed));
+        }
+        for (position, pattern) in header.playlist.iter().enumerate() {
+            if *pattern >= 128 && *pattern != 0xFF {
+                return Err(C67LoadError::InvalidOrder { position, pattern: *pattern });
+            }
+        }
+        for (index, meta) in header.instrument_meta.iter().enumerate() {
+            if meta.sample_length > NO_LOOP {
+                return Err(C67LoadError::InvalidSample { index, reason: "sample is longer than 0xFFFFF bytes" });
+            }
+            if meta.loop_end != NO_LOOP && (meta.loop_end > meta.sample_length || meta.loop_start > meta.loop_end) {
+                return Err(C67LoadError::InvalidSample { index, reason: "loop points lie outside the sample" });
+            }
+        }
+
+        let mut body: Vec<u8> = Vec::new();
+        reader.read_to_end(&mut body)?;
+
+        // Pattern data ends where the furthest pattern ends, sample data follows
+        let mut pattern_data_length = 0usize;
+        for (pattern, (offset, length)) in header.pattern_pointers.list.iter()
+            .zip(header.pattern_lengths.list.iter())
+            .enumerate()
+        {
+            let end = *offset as usize + *length as usize;
+            if end > body.len() {
+                return Err(C67LoadError::PatternOutOfBounds {
+                    pattern,
+                    offset: *offset,
+                    length: *length,
+                    file_offset: (HEADER_SIZE + *offset as usize) as u64,
+                });
+            }
+            pattern_data_length = pattern_data_length.max(end);
+        }
+
+        let sample_data_length: usize = header.instrument_meta.iter()
+            .map(|meta| meta.sample_length as usize)
+            .sum();
+        let available = body.len() - pattern_data_length;
+        if available < sample_data_length {
+            return Err(C67LoadError::TruncatedSampleData {
+                expected: sample_data_length,
+                available,
+                file_offset: (HEADER_SIZE + pattern_data_length) as u64,
+            });
+        }
+
+        body.truncate(pattern_data_length + sample_data_length);
+        module.sample_data = body.split_off(pattern_data_length);
+        module.pattern_data = body;
+
+        Ok(module)
+    }
+
+    /// Raw command bytes of a pattern, as addressed by the pattern pointer and length tables.
+    pub fn pattern(&self, index: usize) -> &[u8] {
+        let offset = self.header.pattern_pointers.list[index] as usize;
+        let length = self.header.pattern_lengths.list[index] as usize;
+        &self.pattern_data[offset..offset+length]
+    }
+
+    /// Decoded commands of a pattern.
+    pub fn pattern_commands(&self, index: usize) -> Result<Vec<C67PatternCommand>, PatternDecodeError> {
+        deserialize_pattern(self.pattern(index))
+    }
+
+    /// Unsigned 8-bit audio of a PCM instrument. Samples are stored back to back in instrument order.
+    pub fn sample(&self, index: usize) -> &[u8] {
+        let offset: usize = self.header.instrument_meta[..index].iter()
+            .map(|meta| meta.sample_length as usize)
+            .sum();
+        let length = self.header.instrument_meta[index].sample_length as usize;
+        &self.sample_data[offset..offset+length]
+    }
 }
 
 #[derive(Debug, serde::Serialize)]
@@ -108,12 +236,12 @@ impl Default for C67SampleMetadata {
             _unused: 0,
             sample_length: 0,
             loop_start: 0,
-            loop_end: 0xFFFFF
+            loop_end: NO_LOOP
         }
     }
 }
 
-#[derive(Debug, Default, serde::Serialize)]
+#[derive(Debug, Default, PartialEq, Eq, serde::Serialize)]
 #[repr(C)]
 pub struct C67FMRegisters {
     pub feedback_connection: u8,
@@ -129,7 +257,103 @@ pub struct C67FMRegisters {
     pub carrier_wave_select: u8,
 }
 
+impl C67FMRegisters {
+    pub fn from_bytes(raw: &[u8;11]) -> Self {
+        Self {
+            feedback_connection: raw[0],
+            modulator_characteristics: raw[1],
+            modulator_scale_and_output_level: raw[2],
+            modulator_attack_decay_level: raw[3],
+            modulator_sustain_release_level: raw[4],
+            modulator_wave_select: raw[5],
+            carrier_characteristics: raw[6],
+            carrier_scale_and_output_level: raw[7],
+            carrier_attack_decay_level: raw[8],
+            carrier_sustain_release_level: raw[9],
+            carrier_wave_select: raw[10],
+        }
+    }
+
+    /// The registers in S3M's d00-d0a order, key scale levels the way the
+    /// OPL2 takes them.
+    pub fn s3m_registers(&self) -> [u8;11] {
+        [
+            self.modulator_characteristics,
+            self.carrier_characteristics,
+            swap_key_scale_level(self.modulator_scale_and_output_level),
+            swap_key_scale_level(self.carrier_scale_and_output_level),
+            self.modulator_attack_decay_level,
+            self.carrier_attack_decay_level,
+            self.modulator_sustain_release_level,
+            self.carrier_sustain_release_level,
+            self.modulator_wave_select,
+            self.carrier_wave_select,
+            self.feedback_connection,
+        ]
+    }
+}
+
 #[derive(Debug)]
+pub enum C67LoadError {
+    Io(io::Error),
+    TruncatedHeader { offset: u64 },
+    InvalidSpeed(u8),
+    InvalidOrder { position: usize, pattern: u8 },
+    InvalidSample { index: usize, reason: &'static str },
+    PatternOutOfBounds { pattern: usize, offset: u32, length: u32, file_offset: u64 },
+    TruncatedSampleData { expected: usize, available: usize, file_offset: u64 },
+}
+
+impl fmt::Display for C67LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            C67LoadError::Io(error) => write!(f, "I/O error: {}", error),
+            C67LoadError::TruncatedHeader { offset } => {
+                write!(f, "File ends inside the header at offset 0x{:X}", offset)
+            },
+            C67LoadError::InvalidSpeed(speed) => write!(f, "Invalid speed {}, expected 1-15", speed),
+            C67LoadError::InvalidOrder { position, pattern } => {
+                write!(f, "Order {} refers to pattern {}, only 0-127 exist", position, pattern)
+            },
+            C67LoadError::InvalidSample { index, reason } => {
+                write!(f, "PCM instrument {}: {}", index, reason)
+            },
+            C67LoadError::PatternOutOfBounds { pattern, offset, length, file_offset } => {
+                write!(f, "Pattern {} (offset {}, length {}) runs past the end of the file at offset 0x{:X}",
+                    pattern, offset, length, file_offset)
+            },
+            C67LoadError::TruncatedSampleData { expected, available, file_offset } => {
+                write!(f, "Sample data at offset 0x{:X} is truncated: expected {} bytes, found {}",
+                    file_offset, expected, available)
+            },
+        }
+    }
+}
+
+impl std::error::Error for C67LoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            C67LoadError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for C67LoadError {
+    fn from(error: io::Error) -> Self {
+        C67LoadError::Io(error)
+    }
+}
+
+fn truncated(error: io::Error, offset: u64) -> C67LoadError {
+    if error.kind() == io::ErrorKind::UnexpectedEof {
+        C67LoadError::TruncatedHeader { offset }
+    } else {
+        C67LoadError::Io(error)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum C67PatternCommand {
     PlayNote(PlayNoteCommand),
     SetVolume(SetVolumeCommand),
@@ -148,7 +372,8 @@ impl C67PatternCommand {
                     Channel::FM(num) => data.push(4+num),
                 }
 
-                let mut byte1 = ((command.instrument >> 5) & 1) << 7;
+                // Instruments are 5 bits wide, bit 4 lives in the top bit of the note byte
+                let mut byte1 = ((command.instrument >> 4) & 1) << 7;
                 byte1 |= (command.octave & 7) << 4;
                 byte1 |= command.note & 0xF;
 
@@ -175,19 +400,88 @@ impl C67PatternCommand {
             },
         }
 
-        //dbg!(&data);
-
         data
     }
+
+    /// Decodes a single command from the start of `data`, returning it with its encoded length.
+    pub fn deserialize(data: &[u8]) -> Result<(C67PatternCommand, usize), PatternDecodeError> {
+        let Some(&command_byte) = data.first() else {
+            return Err(PatternDecodeError::Truncated { offset: 0 });
+        };
+
+        let operand = |index: usize| {
+            data.get(index).copied().ok_or(PatternDecodeError::Truncated { offset: 0 })
+        };
+
+        match command_byte {
+            0x00..=0x0C => {
+                let byte1 = operand(1)?;
+                let byte2 = operand(2)?;
+
+                Ok((C67PatternCommand::PlayNote(PlayNoteCommand {
+                    channel: Channel::from_index(command_byte),
+                    octave: (byte1 >> 4) & 7,
+                    note: byte1 & 0xF,
+                    instrument: ((byte1 >> 7) << 4) | (byte2 >> 4),
+                    volume: byte2 & 0xF,
+                }), 3))
+            },
+            0x20..=0x2C => {
+                Ok((C67PatternCommand::SetVolume(SetVolumeCommand {
+                    channel: Channel::from_index(command_byte - 0x20),
+                    volume: operand(1)? & 0xF,
+                }), 2))
+            },
+            0x40 => Ok((C67PatternCommand::Delay(operand(1)?), 2)),
+            0x60 => Ok((C67PatternCommand::End, 1)),
+            _ => Err(PatternDecodeError::UnknownCommand { offset: 0, command: command_byte }),
+        }
+    }
 }
 
-#[derive(Debug)]
+impl fmt::Display for C67PatternCommand {
+    /// Shows the command the way CDFM's editor would, e.g. `FM1 C-4 03 15`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            C67PatternCommand::PlayNote(command) => {
+                let name = NOTE_NAMES.get(command.note as usize).unwrap_or(&"??");
+                write!(f, "{:<4} {}{} {:02} {:02}", command.channel.to_string(), name, command.octave, command.instrument + 1, command.volume)
+            },
+            C67PatternCommand::SetVolume(command) => write!(f, "{:<4} volume {:02}", command.channel.to_string(), command.volume),
+            C67PatternCommand::Delay(rows) => write!(f, "delay {}", rows),
+            C67PatternCommand::End => write!(f, "end"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum Channel {
     PCM(u8),
     FM(u8)
 }
 
-#[derive(Debug)]
+impl fmt::Display for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Channel::PCM(index) => write!(f, "PCM{}", index + 1),
+            Channel::FM(index) => write!(f, "FM{}", index + 1),
+        }
+    }
+}
+
+impl Channel {
+    /// Maps a CDFM channel number (0-3 PCM, 4-12 FM) to a channel.
+    pub fn from_index(index: u8) -> Channel {
+        if index < 4 {
+            Channel::PCM(index)
+        } else {
+            Channel::FM(index - 4)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct PlayNoteCommand {
     pub channel: Channel,
     pub octave: u8,
@@ -196,7 +490,7 @@ pub struct PlayNoteCommand {
     pub volume: u8,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct SetVolumeCommand {
     pub channel: Channel,
     pub volume: u8,
@@ -210,4 +504,181 @@ pub fn serialize_pattern(commands: &[C67PatternCommand]) -> Vec<u8> {
     }
 
     data
-}
\ No newline at end of file
+}
+
+/// Decodes a pattern byte stream up to and including its End command.
+pub fn deserialize_pattern(data: &[u8]) -> Result<Vec<C67PatternCommand>, PatternDecodeError> {
+    let mut commands: Vec<C67PatternCommand> = Vec::new();
+    let mut offset = 0usize;
+
+    while offset < data.len() {
+        let (command, length) = C67PatternCommand::deserialize(&data[offset..])
+            .map_err(|error| error.at(offset))?;
+        offset += length;
+
+        let is_end = command == C67PatternCommand::End;
+        commands.push(command);
+        if is_end {
+            break;
+        }
+    }
+
+    Ok(commands)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PatternDecodeError {
+    Truncated { offset: usize },
+    UnknownCommand { offset: usize, command: u8 },
+}
+
+impl PatternDecodeError {
+    fn at(self, base: usize) -> Self {
+        match self {
+            PatternDecodeError::Truncated { offset } => {
+                PatternDecodeError::Truncated { offset: base + offset }
+            },
+            PatternDecodeError::UnknownCommand { offset, command } => {
+                PatternDecodeError::UnknownCommand { offset: base + offset, command }
+            },
+        }
+    }
+}
+
+impl fmt::Display for PatternDecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PatternDecodeError::Truncated { offset } => {
+                write!(f, "Pattern data ends inside the command at offset {}", offset)
+            },
+            PatternDecodeError::UnknownCommand { offset, command } => {
+                write!(f, "Unknown pattern command 0x{:02X} at offset {}", command, offset)
+            },
+        }
+    }
+}
+
+impl std::error::Error for PatternDecodeError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_module() -> C67Module {
+        let mut module = C67Module::default();
+        let header = &mut module.header;
+        header.speed = 6;
+        header.loop_order = 1;
+        header.instrument_filenames[..8].copy_from_slice(b"KICK.SMP");
+        header.instrument_meta[0] = C67SampleMetadata { sample_length: 100, ..Default::default() };
+        header.instrument_meta[2] = C67SampleMetadata { sample_length: 40, loop_start: 10, loop_end: 40, ..Default::default() };
+        header.adlib_instrument_filenames[13..21].copy_from_slice(b"BASS.SBI");
+        header.adlib_instrument_meta[1] = C67FMRegisters::from_bytes(&[0x0E, 0x21, 0x4F, 0xF2, 0x52, 0x01, 0x31, 0x00, 0xD2, 0x73, 0x02]);
+        header.playlist = [0xFF;256];
+        header.playlist[..3].copy_from_slice(&[0, 1, 0]);
+
+        let patterns = [
+            serialize_pattern(&[C67PatternCommand::Delay(64), C67PatternCommand::End]),
+            serialize_pattern(&[
+                C67PatternCommand::PlayNote(PlayNoteCommand { channel: Channel::FM(1), octave: 4, note: 0, instrument: 1, volume: 15 }),
+                C67PatternCommand::Delay(32),
+                C67PatternCommand::End,
+            ]),
+        ];
+        for (index, pattern) in patterns.iter().enumerate() {
+            header.pattern_pointers.list[index] = module.pattern_data.len() as u32;
+            header.pattern_lengths.list[index] = pattern.len() as u32;
+            module.pattern_data.extend_from_slice(pattern);
+        }
+        module.sample_data = (0..140).map(|index| index as u8).collect();
+
+        module
+    }
+
+    #[test]
+    fn serialize_and_load_round_trip() {
+        let module = test_module();
+        let data = module.serialize();
+        assert_eq!(data.len(), HEADER_SIZE + module.pattern_data.len() + 140);
+
+        let loaded = C67Module::load(data.as_slice()).unwrap();
+        assert_eq!((loaded.header.speed, loaded.header.loop_order), (6, 1));
+        assert_eq!(loaded.header.instrument_filenames, module.header.instrument_filenames);
+        assert_eq!(loaded.header.adlib_instrument_filenames, module.header.adlib_instrument_filenames);
+        assert_eq!(loaded.header.adlib_instrument_meta, module.header.adlib_instrument_meta);
+        assert_eq!(loaded.header.playlist, module.header.playlist);
+        assert_eq!(loaded.pattern_data, module.pattern_data);
+        assert_eq!(loaded.sample(2), &module.sample_data[100..]);
+        assert_eq!(loaded.serialize(), data);
+    }
+
+    #[test]
+    fn truncated_header_reports_the_field_it_ended_in() {
+        let data = test_module().serialize();
+        let truncated_at = |length: usize| match C67Module::load(&data[..length]) {
+            Err(C67LoadError::TruncatedHeader { offset }) => offset,
+            other => panic!("expected a truncated header, got {:?}", other.map(|_| ())),
+        };
+
+        assert_eq!(truncated_at(0), 0);
+        assert_eq!(truncated_at(1), 1);
+        // Inside instrument 2's sample length
+        assert_eq!(truncated_at(0x1C7), 0x1C6);
+        // Inside FM instrument 17's registers
+        assert_eq!(truncated_at(0x600), 0x5FD);
+        assert_eq!(truncated_at(0x700), 0x6A2);
+        assert_eq!(truncated_at(HEADER_SIZE - 1), 0x9A2);
+    }
+
+    #[test]
+    fn invalid_playlist_and_bodies_are_rejected() {
+        let mut module = test_module();
+        module.header.playlist[5] = 0x80;
+        assert!(matches!(
+            C67Module::load(module.serialize().as_slice()),
+            Err(C67LoadError::InvalidOrder { position: 5, pattern: 0x80 })
+        ));
+
+        let module = test_module();
+        let data = module.serialize();
+        let patterns_end = HEADER_SIZE + module.pattern_data.len();
+        assert!(matches!(
+            C67Module::load(&data[..patterns_end - 1]),
+            Err(C67LoadError::PatternOutOfBounds { pattern: 1, file_offset, .. }) if file_offset == (HEADER_SIZE + 3) as u64
+        ));
+        assert!(matches!(
+            C67Module::load(&data[..patterns_end + 50]),
+            Err(C67LoadError::TruncatedSampleData { expected: 140, available: 50, file_offset }) if file_offset == patterns_end as u64
+        ));
+    }
+
+    #[test]
+    fn pattern_commands_round_trip() {
+        let commands = vec![
+            C67PatternCommand::PlayNote(PlayNoteCommand { channel: Channel::PCM(0), octave: 2, note: 0, instrument: 0, volume: 15 }),
+            C67PatternCommand::PlayNote(PlayNoteCommand { channel: Channel::PCM(3), octave: 7, note: 11, instrument: 31, volume: 0 }),
+            C67PatternCommand::PlayNote(PlayNoteCommand { channel: Channel::FM(0), octave: 4, note: 9, instrument: 16, volume: 8 }),
+            C67PatternCommand::PlayNote(PlayNoteCommand { channel: Channel::FM(8), octave: 0, note: 5, instrument: 15, volume: 3 }),
+            C67PatternCommand::SetVolume(SetVolumeCommand { channel: Channel::PCM(2), volume: 7 }),
+            C67PatternCommand::SetVolume(SetVolumeCommand { channel: Channel::FM(8), volume: 15 }),
+            C67PatternCommand::Delay(1),
+            C67PatternCommand::Delay(255),
+            C67PatternCommand::End,
+        ];
+
+        let data = serialize_pattern(&commands);
+        assert_eq!(deserialize_pattern(&data), Ok(commands));
+    }
+
+    #[test]
+    fn decoding_stops_at_end() {
+        let data = serialize_pattern(&[C67PatternCommand::Delay(64), C67PatternCommand::End, C67PatternCommand::Delay(3)]);
+        assert_eq!(deserialize_pattern(&data), Ok(vec![C67PatternCommand::Delay(64), C67PatternCommand::End]));
+    }
+
+    #[test]
+    fn decoding_reports_where_it_failed() {
+        assert_eq!(deserialize_pattern(&[0x40, 0x10, 0x05, 0x20]), Err(PatternDecodeError::Truncated { offset: 2 }));
+        assert_eq!(deserialize_pattern(&[0x40, 0x01, 0x10]), Err(PatternDecodeError::UnknownCommand { offset: 2, command: 0x10 }));
+    }
+}
@@ -0,0 +1,621 @@
+use std::{fmt, io::{self, Read}};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+
+use crate::{diagnostics::{Category, Diagnostic, Diagnostics, Severity}, format_s3m::{S3MColumn, S3MInstrument, S3MModule, S3MRow, S3MSample, EFFECT_BREAK, EFFECT_PORTAMENTO_DOWN, EFFECT_PORTAMENTO_UP, EFFECT_SET_TEMPO, EFFECT_TONE_PORTAMENTO, EFFECT_VIBRATO, EFFECT_VOLUME_SLIDE}, import::{pcm_channel_setting, split_patterns, unroll_ping_pong, TooManyPatterns}};
+
+const HEADER_SIZE: u64 = 0xC0;
+const SAMPLE_HEADER_SIZE: usize = 0x50;
+/// Bytes of an instrument header up to the end of its keyboard table.
+const INSTRUMENT_HEADER_SIZE: usize = 0x130;
+/// More patterns than any IT tracker saves.
+const MAX_PATTERNS: usize = 256;
+/// Rows per pattern IT allows.
+const ROWS: std::ops::RangeInclusive<usize> = 32..=200;
+
+/// Uxy, IT's fine vibrato past ST3's last effect. It becomes a shallower Hxy.
+const EFFECT_FINE_VIBRATO: u8 = 21;
+
+/// Gxx speeds of volume column tone portamento 0-9.
+const VOLUME_TONE_PORTAMENTO: [u8;10] = [0x00, 0x01, 0x04, 0x08, 0x10, 0x20, 0x40, 0x60, 0x80, 0xFF];
+
+/// Loads an Impulse Tracker module, including IT214 and IT215 compressed
+/// samples. In instrument mode each instrument becomes the sample its
+/// keyboard table plays most, envelopes and new note actions are dropped.
+/// Only the first 32 channels are kept, patterns are laid out as 64 row S3M
+/// patterns.
+pub fn load(mut reader: impl io::Read) -> Result<S3MModule, ITLoadError> {
+    let mut data: Vec<u8> = Vec::new();
+    reader.read_to_end(&mut data)?;
+
+    if data.len() < 4 || &data[..4] != b"IMPM" {
+        return Err(ITLoadError::BadMagic);
+    }
+
+    let mut reader = io::Cursor::new(&data[..]);
+    let module = read_module(&mut reader);
+    module.map_err(|error| match error {
+        ITLoadError::Io(error) if error.kind() == io::ErrorKind::UnexpectedEof => {
+            ITLoadError::Truncated { offset: reader.position() }
+        },
+        error => error,
+    })
+}
+
+fn read_module(reader: &mut io::Cursor<&[u8]>) -> Result<S3MModule, ITLoadError> {
+    let mut module = S3MModule {
+        // Scream Tracker 3.20, signed samples
+        tracker_metadata: 0x1320,
+        ffi: 1,
+        _scrm: 0x4D524353,
+        // Stereo
+        mixing_volume: 0xB0,
+        ..Default::default()
+    };
+
+    reader.set_position(4);
+    reader.read_exact(&mut module.song_name[..26])?;
+    let _highlight = reader.read_u16::<LittleEndian>()?;
+    let order_amount = reader.read_u16::<LittleEndian>()? as usize;
+    let instrument_amount = reader.read_u16::<LittleEndian>()? as usize;
+    let sample_amount = reader.read_u16::<LittleEndian>()? as usize;
+    let pattern_amount = reader.read_u16::<LittleEndian>()? as usize;
+    let _created_with = reader.read_u16::<LittleEndian>()?;
+    let compatible_with = reader.read_u16::<LittleEndian>()?;
+    let flags = reader.read_u16::<LittleEndian>()?;
+    let special = reader.read_u16::<LittleEndian>()?;
+    module.global_volume = reader.read_u8()? / 2;
+    let _mixing_volume = reader.read_u8()?;
+    module.initial_speed = reader.read_u8()?.max(1);
+    module.initial_tempo = reader.read_u8()?.max(32);
+    let _separation = reader.read_u8()?;
+    let _pitch_wheel_depth = reader.read_u8()?;
+    let message_length = reader.read_u16::<LittleEndian>()? as usize;
+    let message_offset = reader.read_u32::<LittleEndian>()? as usize;
+    let _reserved = reader.read_u32::<LittleEndian>()?;
+    let mut channel_pannings = [0u8;64];
+    reader.read_exact(&mut channel_pannings)?;
+    if pattern_amount > MAX_PATTERNS {
+        return Err(ITLoadError::PatternAmount(pattern_amount));
+    }
+
+    reader.set_position(HEADER_SIZE);
+    let mut orders = vec![0u8;order_amount];
+    reader.read_exact(&mut orders)?;
+    let mut offsets = |amount: usize| -> io::Result<Vec<u32>> {
+        let mut offsets = vec![0u32;amount];
+        reader.read_u32_into::<LittleEndian>(&mut offsets)?;
+        Ok(offsets)
+    };
+    let instrument_offsets = offsets(instrument_amount)?;
+    let sample_offsets = offsets(sample_amount)?;
+    let pattern_offsets = offsets(pattern_amount)?;
+    let data = *reader.get_ref();
+
+    if special & 1 != 0 && message_length != 0 {
+        match data.get(message_offset..message_offset+message_length) {
+            Some(text) => {
+                let text = text.split(|byte| *byte == 0).next().unwrap_or_default();
+                module.message = Some(String::from_utf8_lossy(text).replace('\r', "\n"));
+            },
+            None => module.diagnostics.push(Diagnostic::new(
+                Severity::Warning,
+                Category::DamagedFile,
+                format!("Song message at offset 0x{:X} lies past the end of the file, ignoring it", message_offset),
+            )),
+        }
+    }
+
+    // Panning 128 and up marks disabled channels
+    module.channel_settings = [0xFF;32];
+    for (index, setting) in module.channel_settings.iter_mut().enumerate() {
+        if channel_pannings[index] < 128 {
+            *setting = pcm_channel_setting(index);
+        }
+    }
+
+    let mut samples: Vec<S3MSample> = Vec::new();
+    for (index, offset) in sample_offsets.iter().enumerate() {
+        samples.push(read_sample(data, index, *offset as usize, &mut module.diagnostics)?);
+    }
+
+    if flags & 4 != 0 {
+        for (index, offset) in instrument_offsets.iter().enumerate() {
+            let Some(header) = data.get(*offset as usize..*offset as usize + INSTRUMENT_HEADER_SIZE) else {
+                return Err(ITLoadError::InstrumentOutOfBounds { index, offset: *offset as u64 });
+            };
+            // Instruments from before IT 2.00 have no global volume
+            let global_volume = if compatible_with >= 0x200 { header[0x18].min(128) } else { 128 };
+            module.instruments.push(S3MInstrument::Sample(instrument_sample(header, global_volume, &samples, index, &mut module.diagnostics)));
+        }
+    } else {
+        module.instruments = samples.into_iter().map(S3MInstrument::Sample).collect();
+    }
+
+    let mut patterns: Vec<Vec<S3MRow>> = Vec::new();
+    let mut dropped_channels = false;
+    for (index, offset) in pattern_offsets.iter().enumerate() {
+        if *offset == 0 {
+            // Stored as nothing, played as 64 empty rows
+            patterns.push(vec![S3MRow::default();64]);
+            continue;
+        }
+        let offset = *offset as usize;
+        let Some(header) = data.get(offset..offset+8) else {
+            return Err(ITLoadError::PatternOutOfBounds { index, offset: offset as u64 });
+        };
+        let packed_length = u16::from_le_bytes([header[0], header[1]]) as usize;
+        let rows = u16::from_le_bytes([header[2], header[3]]) as usize;
+        if !ROWS.contains(&rows) {
+            return Err(ITLoadError::BadRowCount { index, offset: offset as u64, rows });
+        }
+        let Some(packed) = data.get(offset+8..offset+8+packed_length) else {
+            return Err(ITLoadError::PatternOutOfBounds { index, offset: offset as u64 });
+        };
+        let (pattern, dropped) = unpack_pattern(packed, rows);
+        dropped_channels |= dropped;
+        patterns.push(pattern);
+    }
+    if dropped_channels {
+        module.diagnostics.push(Diagnostic::new(Severity::Warning, Category::DroppedNote, "Notes past channel 32 are dropped"));
+    }
+
+    (module.patterns, module.orders) = split_patterns(&patterns, &orders, 0, &mut module.diagnostics)?;
+
+    module.order_amount = module.orders.len() as u16;
+    module.sample_amount = module.instruments.len() as u16;
+    module.pattern_amount = module.patterns.len() as u16;
+
+    Ok(module)
+}
+
+fn read_sample(data: &[u8], index: usize, offset: usize, diagnostics: &mut Diagnostics) -> Result<S3MSample, ITLoadError> {
+    let Some(header) = data.get(offset..offset+SAMPLE_HEADER_SIZE) else {
+        return Err(ITLoadError::SampleOutOfBounds { index, offset: offset as u64, length: SAMPLE_HEADER_SIZE as u64 });
+    };
+    let dword = |offset: usize| u32::from_le_bytes(header[offset..offset+4].try_into().unwrap());
+
+    let mut sample = S3MSample::default();
+    sample.filename.copy_from_slice(&header[0x04..0x10]);
+    sample.sample_name[..26].copy_from_slice(&header[0x14..0x2E]);
+
+    let flags = header[0x12];
+    if flags & 1 == 0 {
+        return Ok(sample);
+    }
+    let global_volume = header[0x11].min(64) as u32;
+    let conversion = header[0x2E];
+    let length = dword(0x30) as usize;
+    let sixteen_bit = flags & 2 != 0;
+    let data_offset = dword(0x48) as usize;
+
+    let audio = if flags & 8 != 0 {
+        decompress(data.get(data_offset..).unwrap_or_default(), length, sixteen_bit, conversion & 4 != 0)
+    } else {
+        let byte_length = if sixteen_bit { length * 2 } else { length };
+        let Some(raw) = data.get(data_offset..data_offset+byte_length) else {
+            return Err(ITLoadError::SampleOutOfBounds { index, offset: data_offset as u64, length: byte_length as u64 });
+        };
+        // Stereo samples store the left channel first, which is all we keep
+        let signed = conversion & 1 != 0;
+        if sixteen_bit {
+            raw.chunks_exact(2)
+                .map(|x| {
+                    let value = u16::from_le_bytes([x[0], x[1]]);
+                    (if signed { value } else { value ^ 0x8000 }) as i16
+                })
+                .collect()
+        } else {
+            raw.iter()
+                .map(|x| (if signed { *x } else { *x ^ 0x80 }) as i8 as i16 * 256)
+                .collect()
+        }
+    };
+    if audio.len() < length {
+        diagnostics.push(Diagnostic::new(
+            Severity::Warning,
+            Category::DamagedFile,
+            format!("Sample {} at offset 0x{:X} ends after {} of its {} samples", index + 1, data_offset, audio.len(), length),
+        ));
+    }
+
+    sample.sample_type = 1;
+    sample.volume = (header[0x13].min(64) as u32 * global_volume / 64) as u8;
+    sample.c4speed = dword(0x3C);
+    sample.length = audio.len() as u32;
+    sample.audio = audio;
+
+    // The sustain loop is what plays while a note is held, C67 never lets go
+    let (loop_flag, ping_pong_flag, loop_begin, loop_end) = if flags & 0x20 != 0 {
+        (true, flags & 0x80 != 0, dword(0x40), dword(0x44))
+    } else {
+        (flags & 0x10 != 0, flags & 0x40 != 0, dword(0x34), dword(0x38))
+    };
+    let loop_end = loop_end.min(sample.length);
+    if loop_flag && loop_end > loop_begin {
+        sample.loop_begin = loop_begin;
+        sample.loop_end = loop_end;
+        sample.flags = 1;
+        if ping_pong_flag {
+            unroll_ping_pong(&mut sample);
+        }
+    }
+
+    Ok(sample)
+}
+
+/// Unpacks IT214 (or IT215, with `double_delta`) compressed sample data,
+/// stored as blocks of variable bit width deltas.
+fn decompress(data: &[u8], length: usize, sixteen_bit: bool, double_delta: bool) -> Vec<i16> {
+    let block_length = if sixteen_bit { 0x4000 } else { 0x8000 };
+    let full_width: u32 = if sixteen_bit { 17 } else { 9 };
+    let value_bits = full_width - 1;
+    // Values just below the largest of a width switch to another width
+    let (border_offset, border_range) = if sixteen_bit { (8, 16) } else { (4, 8) };
+
+    let mut audio: Vec<i16> = Vec::with_capacity(length);
+    let mut position = 0;
+    while audio.len() < length {
+        let Some(size) = data.get(position..position+2) else {
+            break;
+        };
+        let size = u16::from_le_bytes([size[0], size[1]]) as usize;
+        let block = &data[(position + 2).min(data.len())..(position + 2 + size).min(data.len())];
+        position += 2 + size;
+
+        let mut bits = BitReader { data: block, position: 0 };
+        let mut width = full_width;
+        let (mut delta, mut double) = (0i32, 0i32);
+        let count = block_length.min(length - audio.len());
+        let mut decoded = 0;
+        while decoded < count {
+            let Some(value) = bits.read(width) else {
+                break;
+            };
+
+            // Some values change the width instead of being one
+            if width < 7 {
+                if value == 1 << (width - 1) {
+                    let Some(new_width) = bits.read(if sixteen_bit { 4 } else { 3 }) else {
+                        break;
+                    };
+                    let new_width = new_width + 1;
+                    width = if new_width < width { new_width } else { new_width + 1 };
+                    continue;
+                }
+            } else if width < full_width {
+                let border = (((1u32 << value_bits) - 1) >> (full_width - width)) - border_offset;
+                if value > border && value <= border + border_range {
+                    let new_width = value - border;
+                    width = if new_width < width { new_width } else { new_width + 1 };
+                    continue;
+                }
+            } else if value & (1 << value_bits) != 0 {
+                // Widths outside 1 to `full_width` only come from corrupt data
+                let new_width = (value + 1) & 0xFF;
+                if new_width == 0 || new_width > full_width {
+                    break;
+                }
+                width = new_width;
+                continue;
+            }
+
+            // Sign extend from the current width
+            let shift = 32 - width.min(value_bits);
+            let value = ((value << shift) as i32) >> shift;
+            delta = delta.wrapping_add(value);
+            double = double.wrapping_add(delta);
+            let output = if double_delta { double } else { delta };
+            audio.push(if sixteen_bit { output as i16 } else { (output as i8 as i16) * 256 });
+            decoded += 1;
+        }
+        if decoded < count {
+            break;
+        }
+    }
+
+    audio
+}
+
+/// Reads bits least significant first.
+struct BitReader<'d> {
+    data: &'d [u8],
+    position: usize,
+}
+
+impl BitReader<'_> {
+    fn read(&mut self, width: u32) -> Option<u32> {
+        let mut value = 0;
+        for bit in 0..width {
+            let byte = *self.data.get(self.position / 8)?;
+            value |= (((byte >> (self.position % 8)) & 1) as u32) << bit;
+            self.position += 1;
+        }
+        Some(value)
+    }
+}
+
+/// The sample an instrument's keyboard table plays most, at the volume of
+/// the instrument.
+fn instrument_sample(header: &[u8], global_volume: u8, samples: &[S3MSample], index: usize, diagnostics: &mut Diagnostics) -> S3MSample {
+    let mut counts = vec![0usize;samples.len() + 1];
+    for entry in header[0x40..0x130].chunks(2) {
+        if let Some(count) = counts.get_mut(entry[1] as usize) {
+            *count += 1;
+        }
+    }
+    counts[0] = 0;
+
+    let mut sample = match (1..counts.len()).max_by_key(|sample| (counts[*sample], usize::MAX - sample)) {
+        Some(chosen) if counts[chosen] > 0 => {
+            if counts.iter().filter(|count| **count > 0).count() > 1 {
+                diagnostics.push(
+                    Diagnostic::new(Severity::Warning, Category::DroppedInstrument, format!("Plays several samples, keeping sample {}", chosen))
+                        .with_instrument(index)
+                );
+            }
+            samples[chosen - 1].clone()
+        },
+        _ => S3MSample::default(),
+    };
+
+    sample.volume = (sample.volume as u32 * global_volume as u32 / 128) as u8;
+    sample.sample_name[..26].copy_from_slice(&header[0x20..0x3A]);
+    sample
+}
+
+/// Unpacks a pattern, and whether notes past channel 32 were dropped.
+fn unpack_pattern(data: &[u8], rows: usize) -> (Vec<S3MRow>, bool) {
+    let mut pattern = vec![S3MRow::default();rows];
+    let mut dropped = false;
+    let mut masks = [0u8;64];
+    let mut last = [(0u8, 0u8, 0u8, 0u8, 0u8);64];
+
+    let mut position = 0;
+    let mut next = || -> Option<u8> {
+        let byte = *data.get(position)?;
+        position += 1;
+        Some(byte)
+    };
+
+    let mut row = 0;
+    while row < rows {
+        let Some(channel_variable) = next() else {
+            break;
+        };
+        if channel_variable == 0 {
+            row += 1;
+            continue;
+        }
+
+        let channel = ((channel_variable - 1) & 63) as usize;
+        if channel_variable & 0x80 != 0 {
+            let Some(mask) = next() else {
+                break;
+            };
+            masks[channel] = mask;
+        }
+        let mask = masks[channel];
+        let (mut note, mut instrument, mut volume, mut effect, mut param) = (None, 0, None, 0, 0);
+        let previous = &mut last[channel];
+
+        // Low bits bring new values, high bits repeat the last ones
+        let read = (|| -> Option<()> {
+            if mask & 1 != 0 {
+                previous.0 = next()?;
+                note = Some(previous.0);
+            }
+            if mask & 2 != 0 {
+                previous.1 = next()?;
+                instrument = previous.1;
+            }
+            if mask & 4 != 0 {
+                previous.2 = next()?;
+                volume = Some(previous.2);
+            }
+            if mask & 8 != 0 {
+                previous.3 = next()?;
+                previous.4 = next()?;
+                (effect, param) = (previous.3, previous.4);
+            }
+            Some(())
+        })();
+        if read.is_none() {
+            break;
+        }
+        if mask & 16 != 0 { note = Some(previous.0); }
+        if mask & 32 != 0 { instrument = previous.1; }
+        if mask & 64 != 0 { volume = Some(previous.2); }
+        if mask & 128 != 0 { (effect, param) = (previous.3, previous.4); }
+
+        if channel >= 32 {
+            dropped |= note.is_some();
+            continue;
+        }
+        pattern[row][channel] = convert_cell(note, instrument, volume, effect, param);
+    }
+
+    (pattern, dropped)
+}
+
+fn convert_cell(note: Option<u8>, instrument: u8, volume: Option<u8>, effect: u8, param: u8) -> S3MColumn {
+    let mut col = S3MColumn { instrument, ..Default::default() };
+    col.note = match note {
+        // IT's C-5 plays the sample at its C5 rate, like ST3's C-4
+        Some(note @ 12..=107) => (((note - 12) / 12) << 4) | ((note - 12) % 12),
+        // Note off, cut and fade
+        Some(120..) => 254,
+        _ => 255,
+    };
+
+    // IT keeps ST3's effect letters
+    (col.effect, col.effect_value) = match effect {
+        // Plain row numbers until the patterns are laid out
+        EFFECT_BREAK => (EFFECT_BREAK, param),
+        EFFECT_FINE_VIBRATO => (EFFECT_VIBRATO, (param & 0xF0) | ((param & 0xF) / 4)),
+        // T0x and T1x slide the tempo
+        EFFECT_SET_TEMPO if param < 0x20 => (0, 0),
+        // ST3 has M, N, P, U and up only partly or not at all
+        1..=20 if !matches!(effect, 13 | 14 | 16) => (effect, param),
+        _ => (0, 0),
+    };
+
+    match volume {
+        Some(volume @ 0..=64) => col.vol = volume,
+        _ if col.effect != 0 => {},
+        Some(volume @ 65..=74) if volume > 65 => (col.effect, col.effect_value) = (EFFECT_VOLUME_SLIDE, ((volume - 65) << 4) | 0xF),
+        Some(volume @ 75..=84) if volume > 75 => (col.effect, col.effect_value) = (EFFECT_VOLUME_SLIDE, 0xF0 | (volume - 75)),
+        Some(volume @ 85..=94) => (col.effect, col.effect_value) = (EFFECT_VOLUME_SLIDE, (volume - 85) << 4),
+        Some(volume @ 95..=104) => (col.effect, col.effect_value) = (EFFECT_VOLUME_SLIDE, volume - 95),
+        Some(volume @ 105..=114) => (col.effect, col.effect_value) = (EFFECT_PORTAMENTO_DOWN, (volume - 105) * 4),
+        Some(volume @ 115..=124) => (col.effect, col.effect_value) = (EFFECT_PORTAMENTO_UP, (volume - 115) * 4),
+        Some(volume @ 193..=202) => (col.effect, col.effect_value) = (EFFECT_TONE_PORTAMENTO, VOLUME_TONE_PORTAMENTO[(volume - 193) as usize]),
+        Some(volume @ 203..=212) => (col.effect, col.effect_value) = (EFFECT_VIBRATO, volume - 203),
+        _ => {},
+    }
+
+    col
+}
+
+#[derive(Debug)]
+pub enum ITLoadError {
+    Io(io::Error),
+    BadMagic,
+    Truncated { offset: u64 },
+    InstrumentOutOfBounds { index: usize, offset: u64 },
+    SampleOutOfBounds { index: usize, offset: u64, length: u64 },
+    /// More patterns than IT allows.
+    PatternAmount(usize),
+    BadRowCount { index: usize, offset: u64, rows: usize },
+    PatternOutOfBounds { index: usize, offset: u64 },
+    TooManyPatterns { patterns: usize, orders: usize },
+}
+
+impl fmt::Display for ITLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ITLoadError::Io(error) => write!(f, "I/O error: {}", error),
+            ITLoadError::BadMagic => write!(f, "Not an IT module, \"IMPM\" is missing"),
+            ITLoadError::Truncated { offset } => {
+                write!(f, "File ends early at offset 0x{:X}", offset)
+            },
+            ITLoadError::InstrumentOutOfBounds { index, offset } => {
+                write!(f, "Instrument {} at offset 0x{:X} lies past the end of the file", index + 1, offset)
+            },
+            ITLoadError::SampleOutOfBounds { index, offset, length } => {
+                write!(f, "Sample {} (offset 0x{:X}, {} bytes) runs past the end of the file", index + 1, offset, length)
+            },
+            ITLoadError::PatternAmount(amount) => {
+                write!(f, "{} patterns, IT modules hold at most {}", amount, MAX_PATTERNS)
+            },
+            ITLoadError::BadRowCount { index, offset, rows } => {
+                write!(f, "Pattern {} at offset 0x{:X} has {} rows, expected {}-{}", index, offset, rows, ROWS.start(), ROWS.end())
+            },
+            ITLoadError::PatternOutOfBounds { index, offset } => {
+                write!(f, "Pattern {} at offset 0x{:X} runs past the end of the file", index, offset)
+            },
+            ITLoadError::TooManyPatterns { patterns, orders } => {
+                write!(f, "{}", TooManyPatterns { patterns: *patterns, orders: *orders })
+            },
+        }
+    }
+}
+
+impl std::error::Error for ITLoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ITLoadError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ITLoadError {
+    fn from(error: io::Error) -> Self {
+        ITLoadError::Io(error)
+    }
+}
+
+impl From<TooManyPatterns> for ITLoadError {
+    fn from(error: TooManyPatterns) -> Self {
+        ITLoadError::TooManyPatterns { patterns: error.patterns, orders: error.orders }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A compressed block of `(value, width)` fields, packed least
+    /// significant bit first behind the block's length.
+    fn block(fields: &[(u32, u32)]) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::new();
+        let mut bit = 0;
+        for (value, width) in fields {
+            for index in 0..*width {
+                if bit % 8 == 0 {
+                    bytes.push(0);
+                }
+                *bytes.last_mut().unwrap() |= (((value >> index) & 1) as u8) << (bit % 8);
+                bit += 1;
+            }
+        }
+        let mut data = (bytes.len() as u16).to_le_bytes().to_vec();
+        data.extend_from_slice(&bytes);
+        data
+    }
+
+    /// 8-bit deltas 5 and -3 at 9 bits, 3 and -1 at 4 bits, 10 at 7 bits.
+    fn eight_bit_block() -> Vec<u8> {
+        block(&[
+            (5, 9), (0xFD, 9),
+            // Bit 8 set switches to the width in the low byte, minus one
+            (0x103, 9),
+            (3, 4), (0xF, 4),
+            // The top value of a narrow width brings 3 bits of new width
+            (8, 4), (5, 3),
+            (10, 7),
+        ])
+    }
+
+    #[test]
+    fn it214_follows_width_changes() {
+        let audio = decompress(&eight_bit_block(), 5, false, false);
+        assert_eq!(audio, [5, 2, 5, 4, 14].map(|value| value * 256));
+    }
+
+    #[test]
+    fn it215_adds_the_deltas_twice() {
+        let audio = decompress(&eight_bit_block(), 5, false, true);
+        assert_eq!(audio, [5, 7, 12, 16, 30].map(|value| value * 256));
+    }
+
+    #[test]
+    fn sixteen_bit_deltas_are_sign_extended() {
+        assert_eq!(decompress(&block(&[(1000, 17), (0xFFFE, 17)]), 2, true, false), vec![1000, 998]);
+    }
+
+    #[test]
+    fn invalid_widths_stop_decoding() {
+        // Width switches to 0 and to 10 in an 8-bit block
+        assert!(decompress(&block(&[(0x1FF, 9), (1, 9)]), 2, false, false).is_empty());
+        assert_eq!(decompress(&block(&[(7, 9), (0x109, 9), (1, 9)]), 2, false, false), vec![7 * 256]);
+    }
+
+    #[test]
+    fn masks_repeat_the_last_values() {
+        let data = [
+            // Row 0: new mask with note C-5, instrument 2, volume 32 and A06
+            0x81, 0x0F, 60, 2, 32, 1, 6, 0,
+            // Row 1: new mask repeating all four
+            0x81, 0xF0, 0,
+            // Row 2: the last mask again, and a note on channel 34
+            0x01, 0xA2, 0x01, 60, 0,
+        ];
+        let (pattern, dropped) = unpack_pattern(&data, 4);
+        let cell = S3MColumn { note: 0x40, instrument: 2, vol: 32, effect: 1, effect_value: 6 };
+        assert_eq!([pattern[0][0], pattern[1][0], pattern[2][0]], [cell;3]);
+        assert_eq!(pattern[3][0], S3MColumn::default());
+        assert!(dropped);
+    }
+}
@@ -0,0 +1,268 @@
+use std::{fmt, io};
+
+use crate::{diagnostics::{Category, Diagnostic, Severity}, format_s3m::{S3MColumn, S3MInstrument, S3MModule, S3MRow, S3MSample, EFFECT_ARPEGGIO, EFFECT_BREAK, EFFECT_JUMP, EFFECT_PORTAMENTO_DOWN, EFFECT_PORTAMENTO_UP, EFFECT_PORTAMENTO_VOLUME_SLIDE, EFFECT_RETRIGGER, EFFECT_SAMPLE_OFFSET, EFFECT_SET_SPEED, EFFECT_SET_TEMPO, EFFECT_SPECIAL, EFFECT_TONE_PORTAMENTO, EFFECT_TREMOLO, EFFECT_VIBRATO, EFFECT_VIBRATO_VOLUME_SLIDE, EFFECT_VOLUME_SLIDE}, pitch};
+
+/// Signatures at offset 1080 of the 31 sample, 4 channel variants.
+const FOUR_CHANNEL_TAGS: [&[u8;4];4] = [b"M.K.", b"M!K!", b"FLT4", b"4CHN"];
+const TAG_OFFSET: usize = 1080;
+const SAMPLE_HEADER_SIZE: usize = 30;
+const PATTERN_SIZE: usize = 64 * 4 * 4;
+
+/// C4 rate of each finetune value (0-7, then -8 to -1), as ST3 loads them.
+/// The converter resamples by this rate, so finetune needs no pattern data.
+const FINETUNE_C4SPEEDS: [u32;16] = [
+    8363, 8413, 8463, 8529, 8581, 8651, 8723, 8757,
+    7895, 7941, 7985, 8046, 8107, 8169, 8232, 8280,
+];
+
+/// Loads a 4 channel ProTracker MOD (M.K. and alikes) or a 15 sample
+/// Soundtracker module into the structures an S3M loads into. Periods become
+/// notes at the C4 rate of each sample's finetune, MOD effects become their
+/// ST3 counterparts and the channels are panned like an Amiga's.
+pub fn load(mut reader: impl io::Read) -> Result<S3MModule, MODLoadError> {
+    let mut data: Vec<u8> = Vec::new();
+    reader.read_to_end(&mut data)?;
+
+    let tag: Option<[u8;4]> = data.get(TAG_OFFSET..TAG_OFFSET+4).map(|tag| tag.try_into().unwrap());
+    let sample_amount = match tag {
+        Some(tag) if FOUR_CHANNEL_TAGS.contains(&&tag) => 31,
+        Some(tag) if is_multichannel_tag(&tag) => return Err(MODLoadError::UnsupportedChannels(tag)),
+        _ => 15,
+    };
+
+    let header_size = 20 + sample_amount * SAMPLE_HEADER_SIZE + 2 + 128 + if sample_amount == 31 { 4 } else { 0 };
+    if data.len() < header_size {
+        return Err(MODLoadError::TruncatedHeader { offset: data.len() as u64 });
+    }
+
+    let mut module = S3MModule {
+        // Scream Tracker 3.20, signed samples
+        tracker_metadata: 0x1320,
+        ffi: 1,
+        _scrm: 0x4D524353,
+        global_volume: 64,
+        initial_speed: 6,
+        initial_tempo: 125,
+        // Stereo
+        mixing_volume: 0xB0,
+        ..Default::default()
+    };
+    module.song_name[..20].copy_from_slice(&data[..20]);
+
+    // Amiga channels are panned left, right, right, left
+    module.channel_settings = [0xFF;32];
+    module.channel_settings[..4].copy_from_slice(&[0, 8, 9, 1]);
+
+    let order_offset = 20 + sample_amount * SAMPLE_HEADER_SIZE;
+    let song_length = data[order_offset] as usize;
+    let order_table = &data[order_offset+2..order_offset+2+128];
+    // Without a signature, a module is only recognised by a sane order list
+    if !(1..=128).contains(&song_length) || order_table.iter().any(|pattern| *pattern >= 128) {
+        return Err(MODLoadError::BadOrderList { song_length });
+    }
+    // Every pattern in the table is stored, played or not
+    let pattern_amount = *order_table.iter().max().unwrap() as usize + 1;
+
+    module.orders = order_table[..song_length].to_vec();
+    module.orders.push(255);
+    if !module.orders.len().is_multiple_of(2) {
+        module.orders.push(255);
+    }
+
+    let mut sample_offset = header_size + pattern_amount * PATTERN_SIZE;
+    for index in 0..sample_amount {
+        let header = &data[20 + index*SAMPLE_HEADER_SIZE..20 + (index+1)*SAMPLE_HEADER_SIZE];
+        let word = |offset: usize| u16::from_be_bytes([header[offset], header[offset+1]]) as u32 * 2;
+
+        let mut sample = S3MSample {
+            sample_type: 1,
+            volume: header[25].min(64),
+            c4speed: FINETUNE_C4SPEEDS[(header[24] & 0xF) as usize],
+            _scrs: *b"SCRS",
+            ..Default::default()
+        };
+        sample.sample_name[..22].copy_from_slice(&header[..22]);
+
+        let length = word(22) as usize;
+        let available = data.len().saturating_sub(sample_offset).min(length);
+        if available < length {
+            // Rippers often cut the last sample short
+            module.diagnostics.push(Diagnostic::new(
+                Severity::Warning,
+                Category::DamagedFile,
+                format!("Sample {} at offset 0x{:X} claims {} bytes but the file ends after {}", index + 1, sample_offset, length, available),
+            ));
+        }
+        if available > 0 {
+            sample.audio = data[sample_offset..sample_offset+available]
+                .iter()
+                .map(|x| i8::from_ne_bytes([*x]) as i16 * 256)
+                .collect();
+        }
+        sample.length = sample.audio.len() as u32;
+        sample_offset += length;
+
+        // Loops of one word are ProTracker's way of saying none
+        let loop_begin = word(26).min(sample.length);
+        let loop_end = (loop_begin + word(28)).min(sample.length);
+        if word(28) > 2 && loop_end > loop_begin {
+            sample.loop_begin = loop_begin;
+            sample.loop_end = loop_end;
+            sample.flags = 1;
+        }
+
+        module.instruments.push(S3MInstrument::Sample(sample));
+    }
+
+    for index in 0..pattern_amount {
+        let offset = header_size + index * PATTERN_SIZE;
+        let Some(pattern_data) = data.get(offset..offset+PATTERN_SIZE) else {
+            return Err(MODLoadError::PatternOutOfBounds { index, offset: offset as u64 });
+        };
+
+        let mut pattern = [S3MRow::default();64];
+        for (row, row_data) in pattern.iter_mut().zip(pattern_data.chunks(16)) {
+            for (col, cell) in row.iter_mut().zip(row_data.chunks(4)) {
+                *col = convert_cell(cell.try_into().unwrap());
+            }
+        }
+        module.patterns.push(pattern);
+    }
+
+    module.order_amount = module.orders.len() as u16;
+    module.sample_amount = module.instruments.len() as u16;
+    module.pattern_amount = module.patterns.len() as u16;
+
+    Ok(module)
+}
+
+/// xCHN, xxCH and the other signatures of modules with more than 4 channels.
+fn is_multichannel_tag(tag: &[u8;4]) -> bool {
+    matches!(tag, b"FLT8" | b"OCTA" | b"CD81")
+        || (tag[0].is_ascii_digit() && &tag[1..] == b"CHN")
+        || (tag[..2].iter().all(u8::is_ascii_digit) && &tag[2..] == b"CH")
+}
+
+fn convert_cell(cell: &[u8;4]) -> S3MColumn {
+    let instrument = (cell[0] & 0xF0) | (cell[2] >> 4);
+    let period = (((cell[0] & 0xF) as u16) << 8) | cell[1] as u16;
+    let effect = cell[2] & 0xF;
+    let param = cell[3];
+
+    let mut col = S3MColumn { instrument, ..Default::default() };
+    if period != 0 {
+        // Pattern periods are those of finetune 0, the sample's own rate
+        // takes care of the rest. ST3 periods are four times finer.
+        let semitone = pitch::semitone(period as f64 * 4.0, 8363).round().clamp(0.0, 95.0) as u8;
+        col.note = ((semitone / 12) << 4) | (semitone % 12);
+    }
+    convert_effect(&mut col, effect, param);
+
+    col
+}
+
+/// Sets the ST3 counterpart of MOD effect `effect` (0-F) on a column. XM
+/// shares these, apart from the parameter memory MOD lacks.
+pub fn convert_effect(col: &mut S3MColumn, effect: u8, param: u8) {
+    (col.effect, col.effect_value) = match effect {
+        0x0 if param != 0 => (EFFECT_ARPEGGIO, param),
+        // MOD slides do nothing without a parameter, ST3 ones reuse the last
+        0x1 | 0x2 | 0xA if param == 0 => (0, 0),
+        0x5 if param == 0 => (EFFECT_TONE_PORTAMENTO, 0),
+        0x6 if param == 0 => (EFFECT_VIBRATO, 0),
+        // ST3 reads Ex0-Exf as fine slides
+        0x1 => (EFFECT_PORTAMENTO_UP, param.min(0xDF)),
+        0x2 => (EFFECT_PORTAMENTO_DOWN, param.min(0xDF)),
+        0x3 => (EFFECT_TONE_PORTAMENTO, param),
+        0x4 => (EFFECT_VIBRATO, param),
+        0x5 => (EFFECT_PORTAMENTO_VOLUME_SLIDE, volume_slide(param)),
+        0x6 => (EFFECT_VIBRATO_VOLUME_SLIDE, volume_slide(param)),
+        0x7 => (EFFECT_TREMOLO, param),
+        0x9 => (EFFECT_SAMPLE_OFFSET, param),
+        0xA => (EFFECT_VOLUME_SLIDE, volume_slide(param)),
+        0xB => (EFFECT_JUMP, param),
+        0xC => {
+            col.vol = param.min(64);
+            (0, 0)
+        },
+        // Both store the row as BCD
+        0xD => (EFFECT_BREAK, param),
+        0xE => extended_effect(param >> 4, param & 0xF),
+        0xF if param == 0 => (0, 0),
+        0xF if param < 32 => (EFFECT_SET_SPEED, param),
+        0xF => (EFFECT_SET_TEMPO, param),
+        _ => (0, 0),
+    };
+}
+
+/// MOD slides up when both nibbles are set, and never fine slides, where ST3
+/// would slide down or take DxF and DFx as fine slides.
+fn volume_slide(param: u8) -> u8 {
+    if param >> 4 != 0 {
+        param & 0xF0
+    } else {
+        param
+    }
+}
+
+fn extended_effect(command: u8, value: u8) -> (u8, u8) {
+    match command {
+        0x1 if value != 0 => (EFFECT_PORTAMENTO_UP, 0xF0 | value),
+        0x2 if value != 0 => (EFFECT_PORTAMENTO_DOWN, 0xF0 | value),
+        0x6 => (EFFECT_SPECIAL, 0xB0 | value),
+        0x9 if value != 0 => (EFFECT_RETRIGGER, value),
+        0xA if value != 0 => (EFFECT_VOLUME_SLIDE, (value << 4) | 0xF),
+        0xB if value != 0 => (EFFECT_VOLUME_SLIDE, 0xF0 | value),
+        0xC => (EFFECT_SPECIAL, 0xC0 | value),
+        0xD => (EFFECT_SPECIAL, 0xD0 | value),
+        0xE => (EFFECT_SPECIAL, 0xE0 | value),
+        _ => (0, 0),
+    }
+}
+
+#[derive(Debug)]
+pub enum MODLoadError {
+    Io(io::Error),
+    TruncatedHeader { offset: u64 },
+    /// A signature of a module with more than the 4 channels C67 can play.
+    UnsupportedChannels([u8;4]),
+    /// Not a MOD, or a damaged one: the order list makes no sense.
+    BadOrderList { song_length: usize },
+    PatternOutOfBounds { index: usize, offset: u64 },
+}
+
+impl fmt::Display for MODLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MODLoadError::Io(error) => write!(f, "I/O error: {}", error),
+            MODLoadError::TruncatedHeader { offset } => {
+                write!(f, "File ends inside the header at offset 0x{:X}", offset)
+            },
+            MODLoadError::UnsupportedChannels(tag) => {
+                write!(f, "Modules with more than 4 channels are not supported, found signature \"{}\"",
+                    String::from_utf8_lossy(tag))
+            },
+            MODLoadError::BadOrderList { song_length } => {
+                write!(f, "Not a MOD module, song length {} or its order list is out of range", song_length)
+            },
+            MODLoadError::PatternOutOfBounds { index, offset } => {
+                write!(f, "Pattern {} at offset 0x{:X} lies past the end of the file", index, offset)
+            },
+        }
+    }
+}
+
+impl std::error::Error for MODLoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MODLoadError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for MODLoadError {
+    fn from(error: io::Error) -> Self {
+        MODLoadError::Io(error)
+    }
+}
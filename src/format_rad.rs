@@ -0,0 +1,485 @@
+use std::{fmt, io::{self, Read}};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+
+use crate::diagnostics::{Category, Diagnostic, Diagnostics, Severity};
+use crate::format_s3m::{S3MAdlibInstrument, S3MColumn, S3MInstrument, S3MModule, S3MPattern, S3MRow, S3MSample, EFFECT_BREAK, EFFECT_JUMP, EFFECT_PORTAMENTO_DOWN, EFFECT_PORTAMENTO_UP, EFFECT_PORTAMENTO_VOLUME_SLIDE, EFFECT_SET_SPEED, EFFECT_TONE_PORTAMENTO, EFFECT_VOLUME_SLIDE};
+
+const MAGIC: &[u8;16] = b"RAD by REALiTY!!";
+const VERSION_1: u8 = 0x10;
+const VERSION_2: u8 = 0x21;
+
+/// ST3 tempo of RAD's 18.2 Hz slow timer, 125 being its usual 50 Hz.
+const SLOW_TIMER_TEMPO: u8 = 46;
+
+/// Loads a Reality AdLib Tracker module, version 1 or 2, as an S3M playing
+/// RAD's 9 channels on AdLib channels A1-A9. Instrument registers are kept
+/// as they are, RAD effects become their ST3 counterparts. Version 2 features
+/// OPL2 lacks (4 operator and MIDI instruments, riffs) are dropped, noted in
+/// the module's diagnostics.
+pub fn load(mut reader: impl io::Read) -> Result<S3MModule, RADLoadError> {
+    let mut data: Vec<u8> = Vec::new();
+    reader.read_to_end(&mut data)?;
+
+    if data.len() < MAGIC.len() + 1 || &data[..MAGIC.len()] != MAGIC {
+        return Err(RADLoadError::BadMagic);
+    }
+    let version = data[MAGIC.len()];
+    if version != VERSION_1 && version != VERSION_2 {
+        return Err(RADLoadError::UnsupportedVersion(version));
+    }
+
+    let mut reader = io::Cursor::new(&data[MAGIC.len()+1..]);
+    let offset = |reader: &io::Cursor<&[u8]>| reader.position() + MAGIC.len() as u64 + 1;
+    let mut diagnostics = Diagnostics::default();
+    let song = if version == VERSION_1 {
+        read_v1(&mut reader)
+    } else {
+        read_v2(&mut reader, &mut diagnostics)
+    };
+    let song = song.map_err(|error| match error {
+        RADLoadError::Io(error) if error.kind() == io::ErrorKind::UnexpectedEof => {
+            RADLoadError::Truncated { offset: offset(&reader) }
+        },
+        error => error,
+    })?;
+
+    let mut module = S3MModule {
+        // Scream Tracker 3.20
+        tracker_metadata: 0x1320,
+        ffi: 2,
+        _scrm: 0x4D524353,
+        global_volume: 64,
+        initial_speed: song.speed,
+        initial_tempo: song.tempo,
+        // Stereo
+        mixing_volume: 0xB0,
+        message: song.description,
+        diagnostics,
+        ..Default::default()
+    };
+    module.channel_settings = [0xFF;32];
+    for (index, setting) in module.channel_settings[..9].iter_mut().enumerate() {
+        *setting = 16 + index as u8;
+    }
+
+    for instrument in song.instruments {
+        module.instruments.push(match instrument {
+            Some(instrument) => S3MInstrument::Adlib(instrument),
+            None => S3MInstrument::Sample(S3MSample::default()),
+        });
+    }
+
+    module.patterns = song.patterns;
+    module.orders = song.orders;
+    // Orders may name patterns the file leaves out
+    let used = module.orders.iter().map(|pattern| *pattern as usize + 1).max().unwrap_or(0);
+    if module.patterns.len() < used {
+        module.patterns.resize(used, [S3MRow::default();64]);
+    }
+    // A jump marker loops the song, with a copy of the pattern before it
+    // jumping back from wherever that pattern ends
+    if let Some(target) = song.jump {
+        if let Some(last) = module.orders.last_mut() {
+            let mut pattern = module.patterns[*last as usize];
+            for (index, row) in pattern.iter_mut().enumerate() {
+                if index == 63 || row.iter().any(|col| col.effect == EFFECT_BREAK) {
+                    if let Some(col) = row.iter_mut().find(|col| col.effect == 0) {
+                        col.effect = EFFECT_JUMP;
+                        col.effect_value = target;
+                    }
+                }
+            }
+            *last = module.patterns.len() as u8;
+            module.patterns.push(pattern);
+        }
+    }
+    module.orders.push(255);
+    if !module.orders.len().is_multiple_of(2) {
+        module.orders.push(255);
+    }
+
+    module.order_amount = module.orders.len() as u16;
+    module.sample_amount = module.instruments.len() as u16;
+    module.pattern_amount = module.patterns.len() as u16;
+
+    Ok(module)
+}
+
+/// What both versions boil down to.
+struct RADSong {
+    speed: u8,
+    tempo: u8,
+    description: Option<String>,
+    /// Indexed by instrument number - 1.
+    instruments: Vec<Option<S3MAdlibInstrument>>,
+    patterns: Vec<S3MPattern>,
+    orders: Vec<u8>,
+    /// Order the song continues from after the last one, if not the first.
+    jump: Option<u8>,
+}
+
+fn read_v1(reader: &mut io::Cursor<&[u8]>) -> Result<RADSong, RADLoadError> {
+    let flags = reader.read_u8()?;
+    let description = if flags & 0x80 != 0 {
+        Some(read_description(reader)?)
+    } else {
+        None
+    };
+
+    let mut instruments: Vec<Option<S3MAdlibInstrument>> = Vec::new();
+    loop {
+        let number = reader.read_u8()?;
+        if number == 0 {
+            break;
+        }
+        let mut registers = [0u8;11];
+        reader.read_exact(&mut registers)?;
+
+        // Carrier before modulator: 0x23, 0x20, 0x43, 0x40, 0x63, 0x60, 0x83, 0x80, 0xC0, 0xE3, 0xE0
+        let [d01, d00, d03, d02, d05, d04, d07, d06, d0a, d09, d08] = registers;
+        set_instrument(&mut instruments, number, S3MAdlibInstrument {
+            d00, d01, d02, d03, d04, d05, d06, d07, d08, d09, d0a,
+            ..adlib_instrument(64)
+        });
+    }
+
+    let (orders, jump) = read_orders(reader)?;
+
+    let mut pattern_offsets = [0u16;32];
+    reader.read_u16_into::<LittleEndian>(&mut pattern_offsets)?;
+
+    let data = *reader.get_ref();
+    let mut patterns: Vec<S3MPattern> = Vec::new();
+    for (index, offset) in pattern_offsets.iter().enumerate() {
+        if *offset == 0 {
+            patterns.push([S3MRow::default();64]);
+            continue;
+        }
+        // Offsets count from the start of the file
+        let Some(pattern_data) = (*offset as usize).checked_sub(MAGIC.len() + 1).and_then(|start| data.get(start..)) else {
+            return Err(RADLoadError::PatternOutOfBounds { index, offset: *offset as u64 });
+        };
+        let mut pattern_reader = io::Cursor::new(pattern_data);
+        patterns.push(read_pattern(&mut pattern_reader, VERSION_1).map_err(|_| {
+            RADLoadError::PatternOutOfBounds { index, offset: *offset as u64 }
+        })?);
+    }
+
+    Ok(RADSong {
+        speed: speed(flags),
+        tempo: if flags & 0x40 != 0 { SLOW_TIMER_TEMPO } else { 125 },
+        description,
+        instruments,
+        patterns,
+        orders,
+        jump,
+    })
+}
+
+fn read_v2(reader: &mut io::Cursor<&[u8]>, diagnostics: &mut Diagnostics) -> Result<RADSong, RADLoadError> {
+    let flags = reader.read_u8()?;
+    let tempo = if flags & 0x20 != 0 {
+        // ST3 tempo is BPM, both tick at BPM * 2 / 5 Hz
+        reader.read_u16::<LittleEndian>()?.clamp(32, 255) as u8
+    } else if flags & 0x40 != 0 {
+        SLOW_TIMER_TEMPO
+    } else {
+        125
+    };
+    let description = read_description(reader)?;
+
+    let mut instruments: Vec<Option<S3MAdlibInstrument>> = Vec::new();
+    loop {
+        let number = reader.read_u8()?;
+        if number == 0 {
+            break;
+        }
+        let mut name = vec![0u8; reader.read_u8()? as usize];
+        reader.read_exact(&mut name)?;
+
+        let algorithm_and_panning = reader.read_u8()?;
+        let algorithm = algorithm_and_panning & 7;
+        if algorithm == 7 {
+            // MIDI instrument
+            let mut midi = [0u8;6];
+            reader.read_exact(&mut midi)?;
+            diagnostics.push(
+                Diagnostic::new(Severity::Error, Category::DroppedInstrument, "MIDI instrument, leaving it empty")
+                    .with_instrument(number as usize - 1)
+            );
+        } else {
+            let feedback = reader.read_u8()?;
+            let _detune_and_riff_speed = reader.read_u8()?;
+            let volume = reader.read_u8()?;
+            // Carrier first, as the operators appear in RAD's editor
+            let mut operators = [[0u8;5];4];
+            for operator in &mut operators {
+                reader.read_exact(operator)?;
+            }
+            if algorithm > 1 {
+                diagnostics.push(
+                    Diagnostic::new(Severity::Warning, Category::DroppedInstrument, "Needs 4 operators, keeping its first two")
+                        .with_instrument(number as usize - 1)
+                );
+            }
+
+            let [carrier, modulator, ..] = operators;
+            let mut instrument = S3MAdlibInstrument {
+                d00: modulator[0], d01: carrier[0],
+                d02: modulator[1], d03: carrier[1],
+                d04: modulator[2], d05: carrier[2],
+                d06: modulator[3], d07: carrier[3],
+                d08: modulator[4], d09: carrier[4],
+                d0a: ((feedback & 7) << 1) | (algorithm == 1) as u8,
+                ..adlib_instrument(volume.min(64))
+            };
+            let length = name.len().min(instrument.sample_name.len());
+            instrument.sample_name[..length].copy_from_slice(&name[..length]);
+            set_instrument(&mut instruments, number, instrument);
+        }
+
+        if algorithm_and_panning & 0x80 != 0 {
+            // Instrument riff, played by effects C67 cannot follow
+            let mut riff = vec![0u8; reader.read_u16::<LittleEndian>()? as usize];
+            reader.read_exact(&mut riff)?;
+            diagnostics.push(
+                Diagnostic::new(Severity::Warning, Category::UnsupportedEffect, "Instrument riff dropped")
+                    .with_instrument(number as usize - 1)
+            );
+        }
+    }
+
+    let (orders, jump) = read_orders(reader)?;
+
+    let mut patterns: Vec<S3MPattern> = Vec::new();
+    loop {
+        let index = reader.read_u8()?;
+        if index == 0xFF {
+            break;
+        }
+        let length = reader.read_u16::<LittleEndian>()? as usize;
+        let start = reader.position() as usize;
+        let Some(pattern_data) = reader.get_ref().get(start..start+length) else {
+            return Err(RADLoadError::PatternOutOfBounds { index: index as usize, offset: start as u64 });
+        };
+        let pattern = read_pattern(&mut io::Cursor::new(pattern_data), VERSION_2).map_err(|_| {
+            RADLoadError::PatternOutOfBounds { index: index as usize, offset: start as u64 }
+        })?;
+        reader.set_position((start + length) as u64);
+
+        if patterns.len() <= index as usize {
+            patterns.resize(index as usize + 1, [S3MRow::default();64]);
+        }
+        patterns[index as usize] = pattern;
+    }
+    // Riffs follow, nothing in them plays without effects we drop
+    let riffs = count_riffs(reader);
+    if riffs > 0 {
+        diagnostics.push(Diagnostic::new(Severity::Warning, Category::UnsupportedEffect, format!("{} riffs dropped", riffs)));
+    }
+
+    Ok(RADSong {
+        speed: speed(flags),
+        tempo,
+        description: Some(description).filter(|text| !text.is_empty()),
+        instruments,
+        patterns,
+        orders,
+        jump,
+    })
+}
+
+/// Riffs are stored like patterns, each numbered and sized, up to an 0xFF.
+/// A file cut short in them loses nothing that plays.
+fn count_riffs(reader: &mut io::Cursor<&[u8]>) -> usize {
+    let mut riffs = 0;
+    while reader.read_u8().is_ok_and(|number| number != 0xFF) {
+        let Ok(length) = reader.read_u16::<LittleEndian>() else {
+            break;
+        };
+        reader.set_position(reader.position() + length as u64);
+        riffs += 1;
+    }
+    riffs
+}
+
+fn speed(flags: u8) -> u8 {
+    match flags & 0x1F {
+        0 => 6,
+        speed => speed,
+    }
+}
+
+fn adlib_instrument(volume: u8) -> S3MAdlibInstrument {
+    S3MAdlibInstrument {
+        instrument_type: 2,
+        volume,
+        c4freq: 8363,
+        _scri: *b"SCRI",
+        ..Default::default()
+    }
+}
+
+fn set_instrument(instruments: &mut Vec<Option<S3MAdlibInstrument>>, number: u8, instrument: S3MAdlibInstrument) {
+    let index = number as usize - 1;
+    if instruments.len() <= index {
+        instruments.resize(index + 1, None);
+    }
+    instruments[index] = Some(instrument);
+}
+
+/// Null terminated text. Version 1 packs it: 1 starts a new line, 2-31
+/// stand for that many spaces.
+fn read_description(reader: &mut io::Cursor<&[u8]>) -> io::Result<String> {
+    let mut text = String::new();
+    loop {
+        match reader.read_u8()? {
+            0 => return Ok(text),
+            1 => text.push('\n'),
+            spaces @ 2..=31 => text.extend(std::iter::repeat_n(' ', spaces as usize)),
+            character => text.push(character as char),
+        }
+    }
+}
+
+/// The order list up to its first jump marker, and where that jumps to.
+fn read_orders(reader: &mut io::Cursor<&[u8]>) -> io::Result<(Vec<u8>, Option<u8>)> {
+    let mut entries = vec![0u8; reader.read_u8()? as usize];
+    reader.read_exact(&mut entries)?;
+
+    let mut orders: Vec<u8> = Vec::new();
+    for entry in entries {
+        if entry & 0x80 != 0 {
+            let target = entry & 0x7F;
+            // Orders past the marker can never play
+            let jump = (target != 0 && (target as usize) < orders.len()).then_some(target);
+            return Ok((orders, jump));
+        }
+        orders.push(entry);
+    }
+    Ok((orders, None))
+}
+
+fn read_pattern(reader: &mut io::Cursor<&[u8]>, version: u8) -> io::Result<S3MPattern> {
+    let mut pattern = [S3MRow::default();64];
+    loop {
+        let line = reader.read_u8()?;
+        let row = (line & 0x7F) as usize;
+        loop {
+            let channel_flags = reader.read_u8()?;
+            let channel = (channel_flags & 0xF) as usize;
+
+            let mut col = S3MColumn::default();
+            let (note, instrument, effect) = if version == VERSION_1 {
+                let note = reader.read_u8()?;
+                let instrument_and_effect = reader.read_u8()?;
+                let effect = instrument_and_effect & 0xF;
+                let param = if effect != 0 { reader.read_u8()? } else { 0 };
+                (Some(note & 0x7F), ((note & 0x80) >> 3) | (instrument_and_effect >> 4), Some((effect, param)))
+            } else {
+                let note = if channel_flags & 0x40 != 0 { Some(reader.read_u8()? & 0x7F) } else { None };
+                let instrument = if channel_flags & 0x20 != 0 { reader.read_u8()? & 0x7F } else { 0 };
+                let effect = if channel_flags & 0x10 != 0 {
+                    Some((reader.read_u8()? & 0x1F, reader.read_u8()?))
+                } else {
+                    None
+                };
+                (note, instrument, effect)
+            };
+
+            col.note = match note.map(|note| (note >> 4, note & 0xF)) {
+                // Notes run from C# (1) to the C above (12)
+                Some((octave, note @ 1..=12)) => {
+                    let semitone = octave * 12 + note;
+                    ((semitone / 12) << 4) | (semitone % 12)
+                },
+                Some((_, 15)) => 254,
+                _ => 255,
+            };
+            col.instrument = instrument;
+            if let Some((effect, param)) = effect {
+                convert_effect(&mut col, effect, param);
+            }
+
+            if channel < 9 && row < 64 {
+                pattern[row][channel] = col;
+            }
+            if channel_flags & 0x80 != 0 {
+                break;
+            }
+        }
+        if line & 0x80 != 0 {
+            return Ok(pattern);
+        }
+    }
+}
+
+fn convert_effect(col: &mut S3MColumn, effect: u8, param: u8) {
+    (col.effect, col.effect_value) = match effect {
+        0x1 if param != 0 => (EFFECT_PORTAMENTO_UP, param.min(0xDF)),
+        0x2 if param != 0 => (EFFECT_PORTAMENTO_DOWN, param.min(0xDF)),
+        0x3 => (EFFECT_TONE_PORTAMENTO, param),
+        0x5 => (EFFECT_PORTAMENTO_VOLUME_SLIDE, volume_slide(param)),
+        0xA if param != 0 => (EFFECT_VOLUME_SLIDE, volume_slide(param)),
+        0xC => {
+            col.vol = param.min(64);
+            (0, 0)
+        },
+        // ST3 stores the row as BCD
+        0xD => (EFFECT_BREAK, ((param / 10) << 4) | (param % 10)),
+        0xF if (1..32).contains(&param) => (EFFECT_SET_SPEED, param),
+        _ => (0, 0),
+    };
+}
+
+/// RAD slides down by 1-49 and up by 51-99 minus 50.
+fn volume_slide(param: u8) -> u8 {
+    match param {
+        1..=49 => param.min(15),
+        51..=99 => (param - 50).min(15) << 4,
+        _ => 0,
+    }
+}
+
+#[derive(Debug)]
+pub enum RADLoadError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u8),
+    Truncated { offset: u64 },
+    PatternOutOfBounds { index: usize, offset: u64 },
+}
+
+impl fmt::Display for RADLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RADLoadError::Io(error) => write!(f, "I/O error: {}", error),
+            RADLoadError::BadMagic => write!(f, "Not a RAD module, \"RAD by REALiTY!!\" is missing"),
+            RADLoadError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported RAD version {:X}.{:X}", version >> 4, version & 0xF)
+            },
+            RADLoadError::Truncated { offset } => {
+                write!(f, "File ends early at offset 0x{:X}", offset)
+            },
+            RADLoadError::PatternOutOfBounds { index, offset } => {
+                write!(f, "Pattern {} at offset 0x{:X} runs past the end of the file", index, offset)
+            },
+        }
+    }
+}
+
+impl std::error::Error for RADLoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RADLoadError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for RADLoadError {
+    fn from(error: io::Error) -> Self {
+        RADLoadError::Io(error)
+    }
+}
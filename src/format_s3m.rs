@@ -1,8 +1,10 @@
-use byteorder::{LittleEndian, ReadBytesExt};
-use std::io::{self, SeekFrom};
-use anyhow::{Result, anyhow};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::{fmt, io::{self, SeekFrom}};
 
-#[derive(Debug)]
+use crate::diagnostics::{Category, Diagnostic, Diagnostics, Severity};
+use crate::pitch::NOTE_NAMES;
+
+#[derive(Debug, Default, Clone)]
 pub struct S3MModule {
     // FILE STRUCTURE
 
@@ -33,9 +35,14 @@ pub struct S3MModule {
     // PUBLIC
     pub instruments: Vec<S3MInstrument>,
     pub patterns: Vec<S3MPattern>,
+    /// Text attached as special custom data (flag 128), if any.
+    pub message: Option<String>,
+    /// What loading the song had to leave out or repair, carried into the
+    /// conversion report.
+    pub diagnostics: Diagnostics,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum S3MInstrument {
     Sample(S3MSample),
     Adlib(S3MAdlibInstrument)
@@ -91,7 +98,12 @@ pub struct S3MAdlibInstrument {
 
 pub type S3MPattern = [S3MRow;64];
 
-#[derive(Debug, Clone, Copy)]
+/// Order list length ST3 can hold, end markers included.
+pub const MAX_ORDERS: usize = 256;
+/// Pattern numbers 254 and 255 mark skipped and ended orders.
+pub const MAX_PATTERNS: usize = 254;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct S3MColumn {
     pub note: u8,
     pub instrument: u8,
@@ -112,127 +124,116 @@ impl Default for S3MColumn {
     }
 }
 
-pub type S3MRow = [S3MColumn;32];
-
-impl Default for S3MModule {
-    fn default() -> Self {
-        // Somebody please fix this monstrosity.
-        S3MModule {
-            song_name: [0;28],
-            _unused: 0,
-            order_amount: 0,
-            sample_amount: 0,
-            pattern_amount: 0,
-            flags: 0,
-            tracker_metadata: 0,
-            ffi: 0,
-            _scrm: 0,
-            global_volume: 0,
-            initial_speed: 0,
-            initial_tempo: 0,
-            mixing_volume: 0,
-            ramping: 0,
-            default_panning: 0,
-            _unused2: [0;8],
-            special: 0,
-            channel_settings: [0;32],
-            orders: Vec::new(),
-            sample_offsets: Vec::new(),
-            pattern_offsets: Vec::new(),
-            channel_panning: [0;32],
-            instruments: Vec::new(),
-            patterns: Vec::new(),
+impl fmt::Display for S3MColumn {
+    /// Shows the column the way ST3 does, e.g. `C-4 01 40 A06`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.note {
+            255 => write!(f, "...")?,
+            254 => write!(f, "^^.")?,
+            note => match NOTE_NAMES.get((note & 0xF) as usize) {
+                Some(name) => write!(f, "{}{}", name, note >> 4)?,
+                None => write!(f, "???")?,
+            },
+        }
+        match self.instrument {
+            0 => write!(f, " ..")?,
+            instrument => write!(f, " {:02}", instrument)?,
+        }
+        match self.vol {
+            0..=64 => write!(f, " {:02}", self.vol)?,
+            _ => write!(f, " ..")?,
+        }
+        match self.effect {
+            0 => write!(f, " ..."),
+            effect @ 1..=26 => write!(f, " {}{:02X}", (b'A' + effect - 1) as char, self.effect_value),
+            _ => write!(f, " ?{:02X}", self.effect_value),
         }
     }
 }
 
+pub type S3MRow = [S3MColumn;32];
+
+// Effect numbers as stored in pattern data, 1 being effect A
+pub const EFFECT_SET_SPEED: u8 = 1; // Axx
+pub const EFFECT_JUMP: u8 = 2; // Bxx
+pub const EFFECT_BREAK: u8 = 3; // Cxx
+pub const EFFECT_VOLUME_SLIDE: u8 = 4; // Dxy
+pub const EFFECT_PORTAMENTO_DOWN: u8 = 5; // Exx
+pub const EFFECT_PORTAMENTO_UP: u8 = 6; // Fxx
+pub const EFFECT_TONE_PORTAMENTO: u8 = 7; // Gxx
+pub const EFFECT_VIBRATO: u8 = 8; // Hxy
+pub const EFFECT_ARPEGGIO: u8 = 10; // Jxy
+pub const EFFECT_VIBRATO_VOLUME_SLIDE: u8 = 11; // Kxy
+pub const EFFECT_PORTAMENTO_VOLUME_SLIDE: u8 = 12; // Lxy
+pub const EFFECT_SAMPLE_OFFSET: u8 = 15; // Oxx
+pub const EFFECT_RETRIGGER: u8 = 17; // Qxy
+pub const EFFECT_TREMOLO: u8 = 18; // Rxy
+pub const EFFECT_SPECIAL: u8 = 19; // Sxy
+pub const EFFECT_SET_TEMPO: u8 = 20; // Txx
+
 impl S3MModule {
-    pub fn load(mut reader: impl io::Read + io::Seek) -> Result<S3MModule> {
+    pub fn load(mut reader: impl io::Read) -> Result<S3MModule, S3MLoadError> {
         let mut module = S3MModule::default();
+        let mut data: Vec<u8> = Vec::new();
+        reader.read_to_end(&mut data)?;
+        let file_length = data.len() as u64;
+        let mut reader = io::Cursor::new(data.as_slice());
 
-        // HEADER START
-        reader.read(&mut module.song_name).unwrap();
-        module._unused = reader.read_u32::<LittleEndian>().unwrap();
-        module.order_amount = reader.read_u16::<LittleEndian>().unwrap();
-        module.sample_amount = reader.read_u16::<LittleEndian>().unwrap();
-        module.pattern_amount = reader.read_u16::<LittleEndian>().unwrap();
-        module.flags = reader.read_u16::<LittleEndian>().unwrap();
-        module.tracker_metadata = reader.read_u16::<LittleEndian>().unwrap();
-        module.ffi = reader.read_u16::<LittleEndian>().unwrap();
-        module._scrm = reader.read_u32::<LittleEndian>().unwrap();
-        if module._scrm != 0x4D524353 {
-            return Err(anyhow!("File is not a valid module"))
-        };
-        module.global_volume = reader.read_u8().unwrap();
-        module.initial_speed = reader.read_u8().unwrap();
-        module.initial_tempo = reader.read_u8().unwrap();
-        module.mixing_volume = reader.read_u8().unwrap();
-        module.ramping = reader.read_u8().unwrap();
-        module.default_panning = reader.read_u8().unwrap();
-        reader.read(&mut module._unused2).unwrap();
-        module.special = reader.read_u16::<LittleEndian>().unwrap();
-        reader.read(&mut module.channel_settings).unwrap();
-        module.orders.resize(module.order_amount as usize, 255);
-        reader.read(&mut module.orders).unwrap();
-
-        module.sample_offsets.resize(module.sample_amount as usize, 0);
-        reader.read_u16_into::<LittleEndian>(&mut module.sample_offsets).unwrap();
-
-        module.pattern_offsets.resize(module.pattern_amount as usize, 0);
-        reader.read_u16_into::<LittleEndian>(&mut module.pattern_offsets).unwrap();
-
-        reader.read(&mut module.channel_panning).unwrap();
-        // HEADER END
+        // Check the signature first, the header's length depends on fields before it
+        match data.get(0x2C..0x30) {
+            None => return Err(S3MLoadError::TruncatedHeader { offset: file_length }),
+            Some(magic) if magic != b"SCRM" => return Err(S3MLoadError::BadMagic(magic.try_into().unwrap())),
+            Some(_) => {},
+        }
+        read_header(&mut reader, &mut module).map_err(|e| truncated(e, reader.position()))?;
+
+        if module.flags & 128 != 0 && module.special != 0 {
+            let offset = (module.special as u64) << 4;
+            match data.get(offset as usize..) {
+                Some(text) => {
+                    let text = text.split(|byte| *byte == 0).next().unwrap();
+                    module.message = Some(text.iter().map(|byte| *byte as char).collect());
+                },
+                None => module.diagnostics.push(damaged(format!("Song message at offset 0x{:X} lies past the end of the file, ignoring it", offset))),
+            }
+        }
 
         // SAMPLES START
-        dbg!(module.sample_offsets.len());
-        for offset in &module.sample_offsets {
+        for (index, offset) in module.sample_offsets.iter().enumerate() {
             if *offset == 0 {
                 module.instruments.push(S3MInstrument::Sample(S3MSample::default()));
                 continue;
             }
 
-            reader.seek(SeekFrom::Start((*offset as u64) << 4)).unwrap();
-            let mut sample: S3MSample = S3MSample::default();
+            let offset = (*offset as u64) << 4;
+            if offset + INSTRUMENT_HEADER_SIZE > file_length {
+                return Err(S3MLoadError::InstrumentOutOfBounds { index, offset });
+            }
+            reader.set_position(offset);
+            let instrument = read_instrument(&mut reader)?;
 
-            sample.sample_type = reader.read_u8().unwrap();
-            // if sample.sample_type > 1 {
-            //     return Err(anyhow!("Adlib module detected"))
-            // }
-            if sample.sample_type == 0 {
-                module.instruments.push(S3MInstrument::Sample(S3MSample::default()));
-            } else if sample.sample_type == 1 {
-                // PCM sample
-                reader.read(&mut sample.filename).unwrap();
-                reader.read(&mut sample.memseg).unwrap();
-                sample.length = reader.read_u32::<LittleEndian>().unwrap();
-                sample.loop_begin = reader.read_u32::<LittleEndian>().unwrap();
-                sample.loop_end = reader.read_u32::<LittleEndian>().unwrap();
-                sample.volume = reader.read_u8().unwrap();
-                sample._unused = reader.read_u8().unwrap();
-                sample.packed = reader.read_u8().unwrap();
-                if sample.packed == 1 {
-                    return Err(anyhow!("Compressed samples detected"))
+            let S3MInstrument::Sample(mut sample) = instrument else {
+                module.instruments.push(instrument);
+                continue;
+            };
+            if sample.sample_type == 1 {
+                if sample.packed != 0 {
+                    return Err(S3MLoadError::UnsupportedPacking { index, packing: sample.packed });
                 }
-                sample.flags = reader.read_u8().unwrap();
-                sample.c4speed = reader.read_u32::<LittleEndian>().unwrap();
-                reader.seek(SeekFrom::Current(4)).unwrap();
-                sample.int_gp = reader.read_u16::<LittleEndian>().unwrap();
-                reader.seek(SeekFrom::Current(6)).unwrap();
-                reader.read(&mut sample.sample_name).unwrap();
-
-                let sampledata_offset: u32 =
-                    ((sample.memseg[1] as u32) << 4) |
-                    ((sample.memseg[2] as u32) << 12) |
-                    ((sample.memseg[0] as u32) << 20);
-                reader.seek(SeekFrom::Start(sampledata_offset as u64)).unwrap();
+
+                let sampledata_offset: u64 =
+                    ((sample.memseg[1] as u64) << 4) |
+                    ((sample.memseg[2] as u64) << 12) |
+                    ((sample.memseg[0] as u64) << 20);
+                let bytes_per_sample = if sample.flags & 0b100 != 0 { 2 } else { 1 };
+                let length = sample.length as u64 * bytes_per_sample;
+                if sampledata_offset + length > file_length {
+                    return Err(S3MLoadError::SampleOutOfBounds { index, offset: sampledata_offset, length });
+                }
+                let data = &data[sampledata_offset as usize..(sampledata_offset + length) as usize];
 
                 if sample.flags & 0b100 != 0 {
                     // Sample is 16 bit
-                    let mut data: Vec<u8> = Vec::with_capacity(sample.length as usize * 2);
-                    data.resize((sample.length * 2).try_into().unwrap(), 0);
-                    reader.read(&mut data).unwrap();
-
                     if module.ffi == 1 {
                         // Signed?
                         sample.audio = data
@@ -247,10 +248,6 @@ impl S3MModule {
                     }
                 } else {
                     // Sample is 8 bit
-                    let mut data: Vec<u8> = Vec::with_capacity(sample.length as usize);
-                    data.resize((sample.length).try_into().unwrap(), 0);
-                    reader.read(&mut data).unwrap();
-
                     if module.ffi == 1 {
                         // Signed?
                         sample.audio = data
@@ -261,77 +258,814 @@ impl S3MModule {
                         sample.audio = data.iter().map(|x| (*x as i16 - 128) * 256).collect();
                     }
                 }
-
-                module.instruments.push(S3MInstrument::Sample(sample));
-            } else if sample.sample_type >= 2 {
-                // Adlib instrument
-                let mut instrument = S3MAdlibInstrument::default();
-                instrument.instrument_type = sample.sample_type;
-                reader.read(&mut instrument.filename).unwrap();
-                reader.read(&mut instrument._unused).unwrap();
-                instrument.d00 = reader.read_u8().unwrap();
-                instrument.d01 = reader.read_u8().unwrap();
-                instrument.d02 = reader.read_u8().unwrap();
-                instrument.d03 = reader.read_u8().unwrap();
-                instrument.d06 = reader.read_u8().unwrap();
-                instrument.d05 = reader.read_u8().unwrap();
-                instrument.d06 = reader.read_u8().unwrap();
-                instrument.d07 = reader.read_u8().unwrap();
-                instrument.d08 = reader.read_u8().unwrap();
-                instrument.d09 = reader.read_u8().unwrap();
-                instrument.d0a = reader.read_u8().unwrap();
-                instrument.d0b = reader.read_u8().unwrap();
-                instrument.volume = reader.read_u8().unwrap();
-                instrument.disk = reader.read_u8().unwrap();
-                instrument._unused2 = reader.read_u16::<LittleEndian>().unwrap();
-                instrument.c4freq = reader.read_u32::<LittleEndian>().unwrap();
-                reader.read(&mut instrument._unused3).unwrap();
-                reader.read(&mut instrument.sample_name).unwrap();
-                reader.read(&mut instrument._scri).unwrap();
-
-                module.instruments.push(S3MInstrument::Adlib(instrument));
             }
+
+            module.instruments.push(S3MInstrument::Sample(sample));
         }
         // SAMPLES END
 
         // PATTERNS START
-        for offset in &module.pattern_offsets {
+        for (index, offset) in module.pattern_offsets.iter().enumerate() {
             if *offset == 0 {
                 module.patterns.push([S3MRow::default();64]);
                 continue;
             }
 
-            // println!("Offset: {}", offset);
-            reader.seek(SeekFrom::Start(((*offset as u64) << 4) + 2)).unwrap();
-            let mut pattern = [S3MRow::default();64];
+            let offset = (*offset as u64) << 4;
+            if offset + 2 > file_length {
+                return Err(S3MLoadError::PatternOutOfBounds { index, offset });
+            }
+            let start = offset as usize;
+            let packed_length = u16::from_le_bytes([data[start], data[start + 1]]) as usize;
+            let mut end = start + packed_length;
+            if end > data.len() {
+                module.diagnostics.push(damaged(format!("Pattern {} at offset 0x{:X} claims {} bytes but the file ends first", index, offset, packed_length)));
+                end = data.len();
+            }
 
-            let mut row = 0usize;
-            let mut channel;
-            'unpacking: loop {
-                let packed_byte = reader.read_u8().unwrap();
-                if packed_byte == 0 {
-                    row += 1;
-                }
-                channel = (packed_byte & 31) as usize;
-                if packed_byte & 32 != 0 { // note and instrument in the next 2 bytes
-                    pattern[row][channel].note = reader.read_u8().unwrap();
-                    pattern[row][channel].instrument = reader.read_u8().unwrap();
-                }
-                if packed_byte & 64 != 0 { // volume in the next byte
-                    pattern[row][channel].vol = reader.read_u8().unwrap();
-                }
-                if packed_byte & 128 != 0 { // effect in the next 2 bytes
-                    pattern[row][channel].effect = reader.read_u8().unwrap();
-                    pattern[row][channel].effect_value = reader.read_u8().unwrap();
+            let (pattern, issue) = unpack_pattern(&data[(start + 2).min(end)..end]);
+            match issue {
+                Some(UnpackIssue::EndsEarly { row }) => {
+                    module.diagnostics.push(damaged(format!("Pattern {} at offset 0x{:X} ends early at row {}, leaving the rest empty", index, offset, row)));
+                },
+                Some(UnpackIssue::TrailingData { bytes }) => {
+                    module.diagnostics.push(damaged(format!("Pattern {} at offset 0x{:X} has {} bytes past its last row, ignoring them", index, offset, bytes)));
+                },
+                Some(UnpackIssue::Overrun { rows }) => {
+                    return Err(S3MLoadError::PatternOverrun { index, offset, rows });
+                },
+                None => {},
+            }
+            module.patterns.push(pattern);
+        }
+
+        Ok(module)
+    }
+
+    /// Writes the module as an S3M file. Instrument, pattern and order counts
+    /// come from the vectors rather than the header fields, and a song message
+    /// sets or clears flag 128 as needed.
+    pub fn save(&self, mut writer: impl io::Write) -> io::Result<()> {
+        let mut data: Vec<u8> = Vec::new();
+        let message_flag = if self.message.is_some() { 128 } else { 0 };
+
+        // HEADER START
+        data.extend_from_slice(&self.song_name);
+        data.extend_from_slice(&[0x1A, 16, 0, 0]);
+        data.write_u16::<LittleEndian>(self.orders.len() as u16)?;
+        data.write_u16::<LittleEndian>(self.instruments.len() as u16)?;
+        data.write_u16::<LittleEndian>(self.patterns.len() as u16)?;
+        data.write_u16::<LittleEndian>((self.flags & !128) | message_flag)?;
+        data.write_u16::<LittleEndian>(self.tracker_metadata)?;
+        data.write_u16::<LittleEndian>(self.ffi)?;
+        data.extend_from_slice(b"SCRM");
+        data.extend_from_slice(&[
+            self.global_volume,
+            self.initial_speed,
+            self.initial_tempo,
+            self.mixing_volume,
+            self.ramping,
+            self.default_panning,
+        ]);
+        data.extend_from_slice(&self._unused2);
+        let special_offset = data.len();
+        data.write_u16::<LittleEndian>(0)?;
+        data.extend_from_slice(&self.channel_settings);
+        data.extend_from_slice(&self.orders);
+
+        // Parapointers are filled in once everything they point to is placed
+        let instrument_pointers = data.len();
+        data.resize(instrument_pointers + self.instruments.len() * 2, 0);
+        let pattern_pointers = data.len();
+        data.resize(pattern_pointers + self.patterns.len() * 2, 0);
+        if self.default_panning == 0xFC {
+            data.extend_from_slice(&self.channel_panning);
+        }
+        // HEADER END
+
+        if let Some(message) = &self.message {
+            let paragraph = paragraph(&mut data)?;
+            data[special_offset..special_offset + 2].copy_from_slice(&paragraph.to_le_bytes());
+            data.extend(message.chars().map(|c| if (c as u32) < 256 { c as u8 } else { b'?' }));
+            data.push(0);
+        }
+
+        let mut sample_headers: Vec<(usize, &S3MSample)> = Vec::new();
+        for (index, instrument) in self.instruments.iter().enumerate() {
+            let paragraph = paragraph(&mut data)?;
+            data[instrument_pointers + index * 2..instrument_pointers + index * 2 + 2].copy_from_slice(&paragraph.to_le_bytes());
+
+            match instrument {
+                S3MInstrument::Sample(sample) => {
+                    sample_headers.push((data.len(), sample));
+                    write_sample_header(&mut data, sample)?;
+                },
+                S3MInstrument::Adlib(instrument) => write_adlib_header(&mut data, instrument)?,
+            }
+        }
+
+        for (index, pattern) in self.patterns.iter().enumerate() {
+            let paragraph = paragraph(&mut data)?;
+            data[pattern_pointers + index * 2..pattern_pointers + index * 2 + 2].copy_from_slice(&paragraph.to_le_bytes());
+
+            let packed = pack_pattern(pattern);
+            data.write_u16::<LittleEndian>(packed.len() as u16 + 2)?;
+            data.extend_from_slice(&packed);
+        }
+
+        for (header, sample) in sample_headers {
+            if sample.sample_type != 1 {
+                continue;
+            }
+
+            // Sample data may lie beyond what a 16-bit parapointer reaches
+            data.resize(data.len().next_multiple_of(16), 0);
+            let paragraph = data.len() / 16;
+            if paragraph > 0xFFFFFF {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "sample data lies past the 256MB S3M limit"));
+            }
+            data[header + 13] = (paragraph >> 16) as u8;
+            data[header + 14..header + 16].copy_from_slice(&(paragraph as u16).to_le_bytes());
+
+            let signed = self.ffi == 1;
+            if sample.flags & 0b100 != 0 {
+                // Sample is 16 bit
+                for value in &sample.audio {
+                    let value = if signed { *value as u16 } else { *value as u16 ^ 0x8000 };
+                    data.write_u16::<LittleEndian>(value)?;
                 }
-                if row == 64 {
-                    module.patterns.push(pattern);
-                    break 'unpacking;
+            } else {
+                // Sample is 8 bit
+                for value in &sample.audio {
+                    let value = (value >> 8) as i8 as u8;
+                    data.push(if signed { value } else { value ^ 0x80 });
                 }
             }
+        }
+
+        writer.write_all(&data)
+    }
+}
+
+impl S3MModule {
+    /// The tracker that saved the module, from the "created with" field.
+    pub fn tracker(&self) -> Tracker {
+        let reserved = u32::from_le_bytes([self._unused2[0], self._unused2[1], self._unused2[2], self._unused2[3]]);
+        Tracker::from_created_with(self.tracker_metadata, reserved)
+    }
 
+    /// Stereo position of a channel, 0 (left) to 15 (right). Channels the
+    /// panning table does not cover use ST3's defaults for their side.
+    pub fn channel_pan(&self, channel: usize) -> u8 {
+        let pan = self.channel_panning[channel];
+        if pan & 0x20 != 0 {
+            return pan & 0xF;
         }
 
-        Ok(module)
+        match self.channel_settings[channel] & 0x7F {
+            0..=7 => 0x3,
+            8..=15 => 0xC,
+            _ => 0x7,
+        }
+    }
+}
+
+/// A tracker version as stored in the "created with" field, `major.minor`
+/// with the minor version written as two hexadecimal digits (3.20 is 0x320).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrackerVersion {
+    pub major: u8,
+    pub minor: u8,
+}
+
+impl fmt::Display for TrackerVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:X}.{:02X}", self.major, self.minor)
+    }
+}
+
+/// Which tracker saved a module, decoded from the top nibble of the
+/// "created with" field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(clippy::enum_variant_names)]
+pub enum Tracker {
+    ScreamTracker(TrackerVersion),
+    ImagoOrpheus(TrackerVersion),
+    ImpulseTracker(TrackerVersion),
+    /// Versions up to 0.50, or the build date of later versions.
+    SchismTracker(SchismVersion),
+    OpenMPT(TrackerVersion),
+    BeRoTracker,
+    CreamTracker(TrackerVersion),
+    Unknown(u16),
+}
+
+impl Tracker {
+    /// Decodes the "created with" field. `reserved` is the 32-bit field at
+    /// 0x36, where Schism Tracker keeps build dates too late for the former.
+    pub fn from_created_with(created_with: u16, reserved: u32) -> Self {
+        let version = TrackerVersion {
+            major: ((created_with >> 8) & 0xF) as u8,
+            minor: created_with as u8,
+        };
+
+        match created_with >> 12 {
+            1 => Tracker::ScreamTracker(version),
+            2 => Tracker::ImagoOrpheus(version),
+            3 => Tracker::ImpulseTracker(version),
+            4 => Tracker::SchismTracker(match created_with & 0xFFF {
+                version @ 0..=0x50 => SchismVersion::Release(version),
+                // Builds count days since 2009-10-31 from 0x50 on
+                0xFFF => SchismVersion::Build(reserved),
+                version => SchismVersion::Build((version - 0x50) as u32),
+            }),
+            5 => Tracker::OpenMPT(version),
+            6 => Tracker::BeRoTracker,
+            7 => Tracker::CreamTracker(version),
+            _ => Tracker::Unknown(created_with),
+        }
     }
-}
\ No newline at end of file
+}
+
+impl fmt::Display for Tracker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Tracker::ScreamTracker(version) => write!(f, "Scream Tracker {}", version),
+            Tracker::ImagoOrpheus(version) => write!(f, "Imago Orpheus {}", version),
+            Tracker::ImpulseTracker(version) => write!(f, "Impulse Tracker {}", version),
+            Tracker::SchismTracker(SchismVersion::Release(version)) => write!(f, "Schism Tracker 0.{:x}", version),
+            Tracker::SchismTracker(SchismVersion::Build(days)) => {
+                let (year, month, day) = date_after(2009, 10, 31, *days);
+                write!(f, "Schism Tracker {}-{:02}-{:02}", year, month, day)
+            },
+            Tracker::OpenMPT(version) => write!(f, "OpenMPT {}", version),
+            Tracker::BeRoTracker => write!(f, "BeRoTracker"),
+            Tracker::CreamTracker(version) => write!(f, "Cream Tracker {}", version),
+            Tracker::Unknown(created_with) => write!(f, "Unknown tracker 0x{:04X}", created_with),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchismVersion {
+    /// Version 0.x, x written as two hexadecimal digits.
+    Release(u16),
+    /// A build made this many days after 2009-10-31.
+    Build(u32),
+}
+
+/// The calendar date `days` days after the given one.
+fn date_after(mut year: u32, mut month: u32, mut day: u32, days: u32) -> (u32, u32, u32) {
+    for _ in 0..days {
+        let leap = year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400));
+        let month_length = match month {
+            2 if leap => 29,
+            2 => 28,
+            4 | 6 | 9 | 11 => 30,
+            _ => 31,
+        };
+
+        day += 1;
+        if day > month_length {
+            day = 1;
+            month += 1;
+            if month > 12 {
+                month = 1;
+                year += 1;
+            }
+        }
+    }
+
+    (year, month, day)
+}
+
+/// Size of a sample or AdLib instrument header.
+const INSTRUMENT_HEADER_SIZE: u64 = 0x50;
+
+fn read_header(reader: &mut impl io::Read, module: &mut S3MModule) -> io::Result<()> {
+    // HEADER START
+    reader.read_exact(&mut module.song_name)?;
+    module._unused = reader.read_u32::<LittleEndian>()?;
+    module.order_amount = reader.read_u16::<LittleEndian>()?;
+    module.sample_amount = reader.read_u16::<LittleEndian>()?;
+    module.pattern_amount = reader.read_u16::<LittleEndian>()?;
+    module.flags = reader.read_u16::<LittleEndian>()?;
+    module.tracker_metadata = reader.read_u16::<LittleEndian>()?;
+    module.ffi = reader.read_u16::<LittleEndian>()?;
+    module._scrm = reader.read_u32::<LittleEndian>()?;
+    module.global_volume = reader.read_u8()?;
+    module.initial_speed = reader.read_u8()?;
+    module.initial_tempo = reader.read_u8()?;
+    module.mixing_volume = reader.read_u8()?;
+    module.ramping = reader.read_u8()?;
+    module.default_panning = reader.read_u8()?;
+    reader.read_exact(&mut module._unused2)?;
+    module.special = reader.read_u16::<LittleEndian>()?;
+    reader.read_exact(&mut module.channel_settings)?;
+    module.orders.resize(module.order_amount as usize, 255);
+    reader.read_exact(&mut module.orders)?;
+
+    module.sample_offsets.resize(module.sample_amount as usize, 0);
+    reader.read_u16_into::<LittleEndian>(&mut module.sample_offsets)?;
+
+    module.pattern_offsets.resize(module.pattern_amount as usize, 0);
+    reader.read_u16_into::<LittleEndian>(&mut module.pattern_offsets)?;
+
+    // The panning table is only present when the default panning byte says so
+    if module.default_panning == 0xFC {
+        reader.read_exact(&mut module.channel_panning)?;
+    }
+    // HEADER END
+
+    Ok(())
+}
+
+/// Reads an instrument header. Sample data is left for the caller.
+fn read_instrument(reader: &mut (impl io::Read + io::Seek)) -> io::Result<S3MInstrument> {
+    let mut sample = S3MSample {
+        sample_type: reader.read_u8()?,
+        ..Default::default()
+    };
+    if sample.sample_type == 0 {
+        Ok(S3MInstrument::Sample(S3MSample::default()))
+    } else if sample.sample_type == 1 {
+        // PCM sample
+        reader.read_exact(&mut sample.filename)?;
+        reader.read_exact(&mut sample.memseg)?;
+        sample.length = reader.read_u32::<LittleEndian>()?;
+        sample.loop_begin = reader.read_u32::<LittleEndian>()?;
+        sample.loop_end = reader.read_u32::<LittleEndian>()?;
+        sample.volume = reader.read_u8()?;
+        sample._unused = reader.read_u8()?;
+        sample.packed = reader.read_u8()?;
+        sample.flags = reader.read_u8()?;
+        sample.c4speed = reader.read_u32::<LittleEndian>()?;
+        reader.seek(SeekFrom::Current(4))?;
+        sample.int_gp = reader.read_u16::<LittleEndian>()?;
+        reader.seek(SeekFrom::Current(6))?;
+        reader.read_exact(&mut sample.sample_name)?;
+        reader.read_exact(&mut sample._scrs)?;
+
+        Ok(S3MInstrument::Sample(sample))
+    } else {
+        // Adlib instrument
+        let mut instrument = S3MAdlibInstrument {
+            instrument_type: sample.sample_type,
+            ..Default::default()
+        };
+        reader.read_exact(&mut instrument.filename)?;
+        reader.read_exact(&mut instrument._unused)?;
+        instrument.d00 = reader.read_u8()?;
+        instrument.d01 = reader.read_u8()?;
+        instrument.d02 = reader.read_u8()?;
+        instrument.d03 = reader.read_u8()?;
+        instrument.d04 = reader.read_u8()?;
+        instrument.d05 = reader.read_u8()?;
+        instrument.d06 = reader.read_u8()?;
+        instrument.d07 = reader.read_u8()?;
+        instrument.d08 = reader.read_u8()?;
+        instrument.d09 = reader.read_u8()?;
+        instrument.d0a = reader.read_u8()?;
+        instrument.d0b = reader.read_u8()?;
+        instrument.volume = reader.read_u8()?;
+        instrument.disk = reader.read_u8()?;
+        instrument._unused2 = reader.read_u16::<LittleEndian>()?;
+        instrument.c4freq = reader.read_u32::<LittleEndian>()?;
+        reader.read_exact(&mut instrument._unused3)?;
+        reader.read_exact(&mut instrument.sample_name)?;
+        reader.read_exact(&mut instrument._scri)?;
+
+        Ok(S3MInstrument::Adlib(instrument))
+    }
+}
+
+/// Pads `data` to the next 16 byte boundary, returning the parapointer to it.
+fn paragraph(data: &mut Vec<u8>) -> io::Result<u16> {
+    data.resize(data.len().next_multiple_of(16), 0);
+    u16::try_from(data.len() / 16)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "instruments and patterns exceed the 1MB S3M parapointer range"))
+}
+
+/// Writes a sample header, leaving the sample data pointer for the caller.
+fn write_sample_header(data: &mut Vec<u8>, sample: &S3MSample) -> io::Result<()> {
+    data.push(sample.sample_type);
+    data.extend_from_slice(&sample.filename);
+    data.extend_from_slice(&[0;3]);
+    data.write_u32::<LittleEndian>(sample.audio.len() as u32)?;
+    data.write_u32::<LittleEndian>(sample.loop_begin)?;
+    data.write_u32::<LittleEndian>(sample.loop_end)?;
+    data.extend_from_slice(&[sample.volume, sample._unused, 0, sample.flags]);
+    data.write_u32::<LittleEndian>(sample.c4speed)?;
+    data.write_u32::<LittleEndian>(sample._unused2)?;
+    data.write_u16::<LittleEndian>(sample.int_gp)?;
+    data.extend_from_slice(&[0;6]);
+    data.extend_from_slice(&sample.sample_name);
+    data.extend_from_slice(if sample.sample_type == 1 { b"SCRS" } else { &[0;4] });
+
+    Ok(())
+}
+
+fn write_adlib_header(data: &mut Vec<u8>, instrument: &S3MAdlibInstrument) -> io::Result<()> {
+    data.push(instrument.instrument_type);
+    data.extend_from_slice(&instrument.filename);
+    data.extend_from_slice(&instrument._unused);
+    data.extend_from_slice(&[
+        instrument.d00, instrument.d01, instrument.d02, instrument.d03,
+        instrument.d04, instrument.d05, instrument.d06, instrument.d07,
+        instrument.d08, instrument.d09, instrument.d0a, instrument.d0b,
+        instrument.volume, instrument.disk,
+    ]);
+    data.write_u16::<LittleEndian>(instrument._unused2)?;
+    data.write_u32::<LittleEndian>(instrument.c4freq)?;
+    data.extend_from_slice(&instrument._unused3);
+    data.extend_from_slice(&instrument.sample_name);
+    data.extend_from_slice(b"SCRI");
+
+    Ok(())
+}
+
+/// Packs the rows of a pattern, without the length field. Only the parts of
+/// a column that differ from an empty one are stored.
+fn pack_pattern(pattern: &S3MPattern) -> Vec<u8> {
+    let mut data: Vec<u8> = Vec::new();
+    let empty = S3MColumn::default();
+
+    for row in pattern {
+        for (channel, col) in row.iter().enumerate() {
+            let mut packed_byte = channel as u8;
+            if col.note != empty.note || col.instrument != empty.instrument {
+                packed_byte |= 32;
+            }
+            if col.vol != empty.vol {
+                packed_byte |= 64;
+            }
+            if col.effect != empty.effect || col.effect_value != empty.effect_value {
+                packed_byte |= 128;
+            }
+            if packed_byte & 0xE0 == 0 {
+                continue;
+            }
+
+            data.push(packed_byte);
+            if packed_byte & 32 != 0 {
+                data.extend_from_slice(&[col.note, col.instrument]);
+            }
+            if packed_byte & 64 != 0 {
+                data.push(col.vol);
+            }
+            if packed_byte & 128 != 0 {
+                data.extend_from_slice(&[col.effect, col.effect_value]);
+            }
+        }
+        data.push(0);
+    }
+
+    data
+}
+
+/// What was off about a pattern's packed data.
+enum UnpackIssue {
+    /// The data ran out before all 64 rows were terminated.
+    EndsEarly { row: usize },
+    /// Non-zero bytes follow the 64th row.
+    TrailingData { bytes: usize },
+    /// Whole rows with notes or effects follow the 64th row, as if the
+    /// pattern were longer.
+    Overrun { rows: usize },
+}
+
+/// Unpacks the rows of a pattern from its packed data, without the length
+/// field. Rows the data does not reach are left empty.
+fn unpack_pattern(data: &[u8]) -> (S3MPattern, Option<UnpackIssue>) {
+    let mut pattern = [S3MRow::default();64];
+
+    let mut position = 0usize;
+    let mut row = 0usize;
+    while row < 64 {
+        let Some(&packed_byte) = data.get(position) else {
+            return (pattern, Some(UnpackIssue::EndsEarly { row }));
+        };
+        position += 1;
+        if packed_byte == 0 {
+            row += 1;
+            continue;
+        }
+
+        let operand_count = (packed_byte & 32 != 0) as usize * 2
+            + (packed_byte & 64 != 0) as usize
+            + (packed_byte & 128 != 0) as usize * 2;
+        let Some(operands) = data.get(position..position + operand_count) else {
+            return (pattern, Some(UnpackIssue::EndsEarly { row }));
+        };
+        position += operand_count;
+
+        let mut operands = operands.iter().copied();
+        let col = &mut pattern[row][(packed_byte & 31) as usize];
+        if packed_byte & 32 != 0 { // note and instrument in the next 2 bytes
+            col.note = operands.next().unwrap();
+            col.instrument = operands.next().unwrap();
+        }
+        if packed_byte & 64 != 0 { // volume in the next byte
+            col.vol = operands.next().unwrap();
+        }
+        if packed_byte & 128 != 0 { // effect in the next 2 bytes
+            col.effect = operands.next().unwrap();
+            col.effect_value = operands.next().unwrap();
+        }
+    }
+
+    let trailing = &data[position..];
+    if let Some(rows) = packed_rows(trailing) {
+        return (pattern, Some(UnpackIssue::Overrun { rows }));
+    }
+    if trailing.iter().any(|byte| *byte != 0) {
+        return (pattern, Some(UnpackIssue::TrailingData { bytes: trailing.len() }));
+    }
+
+    (pattern, None)
+}
+
+/// Rows in `data` if it is nothing but whole packed rows, at least one of
+/// them holding something. Anything else is not pattern data, including
+/// channel bytes with nothing following them, which no tracker writes.
+fn packed_rows(data: &[u8]) -> Option<usize> {
+    let mut position = 0usize;
+    let mut rows = 0usize;
+    let mut has_entries = false;
+    while let Some(&packed_byte) = data.get(position) {
+        position += 1;
+        if packed_byte == 0 {
+            rows += 1;
+            continue;
+        }
+        if packed_byte & 0xE0 == 0 {
+            return None;
+        }
+
+        has_entries = true;
+        position += (packed_byte & 32 != 0) as usize * 2
+            + (packed_byte & 64 != 0) as usize
+            + (packed_byte & 128 != 0) as usize * 2;
+    }
+
+    (position == data.len() && data.last() == Some(&0) && has_entries).then_some(rows)
+}
+
+#[derive(Debug)]
+pub enum S3MLoadError {
+    Io(io::Error),
+    /// The "SCRM" signature is missing, holding these bytes instead.
+    BadMagic([u8;4]),
+    TruncatedHeader { offset: u64 },
+    InstrumentOutOfBounds { index: usize, offset: u64 },
+    SampleOutOfBounds { index: usize, offset: u64, length: u64 },
+    UnsupportedPacking { index: usize, packing: u8 },
+    PatternOutOfBounds { index: usize, offset: u64 },
+    /// The pattern's data goes on for `rows` rows past the 64th.
+    PatternOverrun { index: usize, offset: u64, rows: usize },
+}
+
+impl fmt::Display for S3MLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            S3MLoadError::Io(error) => write!(f, "I/O error: {}", error),
+            S3MLoadError::BadMagic(magic) => {
+                write!(f, "Not an S3M module, expected \"SCRM\" at offset 0x2C but found {:02X?}", magic)
+            },
+            S3MLoadError::TruncatedHeader { offset } => {
+                write!(f, "File ends inside the header at offset 0x{:X}", offset)
+            },
+            S3MLoadError::InstrumentOutOfBounds { index, offset } => {
+                write!(f, "Instrument {} at offset 0x{:X} lies past the end of the file", index + 1, offset)
+            },
+            S3MLoadError::SampleOutOfBounds { index, offset, length } => {
+                write!(f, "Sample data of instrument {} (offset 0x{:X}, {} bytes) runs past the end of the file",
+                    index + 1, offset, length)
+            },
+            S3MLoadError::UnsupportedPacking { index, packing } => {
+                write!(f, "Instrument {} uses unsupported packing {}", index + 1, packing)
+            },
+            S3MLoadError::PatternOutOfBounds { index, offset } => {
+                write!(f, "Pattern {} at offset 0x{:X} lies past the end of the file", index, offset)
+            },
+            S3MLoadError::PatternOverrun { index, offset, rows } => {
+                write!(f, "Pattern {} at offset 0x{:X} runs {} rows past the 64th", index, offset, rows)
+            },
+        }
+    }
+}
+
+impl std::error::Error for S3MLoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            S3MLoadError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for S3MLoadError {
+    fn from(error: io::Error) -> Self {
+        S3MLoadError::Io(error)
+    }
+}
+
+fn damaged(message: String) -> Diagnostic {
+    Diagnostic::new(Severity::Warning, Category::DamagedFile, message)
+}
+
+fn truncated(error: io::Error, offset: u64) -> S3MLoadError {
+    if error.kind() == io::ErrorKind::UnexpectedEof {
+        S3MLoadError::TruncatedHeader { offset }
+    } else {
+        S3MLoadError::Io(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Packed data for 64 empty rows followed by `trailing`.
+    fn with_trailing(trailing: &[u8]) -> Vec<u8> {
+        let mut data = vec![0u8;64];
+        data.extend_from_slice(trailing);
+        data
+    }
+
+    #[test]
+    fn bytes_past_the_64th_row_are_trailing_data() {
+        assert!(unpack_pattern(&with_trailing(&[])).1.is_none());
+        assert!(unpack_pattern(&with_trailing(&[0, 0])).1.is_none());
+        assert!(matches!(unpack_pattern(&with_trailing(&[0x01, 0x00])).1, Some(UnpackIssue::TrailingData { bytes: 2 })));
+        assert!(matches!(unpack_pattern(&with_trailing(&[0x1A, 0x2B, 0x3C])).1, Some(UnpackIssue::TrailingData { bytes: 3 })));
+        // Not ended by a row terminator
+        assert!(matches!(unpack_pattern(&with_trailing(&[0x41, 0x20])).1, Some(UnpackIssue::TrailingData { bytes: 2 })));
+    }
+
+    #[test]
+    fn rows_past_the_64th_are_an_overrun() {
+        let overrun = with_trailing(&[0x21, 0x40, 0x01, 0x00, 0x00, 0x82, 0x01, 0x02, 0x00]);
+        assert!(matches!(unpack_pattern(&overrun).1, Some(UnpackIssue::Overrun { rows: 3 })));
+    }
+
+    #[test]
+    fn schism_build_dates() {
+        assert_eq!(Tracker::from_created_with(0x4050, 0).to_string(), "Schism Tracker 0.50");
+        assert_eq!(Tracker::from_created_with(0x4051, 0).to_string(), "Schism Tracker 2009-11-01");
+        assert_eq!(Tracker::from_created_with(0x4050 + 366, 0).to_string(), "Schism Tracker 2010-11-01");
+        assert_eq!(Tracker::from_created_with(0x4FFF, 5000).to_string(), "Schism Tracker 2023-07-10");
+    }
+
+    fn test_sample(length: usize, flags: u8, step: i16) -> S3MSample {
+        S3MSample {
+            sample_type: 1,
+            filename: *b"SAMPLE  .RAW",
+            length: length as u32,
+            loop_begin: 16,
+            loop_end: length as u32,
+            volume: 48,
+            flags,
+            c4speed: 22050,
+            sample_name: [b'S';28],
+            _scrs: *b"SCRS",
+            audio: (0..length).map(|index| (index as i16).wrapping_mul(step)).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn test_module() -> S3MModule {
+        let adlib = S3MAdlibInstrument {
+            instrument_type: 2,
+            filename: *b"ORGAN   .INS",
+            d00: 0x21, d01: 0x31, d02: 0x4F, d03: 0x00, d04: 0xF2, d05: 0xD2,
+            d06: 0x52, d07: 0x73, d08: 0x01, d09: 0x02, d0a: 0x0E,
+            volume: 63,
+            c4freq: 8363,
+            sample_name: [b'A';28],
+            _scri: *b"SCRI",
+            ..Default::default()
+        };
+
+        let mut patterns = vec![[S3MRow::default();64];2];
+        patterns[0][0][0] = S3MColumn { note: 0x40, instrument: 1, vol: 64, effect: EFFECT_SET_SPEED, effect_value: 6 };
+        patterns[0][10][5].vol = 32;
+        patterns[0][63][31] = S3MColumn { effect: EFFECT_SET_TEMPO, effect_value: 0x7D, ..Default::default() };
+        patterns[1][1][2].note = 254;
+        patterns[1][2][16] = S3MColumn { note: 0x34, instrument: 2, ..Default::default() };
+
+        let mut channel_settings = [255u8;32];
+        for (channel, setting) in channel_settings.iter_mut().take(17).enumerate() {
+            *setting = channel as u8;
+        }
+
+        S3MModule {
+            song_name: *b"Round trip\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0",
+            tracker_metadata: 0x1320,
+            ffi: 2,
+            global_volume: 64,
+            initial_speed: 6,
+            initial_tempo: 125,
+            mixing_volume: 0xB0,
+            default_panning: 0xFC,
+            channel_settings,
+            channel_panning: std::array::from_fn(|channel| 0x20 | (channel as u8 & 0xF)),
+            orders: vec![0, 1, 0, 255],
+            // The first sample's 1.2MB of data pushes the second past what
+            // 16-bit parapointers reach
+            instruments: vec![
+                S3MInstrument::Sample(test_sample(600_000, 0b101, 7)),
+                S3MInstrument::Adlib(adlib),
+                S3MInstrument::Sample(test_sample(1000, 0b000, 256)),
+            ],
+            patterns,
+            message: Some("Saved and loaded again".to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn assert_same_sample(loaded: &S3MSample, original: &S3MSample) {
+        assert_eq!(loaded.filename, original.filename);
+        assert_eq!(loaded.length, original.length);
+        assert_eq!((loaded.loop_begin, loaded.loop_end), (original.loop_begin, original.loop_end));
+        assert_eq!((loaded.volume, loaded.flags, loaded.c4speed), (original.volume, original.flags, original.c4speed));
+        assert_eq!(loaded.sample_name, original.sample_name);
+        assert_eq!(loaded._scrs, original._scrs);
+        assert!(loaded.audio == original.audio);
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let module = test_module();
+        let mut saved: Vec<u8> = Vec::new();
+        module.save(&mut saved).unwrap();
+        let loaded = S3MModule::load(saved.as_slice()).unwrap();
+
+        assert_eq!(loaded.song_name, module.song_name);
+        assert_eq!(loaded.tracker_metadata, module.tracker_metadata);
+        assert_eq!((loaded.initial_speed, loaded.initial_tempo, loaded.global_volume), (6, 125, 64));
+        assert_eq!(loaded.mixing_volume, module.mixing_volume);
+        assert_eq!(loaded.channel_settings, module.channel_settings);
+        assert_eq!(loaded.channel_panning, module.channel_panning);
+        assert_eq!(loaded.orders, module.orders);
+        assert_eq!(loaded.message, module.message);
+        assert!(loaded.patterns == module.patterns);
+
+        assert_eq!(loaded.instruments.len(), 3);
+        for (loaded, original) in loaded.instruments.iter().zip(&module.instruments) {
+            match (loaded, original) {
+                (S3MInstrument::Sample(loaded), S3MInstrument::Sample(original)) => assert_same_sample(loaded, original),
+                (S3MInstrument::Adlib(loaded), S3MInstrument::Adlib(original)) => {
+                    assert_eq!(loaded.filename, original.filename);
+                    assert_eq!(
+                        [loaded.d00, loaded.d01, loaded.d02, loaded.d03, loaded.d04, loaded.d05, loaded.d06, loaded.d07, loaded.d08, loaded.d09, loaded.d0a],
+                        [original.d00, original.d01, original.d02, original.d03, original.d04, original.d05, original.d06, original.d07, original.d08, original.d09, original.d0a],
+                    );
+                    assert_eq!((loaded.volume, loaded.c4freq), (original.volume, original.c4freq));
+                    assert_eq!(loaded.sample_name, original.sample_name);
+                    assert_eq!(loaded._scri, original._scri);
+                },
+                _ => panic!("instrument kind changed"),
+            }
+        }
+
+        // Saving what was loaded lays the file out the same way again
+        let mut saved_again: Vec<u8> = Vec::new();
+        loaded.save(&mut saved_again).unwrap();
+        assert!(saved_again == saved);
+    }
+
+    #[test]
+    fn parapointers_point_at_what_they_name() {
+        let module = test_module();
+        let mut saved: Vec<u8> = Vec::new();
+        module.save(&mut saved).unwrap();
+        let loaded = S3MModule::load(saved.as_slice()).unwrap();
+
+        let headers: Vec<usize> = loaded.sample_offsets.iter().map(|offset| *offset as usize * 16).collect();
+        assert!(headers.windows(2).all(|pair| pair[0] + INSTRUMENT_HEADER_SIZE as usize <= pair[1]));
+        assert_eq!(&saved[headers[0] + 0x4C..headers[0] + 0x50], b"SCRS");
+        assert_eq!(&saved[headers[1] + 0x4C..headers[1] + 0x50], b"SCRI");
+
+        let mut previous_end = headers[2] + INSTRUMENT_HEADER_SIZE as usize;
+        for (offset, pattern) in loaded.pattern_offsets.iter().zip(&module.patterns) {
+            let start = *offset as usize * 16;
+            assert!(start >= previous_end);
+            let packed = pack_pattern(pattern);
+            assert_eq!(u16::from_le_bytes([saved[start], saved[start + 1]]) as usize, packed.len() + 2);
+            assert_eq!(&saved[start + 2..start + 2 + packed.len()], packed.as_slice());
+            previous_end = start + 2 + packed.len();
+        }
+
+        // Sample data follows the patterns, the second sample's pointer
+        // needing the memseg's high byte
+        let S3MInstrument::Sample(first) = &loaded.instruments[0] else { unreachable!() };
+        let S3MInstrument::Sample(last) = &loaded.instruments[2] else { unreachable!() };
+        let data_offset = |sample: &S3MSample| ((sample.memseg[0] as usize) << 20) | (u16::from_le_bytes([sample.memseg[1], sample.memseg[2]]) as usize) << 4;
+        assert!(data_offset(first) >= previous_end);
+        assert_eq!(first.memseg[0], 0);
+        assert_ne!(last.memseg[0], 0);
+        assert!(data_offset(last) >= data_offset(first) + 600_000 * 2);
+        assert_eq!(data_offset(last) + 1000, saved.len());
+    }
+}
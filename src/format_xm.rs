@@ -0,0 +1,422 @@
+use std::{fmt, io::{self, Read}};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+
+use crate::{diagnostics::{Category, Diagnostic, Diagnostics, Severity}, format_mod, format_s3m::{S3MColumn, S3MInstrument, S3MModule, S3MRow, S3MSample, EFFECT_BREAK, EFFECT_PORTAMENTO_DOWN, EFFECT_PORTAMENTO_UP, EFFECT_PORTAMENTO_VOLUME_SLIDE, EFFECT_RETRIGGER, EFFECT_SPECIAL, EFFECT_TONE_PORTAMENTO, EFFECT_VIBRATO, EFFECT_VIBRATO_VOLUME_SLIDE, EFFECT_VOLUME_SLIDE}, import::{pcm_channel_setting, split_patterns, unroll_ping_pong, TooManyPatterns}};
+
+const MAGIC: &[u8;17] = b"Extended Module: ";
+const SAMPLE_HEADER_SIZE: usize = 40;
+/// Fields of a pattern header up to its packed size.
+const PATTERN_HEADER_SIZE: u64 = 9;
+/// Most patterns and rows per pattern FT2 allows.
+const MAX_PATTERNS: usize = 256;
+const MAX_ROWS: usize = 256;
+
+/// Loads a FastTracker 2 module. Each instrument becomes the sample it plays
+/// most of its notes with, at a C4 rate folding in relative note and
+/// finetune. Envelopes are dropped, patterns are laid out as 64 row S3M
+/// patterns.
+pub fn load(mut reader: impl io::Read) -> Result<S3MModule, XMLoadError> {
+    let mut data: Vec<u8> = Vec::new();
+    reader.read_to_end(&mut data)?;
+
+    if data.len() < 60 || &data[..MAGIC.len()] != MAGIC {
+        return Err(XMLoadError::BadMagic);
+    }
+
+    let mut reader = io::Cursor::new(&data[..]);
+    let module = read_module(&mut reader);
+    module.map_err(|error| match error {
+        XMLoadError::Io(error) if error.kind() == io::ErrorKind::UnexpectedEof => {
+            XMLoadError::Truncated { offset: reader.position() }
+        },
+        error => error,
+    })
+}
+
+fn read_module(reader: &mut io::Cursor<&[u8]>) -> Result<S3MModule, XMLoadError> {
+    let mut module = S3MModule {
+        // Scream Tracker 3.20, signed samples
+        tracker_metadata: 0x1320,
+        ffi: 1,
+        _scrm: 0x4D524353,
+        global_volume: 64,
+        // Stereo
+        mixing_volume: 0xB0,
+        ..Default::default()
+    };
+
+    reader.set_position(MAGIC.len() as u64);
+    reader.read_exact(&mut module.song_name[..20])?;
+    reader.set_position(58);
+    let version = reader.read_u16::<LittleEndian>()?;
+    if version < 0x0104 {
+        return Err(XMLoadError::UnsupportedVersion(version));
+    }
+
+    let header_size = reader.read_u32::<LittleEndian>()? as u64;
+    let song_length = reader.read_u16::<LittleEndian>()? as usize;
+    let restart = reader.read_u16::<LittleEndian>()? as usize;
+    let channel_amount = reader.read_u16::<LittleEndian>()? as usize;
+    let pattern_amount = reader.read_u16::<LittleEndian>()? as usize;
+    let instrument_amount = reader.read_u16::<LittleEndian>()? as usize;
+    let _flags = reader.read_u16::<LittleEndian>()?;
+    module.initial_speed = reader.read_u16::<LittleEndian>()?.clamp(1, 31) as u8;
+    module.initial_tempo = reader.read_u16::<LittleEndian>()?.clamp(32, 255) as u8;
+    let mut order_table = [0u8;256];
+    reader.read_exact(&mut order_table)?;
+
+    if pattern_amount > MAX_PATTERNS {
+        return Err(XMLoadError::PatternAmount(pattern_amount));
+    }
+    if channel_amount > 32 {
+        module.diagnostics.push(Diagnostic::new(
+            Severity::Warning,
+            Category::DroppedChannel,
+            format!("Only the first 32 of {} channels are kept", channel_amount),
+        ));
+    }
+    module.channel_settings = [0xFF;32];
+    for (index, setting) in module.channel_settings.iter_mut().enumerate().take(channel_amount) {
+        *setting = pcm_channel_setting(index);
+    }
+
+    reader.set_position(60 + header_size);
+    let mut patterns: Vec<Vec<S3MRow>> = Vec::new();
+    for index in 0..pattern_amount {
+        let start = reader.position();
+        let pattern_header_size = reader.read_u32::<LittleEndian>()? as u64;
+        let _packing = reader.read_u8()?;
+        let rows = reader.read_u16::<LittleEndian>()? as usize;
+        let packed_size = reader.read_u16::<LittleEndian>()? as usize;
+        if pattern_header_size < PATTERN_HEADER_SIZE {
+            return Err(XMLoadError::BadPatternHeader { index, offset: start, size: pattern_header_size });
+        }
+        if !(1..=MAX_ROWS).contains(&rows) {
+            return Err(XMLoadError::BadRowCount { index, offset: start, rows });
+        }
+        reader.set_position(start + pattern_header_size);
+
+        let packed_start = reader.position() as usize;
+        let Some(packed) = reader.get_ref().get(packed_start..packed_start+packed_size) else {
+            return Err(XMLoadError::PatternOutOfBounds { index, offset: packed_start as u64 });
+        };
+        let pattern = if packed_size == 0 {
+            vec![S3MRow::default();rows]
+        } else {
+            unpack_pattern(packed, rows, channel_amount)
+                .ok_or(XMLoadError::PatternOutOfBounds { index, offset: packed_start as u64 })?
+        };
+        patterns.push(pattern);
+        reader.set_position((packed_start + packed_size) as u64);
+    }
+
+    for index in 0..instrument_amount {
+        let instrument = read_instrument(reader, index, &mut module.diagnostics)?;
+        module.instruments.push(S3MInstrument::Sample(instrument));
+    }
+
+    let orders = &order_table[..song_length.min(256)];
+    (module.patterns, module.orders) = split_patterns(&patterns, orders, restart, &mut module.diagnostics)?;
+
+    module.order_amount = module.orders.len() as u16;
+    module.sample_amount = module.instruments.len() as u16;
+    module.pattern_amount = module.patterns.len() as u16;
+
+    Ok(module)
+}
+
+fn unpack_pattern(data: &[u8], rows: usize, channel_amount: usize) -> Option<Vec<S3MRow>> {
+    let mut pattern = vec![S3MRow::default();rows];
+    let mut position = 0;
+    let mut next = || -> Option<u8> {
+        let byte = *data.get(position)?;
+        position += 1;
+        Some(byte)
+    };
+
+    for row in pattern.iter_mut() {
+        for channel in 0..channel_amount {
+            let first = next()?;
+            let (mask, note) = if first & 0x80 != 0 {
+                (first, if first & 1 != 0 { next()? } else { 0 })
+            } else {
+                (0x1F, first)
+            };
+            let instrument = if mask & 2 != 0 { next()? } else { 0 };
+            let volume = if mask & 4 != 0 { next()? } else { 0 };
+            let effect = if mask & 8 != 0 { next()? } else { 0 };
+            let param = if mask & 16 != 0 { next()? } else { 0 };
+
+            if let Some(col) = row.get_mut(channel) {
+                *col = convert_cell(note, instrument, volume, effect, param);
+            }
+        }
+    }
+
+    Some(pattern)
+}
+
+fn convert_cell(note: u8, instrument: u8, volume: u8, effect: u8, param: u8) -> S3MColumn {
+    let mut col = S3MColumn { instrument, ..Default::default() };
+    col.note = match note {
+        // C-0 to B-7
+        1..=96 => (((note - 1) / 12) << 4) | ((note - 1) % 12),
+        97 => 254,
+        _ => 255,
+    };
+
+    match effect {
+        // Unlike MOD, FT2 remembers the last parameter like ST3 does
+        0x1 | 0x2 | 0x5 | 0x6 | 0xA if param == 0 => {
+            col.effect = match effect {
+                0x1 => EFFECT_PORTAMENTO_UP,
+                0x2 => EFFECT_PORTAMENTO_DOWN,
+                0x5 => EFFECT_PORTAMENTO_VOLUME_SLIDE,
+                0x6 => EFFECT_VIBRATO_VOLUME_SLIDE,
+                _ => EFFECT_VOLUME_SLIDE,
+            };
+        },
+        0x0..=0xF => {
+            format_mod::convert_effect(&mut col, effect, param);
+            if col.effect == EFFECT_BREAK {
+                // Plain row numbers until the patterns are laid out
+                col.effect_value = (param >> 4) * 10 + (param & 0xF);
+            }
+        },
+        // Kxx, key off
+        0x14 => (col.effect, col.effect_value) = (EFFECT_SPECIAL, 0xC0 | param.min(0xF)),
+        // Rxy, multi retrigger
+        0x1B => (col.effect, col.effect_value) = (EFFECT_RETRIGGER, param),
+        // X1x and X2x, extra fine portamento
+        0x21 if param >> 4 == 1 => (col.effect, col.effect_value) = (EFFECT_PORTAMENTO_UP, 0xE0 | (param & 0xF)),
+        0x21 if param >> 4 == 2 => (col.effect, col.effect_value) = (EFFECT_PORTAMENTO_DOWN, 0xE0 | (param & 0xF)),
+        _ => {},
+    }
+
+    // The volume column sets the volume unless Cxx does, or takes an
+    // effect if there is room
+    let (command, value) = (volume >> 4, volume & 0xF);
+    match command {
+        0x1..=0x5 if col.vol == 255 => col.vol = (volume - 0x10).min(64),
+        0x1..=0x5 => {},
+        _ if col.effect != 0 => {},
+        0x6 => (col.effect, col.effect_value) = (EFFECT_VOLUME_SLIDE, value),
+        0x7 => (col.effect, col.effect_value) = (EFFECT_VOLUME_SLIDE, value << 4),
+        0x8 if value != 0 => (col.effect, col.effect_value) = (EFFECT_VOLUME_SLIDE, 0xF0 | value),
+        0x9 if value != 0 => (col.effect, col.effect_value) = (EFFECT_VOLUME_SLIDE, (value << 4) | 0xF),
+        0xB => (col.effect, col.effect_value) = (EFFECT_VIBRATO, value),
+        0xF => (col.effect, col.effect_value) = (EFFECT_TONE_PORTAMENTO, value << 4),
+        _ => {},
+    }
+
+    col
+}
+
+fn read_instrument(reader: &mut io::Cursor<&[u8]>, index: usize, diagnostics: &mut Diagnostics) -> Result<S3MSample, XMLoadError> {
+    let start = reader.position();
+    let header_size = reader.read_u32::<LittleEndian>()? as u64;
+    let mut name = [0u8;22];
+    reader.read_exact(&mut name)?;
+    let _instrument_type = reader.read_u8()?;
+    let sample_amount = reader.read_u16::<LittleEndian>()? as usize;
+
+    let mut instrument = S3MSample::default();
+    instrument.sample_name[..22].copy_from_slice(&name);
+    if sample_amount == 0 {
+        reader.set_position(start + header_size);
+        return Ok(instrument);
+    }
+
+    let _sample_header_size = reader.read_u32::<LittleEndian>()?;
+    let mut note_samples = [0u8;96];
+    reader.read_exact(&mut note_samples)?;
+    reader.set_position(start + header_size);
+
+    let mut headers: Vec<[u8;SAMPLE_HEADER_SIZE]> = Vec::new();
+    for _ in 0..sample_amount {
+        let mut header = [0u8;SAMPLE_HEADER_SIZE];
+        reader.read_exact(&mut header)?;
+        headers.push(header);
+    }
+
+    // S3M instruments have one sample, keep the one most notes play
+    let mut note_counts = vec![0usize;sample_amount];
+    for sample in note_samples {
+        if let Some(count) = note_counts.get_mut(sample as usize) {
+            *count += 1;
+        }
+    }
+    let chosen = (0..sample_amount).max_by_key(|sample| (note_counts[*sample], usize::MAX - sample)).unwrap_or(0);
+    if note_counts.iter().filter(|count| **count > 0).count() > 1 {
+        diagnostics.push(
+            Diagnostic::new(Severity::Warning, Category::DroppedInstrument, format!("Plays several samples, keeping sample {}", chosen + 1))
+                .with_instrument(index)
+        );
+    }
+
+    for (sample_index, header) in headers.iter().enumerate() {
+        let dword = |offset: usize| u32::from_le_bytes(header[offset..offset+4].try_into().unwrap());
+        let length = dword(0) as usize;
+        let data_start = reader.position() as usize;
+        let Some(data) = reader.get_ref().get(data_start..data_start+length) else {
+            return Err(XMLoadError::SampleOutOfBounds { index, offset: data_start as u64, length: length as u64 });
+        };
+        reader.set_position((data_start + length) as u64);
+        if sample_index != chosen {
+            continue;
+        }
+
+        let sixteen_bit = header[14] & 0x10 != 0;
+        let audio = decode_deltas(data, sixteen_bit);
+        let bytes_per_sample = if sixteen_bit { 2 } else { 1 };
+
+        let finetune = header[13] as i8 as f64;
+        let relative_note = header[16] as i8 as f64;
+        instrument.sample_type = 1;
+        instrument.volume = header[12].min(64);
+        instrument.c4speed = (8363.0 * 2f64.powf((relative_note * 128.0 + finetune) / (12.0 * 128.0))).round() as u32;
+        instrument.length = audio.len() as u32;
+        instrument.audio = audio;
+
+        let loop_begin = (dword(4) / bytes_per_sample).min(instrument.length);
+        let loop_end = (loop_begin + dword(8) / bytes_per_sample).min(instrument.length);
+        let loop_type = header[14] & 3;
+        if loop_type != 0 && loop_end > loop_begin {
+            instrument.loop_begin = loop_begin;
+            instrument.loop_end = loop_end;
+            instrument.flags = 1;
+            if loop_type == 2 {
+                unroll_ping_pong(&mut instrument);
+            }
+        }
+        if name.iter().all(|byte| *byte == 0) {
+            instrument.sample_name[..22].copy_from_slice(&header[18..40]);
+        }
+    }
+
+    Ok(instrument)
+}
+
+/// Sample data is stored as deltas of consecutive values.
+fn decode_deltas(data: &[u8], sixteen_bit: bool) -> Vec<i16> {
+    if sixteen_bit {
+        let mut value = 0i16;
+        data.chunks_exact(2)
+            .map(|delta| {
+                value = value.wrapping_add(i16::from_le_bytes([delta[0], delta[1]]));
+                value
+            })
+            .collect()
+    } else {
+        let mut value = 0i8;
+        data.iter()
+            .map(|delta| {
+                value = value.wrapping_add(*delta as i8);
+                value as i16 * 256
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+pub enum XMLoadError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u16),
+    Truncated { offset: u64 },
+    /// More patterns than FT2 allows.
+    PatternAmount(usize),
+    /// A pattern header too short to hold its own fields.
+    BadPatternHeader { index: usize, offset: u64, size: u64 },
+    BadRowCount { index: usize, offset: u64, rows: usize },
+    PatternOutOfBounds { index: usize, offset: u64 },
+    SampleOutOfBounds { index: usize, offset: u64, length: u64 },
+    TooManyPatterns { patterns: usize, orders: usize },
+}
+
+impl fmt::Display for XMLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            XMLoadError::Io(error) => write!(f, "I/O error: {}", error),
+            XMLoadError::BadMagic => write!(f, "Not an XM module, \"Extended Module: \" is missing"),
+            XMLoadError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported XM version {}.{:02X}, expected 1.04", version >> 8, version & 0xFF)
+            },
+            XMLoadError::Truncated { offset } => {
+                write!(f, "File ends early at offset 0x{:X}", offset)
+            },
+            XMLoadError::PatternAmount(amount) => {
+                write!(f, "{} patterns, XM modules hold at most {}", amount, MAX_PATTERNS)
+            },
+            XMLoadError::BadPatternHeader { index, offset, size } => {
+                write!(f, "Pattern {} at offset 0x{:X} has a {} byte header, expected at least {}", index, offset, size, PATTERN_HEADER_SIZE)
+            },
+            XMLoadError::BadRowCount { index, offset, rows } => {
+                write!(f, "Pattern {} at offset 0x{:X} has {} rows, expected 1-{}", index, offset, rows, MAX_ROWS)
+            },
+            XMLoadError::PatternOutOfBounds { index, offset } => {
+                write!(f, "Pattern {} at offset 0x{:X} runs past the end of the file", index, offset)
+            },
+            XMLoadError::SampleOutOfBounds { index, offset, length } => {
+                write!(f, "Sample data of instrument {} (offset 0x{:X}, {} bytes) runs past the end of the file",
+                    index + 1, offset, length)
+            },
+            XMLoadError::TooManyPatterns { patterns, orders } => {
+                write!(f, "{}", TooManyPatterns { patterns: *patterns, orders: *orders })
+            },
+        }
+    }
+}
+
+impl std::error::Error for XMLoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            XMLoadError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for XMLoadError {
+    fn from(error: io::Error) -> Self {
+        XMLoadError::Io(error)
+    }
+}
+
+impl From<TooManyPatterns> for XMLoadError {
+    fn from(error: TooManyPatterns) -> Self {
+        XMLoadError::TooManyPatterns { patterns: error.patterns, orders: error.orders }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample_deltas_accumulate_and_wrap() {
+        assert_eq!(decode_deltas(&[10, 0xFE, 0x80], false), vec![10 * 256, 8 * 256, -120 * 256]);
+        // A trailing odd byte is not a 16-bit value
+        assert_eq!(decode_deltas(&[0x00, 0x10, 0x00, 0xF0, 0x7F], true), vec![0x1000, 0]);
+    }
+
+    #[test]
+    fn packed_cells_unpack_by_mask() {
+        let data = [
+            // Row 0: a full cell on channel 1, nothing on channel 2
+            49, 1, 0x30, 0, 0, 0x80,
+            // Row 1: a key off with instrument 2, then Axx with no parameter
+            0x83, 97, 2, 0x98, 0x0A, 0x00,
+        ];
+        let pattern = unpack_pattern(&data, 2, 2).unwrap();
+        assert_eq!(pattern.len(), 2);
+        assert_eq!(pattern[0][0], S3MColumn { note: 0x40, instrument: 1, vol: 32, ..Default::default() });
+        assert_eq!(pattern[0][1], S3MColumn::default());
+        assert_eq!(pattern[1][0], S3MColumn { note: 254, instrument: 2, ..Default::default() });
+        assert_eq!(pattern[1][1], S3MColumn { effect: EFFECT_VOLUME_SLIDE, ..Default::default() });
+
+        // Cells run out before the last row does
+        assert_eq!(unpack_pattern(&data[..10], 2, 2), None);
+    }
+}
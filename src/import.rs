@@ -0,0 +1,242 @@
+use std::fmt;
+
+use crate::diagnostics::{Category, Diagnostic, Diagnostics, Severity};
+use crate::format_s3m::{S3MPattern, S3MRow, S3MSample, EFFECT_BREAK, EFFECT_JUMP, MAX_ORDERS, MAX_PATTERNS};
+
+/// S3M channel setting for the `index`th sample channel of a module with
+/// more channels than ST3 names, alternating left and right. ST3 only has 16
+/// sample channel settings, so from the 17th channel on they repeat. That
+/// is harmless here, channels are told apart by their position and their
+/// setting only says they play samples and on which side.
+pub fn pcm_channel_setting(index: usize) -> u8 {
+    let channel = (index % 16) as u8;
+    if channel.is_multiple_of(2) {
+        channel / 2
+    } else {
+        8 + channel / 2
+    }
+}
+
+/// Lays patterns of any length out as 64 row S3M patterns and rewrites the
+/// order list to match. Patterns longer than 64 rows take several orders,
+/// shorter ones end in a pattern break, and Bxx and Cxx are rewritten to
+/// land where they did. Pattern breaks in `patterns` hold plain row numbers
+/// rather than BCD. Orders are pattern numbers, 254 (skip) or 255 (end).
+/// A nonzero `restart` order is where the song continues after the last one.
+/// Jumps with no free effect column left to go in end up in `diagnostics`.
+pub fn split_patterns(patterns: &[Vec<S3MRow>], orders: &[u8], restart: usize, diagnostics: &mut Diagnostics) -> Result<(Vec<S3MPattern>, Vec<u8>), TooManyPatterns> {
+    let orders: Vec<u8> = orders.iter().copied().take_while(|pattern| *pattern != 255).collect();
+    let empty = vec![S3MRow::default();64];
+    let rows_of = |pattern: u8| -> &[S3MRow] {
+        match patterns.get(pattern as usize) {
+            Some(rows) if !rows.is_empty() => rows,
+            // Trackers play missing patterns as empty ones
+            _ => &empty,
+        }
+    };
+    let chunk_count = |pattern: u8| -> usize {
+        if pattern == 254 {
+            1
+        } else {
+            rows_of(pattern).len().div_ceil(64)
+        }
+    };
+
+    // Where each order starts in the new order list
+    let mut order_starts: Vec<usize> = Vec::new();
+    let mut total = 0;
+    for pattern in &orders {
+        order_starts.push(total);
+        total += chunk_count(*pattern);
+    }
+    let order_start = |order: usize| order_starts.get(order).copied().unwrap_or(total);
+
+    let mut s3m_patterns: Vec<S3MPattern> = Vec::new();
+    let mut s3m_orders: Vec<usize> = Vec::new();
+    for (order, pattern) in orders.iter().enumerate() {
+        if *pattern == 254 {
+            s3m_orders.push(254);
+            continue;
+        }
+
+        let after = if order + 1 < orders.len() {
+            order_start(order + 1)
+        } else if restart != 0 && restart < orders.len() {
+            order_start(restart)
+        } else {
+            total
+        };
+        // Rows of the order after this one
+        let after_rows = orders.get(order + 1).map_or(64, |next| chunk_count(*next) * 64);
+
+        let chunks: Vec<&[S3MRow]> = rows_of(*pattern).chunks(64).collect();
+        for (index, chunk) in chunks.iter().enumerate() {
+            let natural_next = order_starts[order] + index + 1;
+            let mut s3m_pattern = [S3MRow::default();64];
+            s3m_pattern[..chunk.len()].copy_from_slice(chunk);
+
+            let mut ends = false;
+            let mut jump_lost = false;
+            for row in s3m_pattern[..chunk.len()].iter_mut() {
+                let mut destination: Option<(usize, u8)> = None;
+                for col in row.iter_mut() {
+                    match col.effect {
+                        EFFECT_JUMP => {
+                            destination = Some((order_start(col.effect_value as usize), 0));
+                            col.effect = 0;
+                        },
+                        EFFECT_BREAK => {
+                            let target = (col.effect_value as usize).min(after_rows - 1);
+                            let row = destination.map_or(0, |(_, row)| row).max((target % 64) as u8);
+                            let order = destination.map_or(after + target / 64, |(order, _)| order);
+                            destination = Some((order, row));
+                            col.effect = 0;
+                        },
+                        _ => {},
+                    }
+                }
+                if let Some((order, row_number)) = destination {
+                    jump_lost |= !set_destination(row, order, row_number, natural_next);
+                    ends = true;
+                    break;
+                }
+            }
+
+            // The last chunk carries on to the next order, wherever that now is
+            if index == chunks.len() - 1 && !ends && (chunk.len() < 64 || after != natural_next) {
+                jump_lost |= !set_destination(&mut s3m_pattern[chunk.len() - 1], after, 0, natural_next);
+            }
+            if jump_lost {
+                diagnostics.push(Diagnostic::new(
+                    Severity::Warning,
+                    Category::UnsupportedEffect,
+                    format!("Pattern {} has no free effect column for a jump, the song may play in a different order", pattern),
+                ));
+            }
+
+            let index = match s3m_patterns.iter().position(|existing| *existing == s3m_pattern) {
+                Some(index) => index,
+                None => {
+                    s3m_patterns.push(s3m_pattern);
+                    s3m_patterns.len() - 1
+                },
+            };
+            s3m_orders.push(index);
+        }
+    }
+
+    // ST3 wants the order list ended by a marker and padded to an even length
+    let order_amount = (s3m_orders.len() + 2) & !1;
+    if order_amount > MAX_ORDERS || s3m_patterns.len() > MAX_PATTERNS {
+        return Err(TooManyPatterns { patterns: s3m_patterns.len(), orders: order_amount });
+    }
+    let mut s3m_orders: Vec<u8> = s3m_orders.into_iter().map(|index| index as u8).collect();
+    s3m_orders.resize(order_amount, 255);
+
+    Ok((s3m_patterns, s3m_orders))
+}
+
+/// A song that takes more patterns or orders than ST3 can hold once laid
+/// out as 64 row patterns.
+#[derive(Debug)]
+pub struct TooManyPatterns {
+    pub patterns: usize,
+    pub orders: usize,
+}
+
+impl fmt::Display for TooManyPatterns {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Song needs {} patterns and {} orders, more than ST3 can hold ({} and {})",
+            self.patterns, self.orders, MAX_PATTERNS, MAX_ORDERS
+        )
+    }
+}
+
+/// Ends a row with a jump to `row` of `order`. The jump is left out when
+/// `order` comes next anyway, the break when it would go to row 0 of the
+/// next order. Returns whether there was room for them.
+fn set_destination(row: &mut S3MRow, order: usize, row_number: u8, natural_next: usize) -> bool {
+    let mut effects: Vec<(u8, u8)> = Vec::new();
+    if order != natural_next {
+        effects.push((EFFECT_JUMP, order.min(255) as u8));
+    }
+    if row_number != 0 || order == natural_next {
+        effects.push((EFFECT_BREAK, ((row_number / 10) << 4) | (row_number % 10)));
+    }
+
+    for (effect, value) in effects {
+        match row.iter_mut().find(|col| col.effect == 0) {
+            Some(col) => (col.effect, col.effect_value) = (effect, value),
+            None => return false,
+        }
+    }
+    true
+}
+
+/// Turns a ping-pong loop into a forward one, by following the loop with a
+/// reversed copy of itself.
+pub fn unroll_ping_pong(sample: &mut S3MSample) {
+    let (start, end) = (sample.loop_begin as usize, sample.loop_end as usize);
+    if end <= start + 1 || end > sample.audio.len() {
+        return;
+    }
+
+    let mut audio = sample.audio[..end].to_vec();
+    audio.extend(sample.audio[start+1..end-1].iter().rev());
+    sample.loop_end = audio.len() as u32;
+    sample.length = audio.len() as u32;
+    sample.audio = audio;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A pattern of `rows` rows whose first note is `note`.
+    fn pattern(rows: usize, note: u8) -> Vec<S3MRow> {
+        let mut pattern = vec![S3MRow::default();rows];
+        pattern[0][0].note = note;
+        pattern
+    }
+
+    #[test]
+    fn long_patterns_take_several_orders() {
+        let patterns = vec![pattern(100, 0), pattern(64, 1)];
+        let (s3m_patterns, orders) = split_patterns(&patterns, &[1, 0, 255], 0, &mut Diagnostics::default()).unwrap();
+        assert_eq!(orders, vec![0, 1, 2, 255]);
+        assert_eq!(s3m_patterns.len(), 3);
+        // The 36 rows left of pattern 0 break to the end of the song
+        assert!(s3m_patterns[2][35].iter().any(|col| col.effect == EFFECT_BREAK));
+    }
+
+    #[test]
+    fn jumps_with_no_free_column_are_diagnosed() {
+        // A short pattern has to break at its last row, which is full
+        let mut patterns = vec![pattern(10, 0)];
+        for col in patterns[0][9].iter_mut() {
+            col.effect = 1;
+        }
+        let mut diagnostics = Diagnostics::default();
+        split_patterns(&patterns, &[0, 255], 0, &mut diagnostics).unwrap();
+        assert_eq!(diagnostics.count(Category::UnsupportedEffect), 1);
+
+        let mut diagnostics = Diagnostics::default();
+        split_patterns(&[pattern(10, 0)], &[0, 255], 0, &mut diagnostics).unwrap();
+        assert!(diagnostics.is_empty());
+    }
+
+    #[test]
+    fn songs_st3_cannot_hold_are_rejected() {
+        let mut patterns: Vec<Vec<S3MRow>> = (0..253).map(|index| pattern(64, index as u8)).collect();
+        let orders: Vec<u8> = (0..254).collect();
+        patterns.push(pattern(64, 253));
+        assert!(split_patterns(&patterns, &orders, 0, &mut Diagnostics::default()).is_ok());
+
+        // One pattern more once the last one takes two
+        patterns[253] = pattern(128, 253);
+        let error = split_patterns(&patterns, &orders, 0, &mut Diagnostics::default()).unwrap_err();
+        assert_eq!((error.patterns, error.orders), (255, 256));
+    }
+}
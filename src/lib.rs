@@ -0,0 +1,214 @@
+use std::{fmt, io, path::Path};
+
+use channel_reduction::reduce_channels;
+use conversion::Converter;
+use diagnostics::{Category, Diagnostic, Severity};
+use format_c67::C67Module;
+use format_it::ITLoadError;
+use format_mod::MODLoadError;
+use format_rad::RADLoadError;
+use format_s3m::{S3MLoadError, S3MModule};
+use format_xm::XMLoadError;
+
+pub mod format_s3m;
+pub mod format_c67;
+pub mod format_it;
+pub mod format_mod;
+pub mod format_rad;
+pub mod format_xm;
+pub mod diagnostics;
+mod conversion;
+mod reverse_conversion;
+mod adlib_drums;
+mod channel_reduction;
+mod opl;
+mod playback;
+mod pitch;
+mod render;
+mod resample;
+mod song;
+mod timing;
+mod voice_allocation;
+mod flow;
+mod import;
+
+pub use channel_reduction::ChannelSelection;
+pub use conversion::ConversionReport;
+pub use pitch::PitchEffectOptions;
+pub use render::{render_c67, render_s3m, Rendering};
+pub use resample::ResampleQuality;
+pub use reverse_conversion::{c67_to_s3m, ReverseConversionError};
+pub use timing::TimingReport;
+pub use voice_allocation::{VoiceAllocationOptions, VoicePriority, VoiceStealing};
+
+/// Everything that can be tuned about a conversion to C67.
+#[derive(Debug, Clone, Default)]
+pub struct ConvertOptions {
+    pub voice_allocation: VoiceAllocationOptions,
+    pub resample_quality: ResampleQuality,
+    /// Pitch effects approximated by playing new notes, none by default.
+    pub pitch_effects: PitchEffectOptions,
+    /// Which sample channels keep playing when a song has more than C67's 4.
+    /// Without one, the voice allocator shares the voices out note by note.
+    pub channel_selection: Option<ChannelSelection>,
+}
+
+impl ConvertOptions {
+    /// The defaults for songs in `format`. XM and IT songs tend to use far
+    /// more channels than C67 has voices, so the busiest ones are picked.
+    pub fn for_format(format: InputFormat) -> Self {
+        Self {
+            channel_selection: matches!(format, InputFormat::XM | InputFormat::IT).then_some(ChannelSelection::NoteDensity),
+            ..Default::default()
+        }
+    }
+}
+
+/// Converts a song to C67. The report lists the sample channels left
+/// playing if `options` picks some, and everything lost on the way, from
+/// what loading the song left out through muted channels and ignored
+/// effects to dropped notes.
+pub fn convert(module: &S3MModule, options: &ConvertOptions) -> (C67Module, ConversionReport) {
+    let mut diagnostics = module.diagnostics.clone();
+    let ((song, playback_diagnostics), kept_channels) = match &options.channel_selection {
+        Some(selection) => {
+            let mut reduced = module.clone();
+            let (kept, reduction_diagnostics) = reduce_channels(&mut reduced, selection);
+            diagnostics.extend(reduction_diagnostics);
+            for channel in (0..32).filter(|channel| module.channel_settings[*channel] < 16 && !kept.contains(channel)) {
+                diagnostics.push(
+                    Diagnostic::new(Severity::Warning, Category::DroppedChannel, "Sample channel muted to fit C67's 4 voices")
+                        .on_channel(channel)
+                );
+            }
+            (playback::play(&reduced, options.pitch_effects), Some(kept))
+        },
+        None => (playback::play(module, options.pitch_effects), None),
+    };
+    diagnostics.extend(playback_diagnostics);
+
+    let converter = Converter::new(&song)
+        .with_voice_allocation(options.voice_allocation)
+        .with_resample_quality(options.resample_quality);
+    let (converted, mut report) = converter.convert();
+    diagnostics.extend(report.diagnostics);
+    report.diagnostics = diagnostics;
+    report.kept_channels = kept_channels;
+
+    (converted, report)
+}
+
+/// Length of one pass through `module` in seconds, and the order it loops
+/// back to once the last one has played.
+pub fn play_length(module: &S3MModule) -> (f64, usize) {
+    let (song, _) = playback::play(module, PitchEffectOptions::default());
+    let loop_order = song.orders.get(song.loop_order).map_or(0, |order| order.source_order);
+    (song.tempo_map.seconds(song.length()), loop_order)
+}
+
+/// Song formats that load into an [`S3MModule`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum InputFormat {
+    S3M,
+    MOD,
+    RAD,
+    XM,
+    IT,
+}
+
+impl InputFormat {
+    /// Guesses the format from a file extension, falling back to S3M.
+    pub fn from_path(path: &Path) -> Self {
+        let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or_default();
+        Self::from_extension(extension).unwrap_or(InputFormat::S3M)
+    }
+
+    /// The format files with `extension` hold, if it is a known one.
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        match extension.to_lowercase().as_str() {
+            "s3m" => Some(InputFormat::S3M),
+            "mod" => Some(InputFormat::MOD),
+            "rad" => Some(InputFormat::RAD),
+            "xm" => Some(InputFormat::XM),
+            "it" => Some(InputFormat::IT),
+            _ => None,
+        }
+    }
+}
+
+/// Loads a song in any supported format as an S3M, the form every input
+/// takes before it is converted.
+pub fn load(reader: impl io::Read, format: InputFormat) -> Result<S3MModule, LoadError> {
+    Ok(match format {
+        InputFormat::S3M => S3MModule::load(reader)?,
+        InputFormat::MOD => format_mod::load(reader)?,
+        InputFormat::RAD => format_rad::load(reader)?,
+        InputFormat::XM => format_xm::load(reader)?,
+        InputFormat::IT => format_it::load(reader)?,
+    })
+}
+
+#[derive(Debug)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum LoadError {
+    S3M(S3MLoadError),
+    MOD(MODLoadError),
+    RAD(RADLoadError),
+    XM(XMLoadError),
+    IT(ITLoadError),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::S3M(error) => error.fmt(f),
+            LoadError::MOD(error) => error.fmt(f),
+            LoadError::RAD(error) => error.fmt(f),
+            LoadError::XM(error) => error.fmt(f),
+            LoadError::IT(error) => error.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LoadError::S3M(error) => Some(error),
+            LoadError::MOD(error) => Some(error),
+            LoadError::RAD(error) => Some(error),
+            LoadError::XM(error) => Some(error),
+            LoadError::IT(error) => Some(error),
+        }
+    }
+}
+
+impl From<S3MLoadError> for LoadError {
+    fn from(error: S3MLoadError) -> Self {
+        LoadError::S3M(error)
+    }
+}
+
+impl From<MODLoadError> for LoadError {
+    fn from(error: MODLoadError) -> Self {
+        LoadError::MOD(error)
+    }
+}
+
+impl From<RADLoadError> for LoadError {
+    fn from(error: RADLoadError) -> Self {
+        LoadError::RAD(error)
+    }
+}
+
+impl From<XMLoadError> for LoadError {
+    fn from(error: XMLoadError) -> Self {
+        LoadError::XM(error)
+    }
+}
+
+impl From<ITLoadError> for LoadError {
+    fn from(error: ITLoadError) -> Self {
+        LoadError::IT(error)
+    }
+}
@@ -1,26 +1,632 @@
-use std::{fs::File, io::Write, env};
+use std::{cell::RefCell, env, fmt, fs::{self, File}, io::{BufWriter, Write}, path::{Path, PathBuf}, process, thread};
 
-use format_s3m::S3MModule;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use s3m2c67::{c67_to_s3m, convert, diagnostics::{Category, Diagnostic, Diagnostics, Severity}, format_c67::{C67FMRegisters, C67Module, C67PatternCommand, NO_LOOP}, format_s3m::{S3MInstrument, S3MModule}, load, play_length, render_c67, render_s3m, ChannelSelection, ConversionReport, ConvertOptions, InputFormat, PitchEffectOptions, ResampleQuality, VoicePriority, VoiceStealing};
 
-mod format_s3m;
-mod format_c67;
-mod conversion;
+mod batch;
+
+const USAGE: &str = "\
+Usage: s3m2c67 <command> <input> [options]
+
+Commands:
+  convert <input> [-o <output>]  Convert a song to C67, or a C67 back to S3M
+  info <input>                   Show what a song contains
+  dump <input>                   Print a song's orders and pattern data
+  validate <input>               Check that a song loads and converts without losses
+  render <input> [-o <output>]   Render a C67 to a WAV file, converting other songs
+                                 to C67 first unless --source is given
+  batch <directory> -o <directory>
+                                 Convert every song in a directory tree, writing a
+                                 JSON summary of what each one lost
+
+Inputs are S3M, MOD, RAD, XM and IT songs, told apart by extension, and C67.
+
+Conversion options:
+  --channels <auto|all|N,N,..>   Sample channels to keep when there are more than 4:
+                                 the busiest ones, all of them, or the ones listed.
+                                 XM and IT default to auto, other formats to all
+  --pitch-effects <all|none|E,E,..>
+                                 Pitch effects to imitate by retriggering notes, any of
+                                 arpeggio, vibrato, portamento, tone-portamento [none]
+  --voice-priority <channel-order|loudest>
+                                 Which note wins when voices run out [channel-order]
+  --voice-stealing <never|oldest|quietest>
+                                 Which sounding note gives way to it [oldest]
+  --resample <nearest|linear|sinc>
+                                 Interpolation when resampling [sinc]
+
+General options:
+  -o, --output <path>            Where convert writes to, next to the input by default
+  --json                         Print what convert and validate found lost as JSON
+  --rate <hz>                    Sample rate render writes at [44100]
+  --source                       Render a song as it plays before conversion, to
+                                 compare with the rendering of its C67
+  --summary <path>               Where batch writes its summary [<output>/summary.json]
+  --jobs <count>                 Songs batch converts at once [one per CPU]
+  -q, --quiet                    Only print errors
+  -v, --verbose                  Print more detail, twice for even more
+  -h, --help                     Print this help";
+
+/// The input could not be read, loaded or written.
+const EXIT_FAILURE: i32 = 1;
+/// The command line made no sense.
+const EXIT_USAGE: i32 = 2;
+/// validate found the conversion loses part of the song.
+const EXIT_LOSSY: i32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Command {
+    Convert,
+    Info,
+    Dump,
+    Validate,
+    Render,
+    Batch,
+}
+
+#[derive(Debug)]
+struct Arguments {
+    command: Command,
+    input: PathBuf,
+    output: Option<PathBuf>,
+    summary: Option<PathBuf>,
+    jobs: Option<usize>,
+    json: bool,
+    rate: Option<u32>,
+    source: bool,
+    /// Overrides the input format's default when given.
+    channel_selection: Option<Option<ChannelSelection>>,
+    options: ConvertOptions,
+    verbosity: i32,
+}
+
+/// A command line that could not be understood.
+#[derive(Debug)]
+struct UsageError(String);
+
+impl fmt::Display for UsageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// A command that failed, with the exit code to report.
+struct Failure {
+    code: i32,
+    message: String,
+}
+
+impl Failure {
+    fn new(path: &Path, error: impl fmt::Display) -> Self {
+        Self {
+            code: EXIT_FAILURE,
+            message: format!("{}: {}", path.display(), error),
+        }
+    }
+}
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    let module_file = File::open(&args[1]).unwrap();
-    let module = S3MModule::load(module_file).unwrap();
-    
-    // for i in &module.instruments {
-    //     if let S3MInstrument::Adlib(ai) = i {
-    //         println!("{:?}", String::from_utf8(ai.filename.to_vec()).unwrap());
-    //     }
-    // }
-
-    let converter = conversion::Converter::new(&module);
-    let converted_module = converter.convert();
-    dbg!("{:?}", &converted_module);
-    let serialized_module = converted_module.serialize();
-    let mut file = File::create("out.c67").unwrap();
-    file.write(&serialized_module).unwrap();
-}
\ No newline at end of file
+    let arguments = match parse_arguments(env::args().skip(1)) {
+        Ok(Some(arguments)) => arguments,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return;
+        },
+        Err(error) => {
+            eprintln!("error: {}\nRun s3m2c67 --help for usage", error);
+            process::exit(EXIT_USAGE);
+        },
+    };
+
+    let level = match arguments.verbosity {
+        ..=-1 => LevelFilter::Error,
+        0 => LevelFilter::Info,
+        1 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    log::set_logger(Box::leak(Box::new(Logger { level }))).unwrap();
+    // Warnings are always let through so validate can count them
+    log::set_max_level(level.max(LevelFilter::Warn));
+
+    let result = match arguments.command {
+        Command::Convert => run_convert(&arguments),
+        Command::Info => run_info(&arguments),
+        Command::Dump => run_dump(&arguments),
+        Command::Validate => run_validate(&arguments),
+        Command::Render => run_render(&arguments),
+        Command::Batch => run_batch(&arguments),
+    };
+    if let Err(failure) = result {
+        log::error!("{}", failure.message);
+        process::exit(failure.code);
+    }
+}
+
+/// Returns `None` when help was asked for.
+fn parse_arguments(mut args: impl Iterator<Item = String>) -> Result<Option<Arguments>, UsageError> {
+    let mut command: Option<Command> = None;
+    let mut input: Option<PathBuf> = None;
+    let mut output: Option<PathBuf> = None;
+    let mut summary: Option<PathBuf> = None;
+    let mut jobs: Option<usize> = None;
+    let mut json = false;
+    let mut rate: Option<u32> = None;
+    let mut source = false;
+    let mut channel_selection: Option<Option<ChannelSelection>> = None;
+    let mut options = ConvertOptions::default();
+    let mut verbosity = 0;
+
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or_else(|| UsageError(format!("{} needs a value", name)));
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "-q" | "--quiet" => verbosity = -1,
+            "-v" | "--verbose" => verbosity = verbosity.max(0) + 1,
+            "-vv" => verbosity = verbosity.max(0) + 2,
+            "-o" | "--output" => output = Some(PathBuf::from(value(&arg)?)),
+            "--json" => json = true,
+            "--rate" => {
+                rate = match value(&arg)?.parse::<u32>() {
+                    Ok(rate @ 8000..=192000) => Some(rate),
+                    _ => return Err(UsageError("--rate needs a rate from 8000 to 192000 Hz".to_string())),
+                };
+            },
+            "--source" => source = true,
+            "--summary" => summary = Some(PathBuf::from(value(&arg)?)),
+            "--jobs" => {
+                jobs = match value(&arg)?.parse::<usize>() {
+                    Ok(count) if count > 0 => Some(count),
+                    _ => return Err(UsageError("--jobs needs a positive number".to_string())),
+                };
+            },
+            "--channels" => channel_selection = Some(parse_channels(&value(&arg)?)?),
+            "--pitch-effects" => options.pitch_effects = parse_pitch_effects(&value(&arg)?)?,
+            "--voice-priority" => {
+                options.voice_allocation.priority = match value(&arg)?.as_str() {
+                    "channel-order" => VoicePriority::ChannelOrder,
+                    "loudest" => VoicePriority::Loudest,
+                    other => return Err(UsageError(format!("Unknown voice priority \"{}\"", other))),
+                };
+            },
+            "--voice-stealing" => {
+                options.voice_allocation.stealing = match value(&arg)?.as_str() {
+                    "never" => VoiceStealing::Never,
+                    "oldest" => VoiceStealing::Oldest,
+                    "quietest" => VoiceStealing::Quietest,
+                    other => return Err(UsageError(format!("Unknown voice stealing \"{}\"", other))),
+                };
+            },
+            "--resample" => {
+                options.resample_quality = match value(&arg)?.as_str() {
+                    "nearest" => ResampleQuality::Nearest,
+                    "linear" => ResampleQuality::Linear,
+                    "sinc" => ResampleQuality::Sinc,
+                    other => return Err(UsageError(format!("Unknown resampling \"{}\"", other))),
+                };
+            },
+            flag if flag.starts_with('-') && flag.len() > 1 => {
+                return Err(UsageError(format!("Unknown option {}", flag)));
+            },
+            _ if command.is_none() => {
+                command = Some(match arg.as_str() {
+                    "convert" => Command::Convert,
+                    "info" => Command::Info,
+                    "dump" => Command::Dump,
+                    "validate" => Command::Validate,
+                    "render" => Command::Render,
+                    "batch" => Command::Batch,
+                    other => return Err(UsageError(format!("Unknown command \"{}\"", other))),
+                });
+            },
+            _ if input.is_none() => input = Some(PathBuf::from(arg)),
+            _ => return Err(UsageError(format!("Unexpected argument \"{}\"", arg))),
+        }
+    }
+
+    let Some(command) = command else {
+        return Err(UsageError("No command given".to_string()));
+    };
+    let Some(input) = input else {
+        return Err(UsageError("No input file given".to_string()));
+    };
+    if output.is_some() && !matches!(command, Command::Convert | Command::Render | Command::Batch) {
+        return Err(UsageError("Only convert, render and batch write output files".to_string()));
+    }
+    if output.is_none() && command == Command::Batch {
+        return Err(UsageError("batch needs an output directory".to_string()));
+    }
+    if (summary.is_some() || jobs.is_some()) && command != Command::Batch {
+        return Err(UsageError("--summary and --jobs only apply to batch".to_string()));
+    }
+    if json && !matches!(command, Command::Convert | Command::Validate) {
+        return Err(UsageError("--json only applies to convert and validate".to_string()));
+    }
+    if (rate.is_some() || source) && command != Command::Render {
+        return Err(UsageError("--rate and --source only apply to render".to_string()));
+    }
+    if source && is_c67(&input) {
+        return Err(UsageError("--source needs a song that converts to C67, not a C67".to_string()));
+    }
+
+    Ok(Some(Arguments { command, input, output, summary, jobs, json, rate, source, channel_selection, options, verbosity }))
+}
+
+/// Parses `auto`, `all` or a list of channel numbers counted from 1.
+fn parse_channels(value: &str) -> Result<Option<ChannelSelection>, UsageError> {
+    match value {
+        "auto" => Ok(Some(ChannelSelection::NoteDensity)),
+        "all" => Ok(None),
+        list => {
+            let channels = list.split(',')
+                .map(|channel| match channel.trim().parse::<usize>() {
+                    Ok(channel @ 1..=32) => Ok(channel - 1),
+                    _ => Err(UsageError(format!("Channel \"{}\" is not a number from 1 to 32", channel))),
+                })
+                .collect::<Result<Vec<usize>, UsageError>>()?;
+            Ok(Some(ChannelSelection::Manual(channels)))
+        },
+    }
+}
+
+fn parse_pitch_effects(value: &str) -> Result<PitchEffectOptions, UsageError> {
+    match value {
+        "all" => return Ok(PitchEffectOptions::all()),
+        "none" => return Ok(PitchEffectOptions::default()),
+        _ => {},
+    }
+
+    let mut options = PitchEffectOptions::default();
+    for effect in value.split(',') {
+        match effect.trim() {
+            "arpeggio" => options.arpeggio = true,
+            "vibrato" => options.vibrato = true,
+            "portamento" => options.portamento = true,
+            "tone-portamento" => options.tone_portamento = true,
+            other => return Err(UsageError(format!("Unknown pitch effect \"{}\"", other))),
+        }
+    }
+    Ok(options)
+}
+
+fn is_c67(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("c67"))
+}
+
+/// The conversion options given, with the defaults of `format` for the rest.
+fn convert_options(arguments: &Arguments, format: InputFormat) -> ConvertOptions {
+    ConvertOptions {
+        channel_selection: arguments.channel_selection.clone()
+            .unwrap_or(ConvertOptions::for_format(format).channel_selection),
+        ..arguments.options.clone()
+    }
+}
+
+fn load_song(arguments: &Arguments) -> Result<(S3MModule, ConvertOptions), Failure> {
+    let path = &arguments.input;
+    let file = File::open(path).map_err(|error| Failure::new(path, error))?;
+    let format = InputFormat::from_path(path);
+    let module = load(file, format).map_err(|error| Failure::new(path, error))?;
+
+    Ok((module, convert_options(arguments, format)))
+}
+
+fn load_c67(path: &Path) -> Result<C67Module, Failure> {
+    let file = File::open(path).map_err(|error| Failure::new(path, error))?;
+    C67Module::load(file).map_err(|error| Failure::new(path, error))
+}
+
+fn run_convert(arguments: &Arguments) -> Result<(), Failure> {
+    let input = &arguments.input;
+
+    if is_c67(input) {
+        let module = load_c67(input)?;
+        let converted = c67_to_s3m(&module).map_err(|error| Failure::new(input, error))?;
+
+        let output = arguments.output.clone().unwrap_or_else(|| input.with_extension("s3m"));
+        let file = File::create(&output).map_err(|error| Failure::new(&output, error))?;
+        converted.save(file).map_err(|error| Failure::new(&output, error))?;
+        log::info!("Wrote {}", output.display());
+        return Ok(());
+    }
+
+    let (module, options) = load_song(arguments)?;
+    let (converted, report) = convert(&module, &options);
+    if let Some(kept) = &report.kept_channels {
+        log::info!("Playing channels {:?}", kept.iter().map(|channel| channel + 1).collect::<Vec<_>>());
+    }
+    log::info!("{}", report.timing);
+    print_report(arguments, &report);
+
+    let output = arguments.output.clone().unwrap_or_else(|| input.with_extension("c67"));
+    let mut file = File::create(&output).map_err(|error| Failure::new(&output, error))?;
+    file.write_all(&converted.serialize()).map_err(|error| Failure::new(&output, error))?;
+    log::info!("Wrote {}", output.display());
+
+    Ok(())
+}
+
+fn run_info(arguments: &Arguments) -> Result<(), Failure> {
+    let input = &arguments.input;
+
+    if is_c67(input) {
+        let module = load_c67(input)?;
+        let header = &module.header;
+        let orders = header.playlist.iter().take_while(|pattern| **pattern != 0xFF).count();
+        let mut patterns: Vec<u8> = header.playlist[..orders].to_vec();
+        patterns.sort();
+        patterns.dedup();
+
+        println!("Format: C67");
+        println!("Speed: {}", header.speed);
+        println!("Orders: {}, looping to order {}", orders, header.loop_order);
+        println!("Patterns: {}", patterns.len());
+        println!("PCM instruments:");
+        for (index, meta) in header.instrument_meta.iter().enumerate().filter(|(_, meta)| meta.sample_length != 0) {
+            let looped = if meta.loop_end != NO_LOOP { ", looped" } else { "" };
+            println!("  {:2} {:<12} {} samples{}", index + 1, filename(&header.instrument_filenames[index*13..index*13+12]), meta.sample_length, looped);
+        }
+        println!("FM instruments:");
+        for (index, _) in header.adlib_instrument_meta.iter().enumerate().filter(|(_, registers)| **registers != C67FMRegisters::default()) {
+            println!("  {:2} {}", index + 1, filename(&header.adlib_instrument_filenames[index*13..index*13+12]));
+        }
+        return Ok(());
+    }
+
+    let (module, _) = load_song(arguments)?;
+    log_diagnostics(&module.diagnostics);
+    let (seconds, loop_order) = play_length(&module);
+    let format = InputFormat::from_path(input);
+
+    println!("Format: {:?}", format);
+    if format == InputFormat::S3M {
+        println!("Tracker: {}", module.tracker());
+    }
+    println!("Title: {}", filename(&module.song_name));
+    println!("Orders: {}", module.orders.iter().take_while(|pattern| **pattern != 255).count());
+    println!("Patterns: {}", module.patterns.len());
+    println!("Speed {}, tempo {}", module.initial_speed, module.initial_tempo);
+    println!("Length: {}:{:06.3}, looping to order {}", (seconds / 60.0) as u32, seconds % 60.0, loop_order);
+
+    let enabled = |range: std::ops::RangeInclusive<u8>| module.channel_settings.iter().filter(|setting| range.contains(setting)).count();
+    println!("Channels: {} sample, {} AdLib, {} AdLib drum", enabled(0..=15), enabled(16..=24), enabled(25..=29));
+    println!("Instruments:");
+    for (index, instrument) in module.instruments.iter().enumerate() {
+        match instrument {
+            S3MInstrument::Sample(sample) if sample.audio.is_empty() => {},
+            S3MInstrument::Sample(sample) => {
+                let looped = if sample.flags & 1 != 0 { ", looped" } else { "" };
+                println!("  {:2} sample {:<28} {} samples at {} Hz{}", index + 1, filename(&sample.sample_name), sample.audio.len(), sample.c4speed, looped);
+            },
+            S3MInstrument::Adlib(instrument) => {
+                println!("  {:2} AdLib  {}", index + 1, filename(&instrument.sample_name));
+            },
+        }
+    }
+    if let Some(message) = &module.message {
+        println!("Message:\n{}", message);
+    }
+
+    Ok(())
+}
+
+fn run_dump(arguments: &Arguments) -> Result<(), Failure> {
+    let input = &arguments.input;
+
+    if is_c67(input) {
+        let module = load_c67(input)?;
+        let orders: Vec<u8> = module.header.playlist.iter().copied().take_while(|pattern| *pattern != 0xFF).collect();
+        println!("Orders: {:?}", orders);
+
+        let mut patterns = orders.clone();
+        patterns.sort();
+        patterns.dedup();
+        for pattern in patterns {
+            println!("\nPattern {}", pattern);
+            let commands = module.pattern_commands(pattern as usize).map_err(|error| Failure::new(input, error))?;
+            let mut row = 0u32;
+            for command in commands {
+                match command {
+                    C67PatternCommand::Delay(rows) => row += rows as u32,
+                    command => println!("{:4}  {}", row, command),
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    let (module, _) = load_song(arguments)?;
+    log_diagnostics(&module.diagnostics);
+    let orders: Vec<u8> = module.orders.iter().copied().take_while(|pattern| *pattern != 255).collect();
+    println!("Orders: {:?}", orders);
+
+    // Only channels holding anything are worth the width
+    let channels: Vec<usize> = (0..32)
+        .filter(|channel| module.channel_settings[*channel] & 0x80 == 0)
+        .filter(|channel| module.patterns.iter().flatten().any(|row| row[*channel] != Default::default()))
+        .collect();
+    for (index, pattern) in module.patterns.iter().enumerate() {
+        println!("\nPattern {}", index);
+        for (row_index, row) in pattern.iter().enumerate() {
+            let columns: Vec<String> = channels.iter().map(|channel| row[*channel].to_string()).collect();
+            println!("{:2} | {}", row_index, columns.join(" | "));
+        }
+    }
+
+    Ok(())
+}
+
+fn run_validate(arguments: &Arguments) -> Result<(), Failure> {
+    let input = &arguments.input;
+
+    let diagnostics = if is_c67(input) {
+        let module = load_c67(input)?;
+        let header = &module.header;
+        let orders = header.playlist.iter().take_while(|pattern| **pattern != 0xFF).count();
+        for pattern in &header.playlist[..orders] {
+            if *pattern >= 128 {
+                return Err(Failure::new(input, format!("Order list refers to pattern {}, past the last one", pattern)));
+            }
+            module.pattern_commands(*pattern as usize).map_err(|error| Failure::new(input, error))?;
+        }
+        let mut diagnostics = Diagnostics::default();
+        if header.loop_order as usize >= orders.max(1) {
+            diagnostics.push(Diagnostic::new(
+                Severity::Warning,
+                Category::DamagedFile,
+                format!("Loop order {} is past the end of the song", header.loop_order),
+            ));
+        }
+        log_diagnostics(&diagnostics);
+        diagnostics
+    } else {
+        let (module, options) = load_song(arguments)?;
+        let (_, report) = convert(&module, &options);
+        print_report(arguments, &report);
+        report.diagnostics
+    };
+
+    // Anything past a note on how the song plays differently is a loss
+    match diagnostics.iter().filter(|diagnostic| diagnostic.severity > Severity::Info).count() {
+        0 => {
+            log::info!("{}: OK", input.display());
+            Ok(())
+        },
+        losses => Err(Failure {
+            code: EXIT_LOSSY,
+            message: format!("{}: {} loss{}", input.display(), losses, if losses == 1 { "" } else { "es" }),
+        }),
+    }
+}
+
+fn run_render(arguments: &Arguments) -> Result<(), Failure> {
+    let input = &arguments.input;
+    let rate = arguments.rate.unwrap_or(44100);
+
+    let rendering = if is_c67(input) {
+        render_c67(&load_c67(input)?, rate).map_err(|error| Failure::new(input, error))?
+    } else if arguments.source {
+        let (module, _) = load_song(arguments)?;
+        log_diagnostics(&module.diagnostics);
+        render_s3m(&module, rate)
+    } else {
+        let (module, options) = load_song(arguments)?;
+        let (converted, report) = convert(&module, &options);
+        log_diagnostics(&report.diagnostics);
+        render_c67(&converted, rate).map_err(|error| Failure::new(input, error))?
+    };
+
+    let output = arguments.output.clone().unwrap_or_else(|| input.with_extension("wav"));
+    let file = File::create(&output).map_err(|error| Failure::new(&output, error))?;
+    rendering.write_wav(BufWriter::new(file)).map_err(|error| Failure::new(&output, error))?;
+    log::info!("Wrote {} ({:.3}s)", output.display(), rendering.seconds());
+
+    Ok(())
+}
+
+fn run_batch(arguments: &Arguments) -> Result<(), Failure> {
+    let input = &arguments.input;
+    let output = arguments.output.as_deref().unwrap();
+
+    let songs = batch::find_songs(input).map_err(|error| Failure::new(input, error))?;
+    let jobs = arguments.jobs
+        .unwrap_or_else(|| thread::available_parallelism().map_or(1, |count| count.get()));
+    let summary = batch::convert_tree(arguments, output, &songs, jobs);
+
+    let summary_path = arguments.summary.clone().unwrap_or_else(|| output.join("summary.json"));
+    let json = serde_json::to_string_pretty(&summary).unwrap();
+    if let Some(directory) = summary_path.parent() {
+        fs::create_dir_all(directory).map_err(|error| Failure::new(directory, error))?;
+    }
+    fs::write(&summary_path, json + "\n").map_err(|error| Failure::new(&summary_path, error))?;
+    log::info!("Converted {} of {} songs, summary in {}", summary.converted, songs.len(), summary_path.display());
+
+    match summary.failed {
+        0 => Ok(()),
+        failed => Err(Failure {
+            code: EXIT_FAILURE,
+            message: format!("{} of {} songs failed to convert", failed, songs.len()),
+        }),
+    }
+}
+
+/// Shows what a conversion lost, as JSON on stdout with `--json` and as log
+/// messages otherwise.
+fn print_report(arguments: &Arguments, report: &ConversionReport) {
+    if arguments.json {
+        println!("{}", serde_json::to_string_pretty(report).unwrap());
+    }
+    log_diagnostics(&report.diagnostics);
+}
+
+/// Logs diagnostics, warnings for anything lost. Dropped notes can run into
+/// the thousands, so only their count is a warning.
+fn log_diagnostics(diagnostics: &Diagnostics) {
+    for diagnostic in diagnostics {
+        match (diagnostic.category, diagnostic.severity) {
+            (Category::DroppedNote, _) | (_, Severity::Info) => log::debug!("{}", diagnostic),
+            _ => log::warn!("{}", diagnostic),
+        }
+    }
+    match diagnostics.count(Category::DroppedNote) {
+        0 => {},
+        1 => log::warn!("1 note dropped or cut off"),
+        notes => log::warn!("{} notes dropped or cut off", notes),
+    }
+}
+
+/// Text of a NUL padded name field.
+fn filename(bytes: &[u8]) -> String {
+    let length = bytes.iter().position(|byte| *byte == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..length]).trim_end().to_string()
+}
+
+thread_local! {
+    /// Warnings held back by `capture_warnings` on this thread.
+    static CAPTURED: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
+}
+
+/// Runs `f`, collecting the warnings it logs on this thread instead of
+/// printing them.
+fn capture_warnings<T>(f: impl FnOnce() -> T) -> (T, Vec<String>) {
+    CAPTURED.with(|captured| *captured.borrow_mut() = Some(Vec::new()));
+    let result = f();
+    let warnings = CAPTURED.with(|captured| captured.borrow_mut().take()).unwrap_or_default();
+    (result, warnings)
+}
+
+/// Prints log messages to stderr, warnings and errors with a prefix.
+struct Logger {
+    level: LevelFilter,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if record.level() == Level::Warn {
+            let captured = CAPTURED.with(|captured| match captured.borrow_mut().as_mut() {
+                Some(warnings) => {
+                    warnings.push(record.args().to_string());
+                    true
+                },
+                None => false,
+            });
+            if captured {
+                return;
+            }
+        }
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        match record.level() {
+            Level::Error => eprintln!("error: {}", record.args()),
+            Level::Warn => eprintln!("warning: {}", record.args()),
+            _ => eprintln!("{}", record.args()),
+        }
+    }
+
+    fn flush(&self) {}
+}
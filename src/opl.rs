@@ -0,0 +1,332 @@
+use std::f64::consts::PI;
+
+/// Rate the OPL2 generates samples at, its 14.318 MHz clock divided by 288.
+pub const OPL_RATE: f64 = 49716.0;
+
+/// F-numbers of the notes C to B. In block 4 they play the octave from
+/// middle C, each block above doubles the frequency.
+pub const F_NUMBERS: [u16;12] = [0x159, 0x16D, 0x183, 0x19A, 0x1B3, 0x1CC, 0x1E8, 0x205, 0x223, 0x244, 0x267, 0x28B];
+
+/// Block and F-number playing `semitone` above C-0, which may lie between
+/// notes. Each octave takes the block of its number, so the F-numbers match
+/// [`F_NUMBERS`] on whole notes.
+pub fn block_and_f_number(semitone: f64) -> (u8, u16) {
+    let block = (semitone / 12.0).floor().clamp(0.0, 7.0);
+    let frequency = 261.6256 * 2f64.powf((semitone - 48.0) / 12.0);
+    let f_number = (frequency * 2f64.powf(20.0 - block) / OPL_RATE).round().min(1023.0);
+    (block as u8, f_number as u16)
+}
+
+/// Frequency multiples selected by the low nibble of registers 0x20-0x35.
+const MULTIPLIERS: [f64;16] = [0.5, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0, 10.0, 12.0, 12.0, 15.0, 15.0];
+
+/// Key scale level attenuation by the top four bits of the F-number, in
+/// 0.75dB steps before the block is taken into account.
+const KEY_SCALE_LEVELS: [i32;16] = [0, 32, 40, 45, 48, 51, 53, 55, 56, 58, 59, 60, 61, 62, 63, 64];
+
+/// Attack and decay times of rate 1, in seconds. Every rate above halves them.
+const ATTACK_TIME: f64 = 2.82624;
+const DECAY_TIME: f64 = 39.28;
+
+/// Dynamic range of the envelope, in dB.
+const SILENCE: f64 = 96.0;
+
+/// Operators of each channel, modulator first.
+const CHANNEL_OPERATORS: [(usize, usize);9] = [(0, 3), (1, 4), (2, 5), (6, 9), (7, 10), (8, 11), (12, 15), (13, 16), (14, 17)];
+
+/// Software Yamaha YM3812, as found on AdLib and Sound Blaster cards. It is
+/// driven by register writes like the real chip and renders its 9 melodic
+/// channels at any rate. Rhythm mode is not emulated.
+#[derive(Debug, Clone)]
+pub struct Opl2 {
+    rate: f64,
+    /// Whether registers 0xE0-0xF5 pick waveforms, else all are sines.
+    waveform_select: bool,
+    deep_tremolo: bool,
+    deep_vibrato: bool,
+    operators: [Operator;18],
+    channels: [OplChannel;9],
+    /// Seconds since the chip was created, for tremolo and vibrato.
+    time: f64,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct OplChannel {
+    f_number: u16,
+    block: u8,
+    key_on: bool,
+    feedback: u8,
+    /// Both operators sound instead of the modulator driving the carrier.
+    additive: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum Stage {
+    #[default]
+    Off,
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Operator {
+    tremolo: bool,
+    vibrato: bool,
+    /// Holds the sustain level until key off.
+    sustaining: bool,
+    key_scale_rate: bool,
+    multiplier: f64,
+    key_scale_level: u8,
+    /// In dB.
+    total_level: f64,
+    attack_rate: u8,
+    decay_rate: u8,
+    /// In dB.
+    sustain_level: f64,
+    release_rate: u8,
+    waveform: u8,
+
+    stage: Stage,
+    /// Envelope attenuation, in dB.
+    attenuation: f64,
+    /// Position in the waveform, in cycles.
+    phase: f64,
+    /// The last two outputs, for feedback.
+    outputs: [f64;2],
+}
+
+impl Opl2 {
+    /// A chip in its power-on state, rendering `rate` samples per second.
+    pub fn new(rate: u32) -> Self {
+        let operator = Operator {
+            multiplier: MULTIPLIERS[0],
+            attenuation: SILENCE,
+            ..Default::default()
+        };
+        Self {
+            rate: rate as f64,
+            waveform_select: false,
+            deep_tremolo: false,
+            deep_vibrato: false,
+            operators: [operator;18],
+            channels: [OplChannel::default();9],
+            time: 0.0,
+        }
+    }
+
+    pub fn write(&mut self, register: u8, value: u8) {
+        match register {
+            0x01 => self.waveform_select = value & 0x20 != 0,
+            0xBD => {
+                self.deep_tremolo = value & 0x80 != 0;
+                self.deep_vibrato = value & 0x40 != 0;
+            },
+            0xA0..=0xA8 => {
+                let channel = &mut self.channels[(register - 0xA0) as usize];
+                channel.f_number = (channel.f_number & 0x300) | value as u16;
+            },
+            0xB0..=0xB8 => {
+                let index = (register - 0xB0) as usize;
+                let channel = &mut self.channels[index];
+                channel.f_number = (channel.f_number & 0xFF) | (((value & 3) as u16) << 8);
+                channel.block = (value >> 2) & 7;
+
+                let key_on = value & 0x20 != 0;
+                if key_on != channel.key_on {
+                    channel.key_on = key_on;
+                    let (modulator, carrier) = CHANNEL_OPERATORS[index];
+                    for operator in [modulator, carrier] {
+                        let operator = &mut self.operators[operator];
+                        if key_on {
+                            operator.stage = Stage::Attack;
+                            operator.phase = 0.0;
+                        } else if operator.stage != Stage::Off {
+                            operator.stage = Stage::Release;
+                        }
+                    }
+                }
+            },
+            0xC0..=0xC8 => {
+                let channel = &mut self.channels[(register - 0xC0) as usize];
+                channel.feedback = (value >> 1) & 7;
+                channel.additive = value & 1 != 0;
+            },
+            _ => {
+                let Some(operator) = operator_index(register & 0x1F).map(|index| &mut self.operators[index]) else {
+                    return;
+                };
+                match register & 0xE0 {
+                    0x20 => {
+                        operator.tremolo = value & 0x80 != 0;
+                        operator.vibrato = value & 0x40 != 0;
+                        operator.sustaining = value & 0x20 != 0;
+                        operator.key_scale_rate = value & 0x10 != 0;
+                        operator.multiplier = MULTIPLIERS[(value & 0xF) as usize];
+                    },
+                    0x40 => {
+                        operator.key_scale_level = value >> 6;
+                        operator.total_level = (value & 0x3F) as f64 * 0.75;
+                    },
+                    0x60 => {
+                        operator.attack_rate = value >> 4;
+                        operator.decay_rate = value & 0xF;
+                    },
+                    0x80 => {
+                        // The highest sustain level is 93dB down, not 45
+                        operator.sustain_level = match value >> 4 {
+                            0xF => 93.0,
+                            level => level as f64 * 3.0,
+                        };
+                        operator.release_rate = value & 0xF;
+                    },
+                    0xE0 => operator.waveform = value & 3,
+                    _ => {},
+                }
+            },
+        }
+    }
+
+    /// Renders the next sample of all 9 channels mixed. Each operator heard
+    /// peaks at 1.
+    pub fn sample(&mut self) -> f32 {
+        let step = 1.0 / self.rate;
+        self.time += step;
+
+        // Tremolo swings 1dB (4.8dB deep) at 3.7Hz, vibrato 7 cents (14 deep) at 6.1Hz
+        let tremolo = (if self.deep_tremolo { 4.8 } else { 1.0 }) * (0.5 - 0.5 * (2.0 * PI * 3.7 * self.time).cos());
+        let vibrato_cents = (if self.deep_vibrato { 14.0 } else { 7.0 }) * (2.0 * PI * 6.1 * self.time).sin();
+        let vibrato = 2f64.powf(vibrato_cents / 1200.0);
+
+        let mut output = 0.0;
+        for (channel, (modulator, carrier)) in self.channels.iter().zip(CHANNEL_OPERATORS) {
+            let frequency = channel.f_number as f64 * OPL_RATE / (1u32 << (20 - channel.block)) as f64;
+            let key_scale = (channel.block << 1) | ((channel.f_number >> 9) & 1) as u8;
+            let key_scale_level = {
+                let level = (KEY_SCALE_LEVELS[(channel.f_number >> 6) as usize] << 2) - ((8 - channel.block as i32) << 5);
+                level.max(0)
+            };
+
+            let feedback = match channel.feedback {
+                0 => 0.0,
+                feedback => {
+                    let outputs = self.operators[modulator].outputs;
+                    (outputs[0] + outputs[1]) * 2f64.powi(feedback as i32 - 7)
+                },
+            };
+            let modulator_output = self.operators[modulator].render(self.waveform_select, feedback, key_scale_level, tremolo);
+            let carrier_input = if channel.additive { 0.0 } else { modulator_output * 4.0 };
+            let carrier_output = self.operators[carrier].render(self.waveform_select, carrier_input, key_scale_level, tremolo);
+
+            output += if channel.additive { modulator_output + carrier_output } else { carrier_output };
+
+            for operator in [modulator, carrier] {
+                let operator = &mut self.operators[operator];
+                let multiple = if operator.vibrato { vibrato } else { 1.0 };
+                operator.phase = (operator.phase + frequency * operator.multiplier * multiple * step).fract();
+                operator.advance_envelope(step, key_scale);
+            }
+        }
+
+        output as f32
+    }
+}
+
+impl Operator {
+    /// Output at the current phase shifted by `modulation` cycles. The key
+    /// scale level is in the chip's 0.1875dB steps, before scaling.
+    fn render(&mut self, waveform_select: bool, modulation: f64, key_scale_level: i32, tremolo: f64) -> f64 {
+        if self.stage == Stage::Off {
+            self.outputs = [self.outputs[1], 0.0];
+            return 0.0;
+        }
+
+        // 3dB, 1.5dB and 6dB per octave, in the chip's odd bit order
+        let key_scale_attenuation = match self.key_scale_level {
+            0 => 0.0,
+            1 => (key_scale_level >> 1) as f64 * 0.1875,
+            2 => (key_scale_level >> 2) as f64 * 0.1875,
+            _ => key_scale_level as f64 * 0.1875,
+        };
+        let attenuation = self.attenuation + self.total_level + key_scale_attenuation + if self.tremolo { tremolo } else { 0.0 };
+        let amplitude = if attenuation >= SILENCE { 0.0 } else { 10f64.powf(-attenuation / 20.0) };
+
+        let phase = (self.phase + modulation).rem_euclid(1.0);
+        let waveform = if waveform_select { self.waveform } else { 0 };
+        let sine = (2.0 * PI * phase).sin();
+        let wave = match waveform {
+            0 => sine,
+            1 => sine.max(0.0),
+            2 => sine.abs(),
+            _ => if phase % 0.5 < 0.25 { sine.abs() } else { 0.0 },
+        };
+
+        let output = wave * amplitude;
+        self.outputs = [self.outputs[1], output];
+        output
+    }
+
+    /// Moves the envelope on by `step` seconds.
+    fn advance_envelope(&mut self, step: f64, key_scale: u8) {
+        let rate_offset = if self.key_scale_rate { key_scale } else { key_scale >> 2 };
+        let rise = |rate: u8| SILENCE * step / envelope_time(DECAY_TIME, rate, rate_offset);
+
+        match self.stage {
+            Stage::Off => return,
+            Stage::Attack => {
+                let time = envelope_time(ATTACK_TIME, self.attack_rate, rate_offset);
+                if self.attack_rate != 0 && 4 * self.attack_rate + rate_offset >= 60 {
+                    self.attenuation = 0.0;
+                } else {
+                    // Attenuation falls exponentially, getting within 0.1dB of full in the attack time
+                    self.attenuation *= (-step * (SILENCE / 0.1).ln() / time).exp();
+                }
+                if self.attenuation < 0.1 {
+                    self.attenuation = 0.0;
+                    self.stage = Stage::Decay;
+                }
+            },
+            Stage::Decay => {
+                self.attenuation += rise(self.decay_rate);
+                if self.attenuation >= self.sustain_level {
+                    self.attenuation = self.sustain_level;
+                    self.stage = Stage::Sustain;
+                }
+            },
+            // Without sustain, the release starts as soon as the decay is over
+            Stage::Sustain if !self.sustaining => self.attenuation += rise(self.release_rate),
+            Stage::Sustain => {},
+            Stage::Release => self.attenuation += rise(self.release_rate),
+        }
+
+        if self.attenuation >= SILENCE {
+            self.attenuation = SILENCE;
+            if self.stage != Stage::Attack {
+                self.stage = Stage::Off;
+            }
+        }
+    }
+}
+
+/// Seconds an envelope phase takes at `rate` (0-15), `base` being the time
+/// at rate 1. The rate offset from key scaling adds quarter steps.
+fn envelope_time(base: f64, rate: u8, rate_offset: u8) -> f64 {
+    if rate == 0 {
+        return f64::INFINITY;
+    }
+
+    let effective = (4 * rate + rate_offset).min(63);
+    let (rate, fraction) = (effective >> 2, effective & 3);
+    base / 2f64.powi(rate as i32 - 1) * 4.0 / (4.0 + fraction as f64)
+}
+
+/// Operator addressed by the low five bits of an operator register.
+fn operator_index(offset: u8) -> Option<usize> {
+    match offset {
+        0x00..=0x05 => Some(offset as usize),
+        0x08..=0x0D => Some(offset as usize - 2),
+        0x10..=0x15 => Some(offset as usize - 4),
+        _ => None,
+    }
+}
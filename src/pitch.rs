@@ -0,0 +1,51 @@
+use std::f64::consts::PI;
+
+/// Which S3M pitch effects are approximated by playing new notes. C67 can only
+/// change a voice's pitch with a PlayNote command, which restarts PCM samples
+/// and FM envelopes alike, so each effect is a trade-off left to the user.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PitchEffectOptions {
+    /// Jxy
+    pub arpeggio: bool,
+    /// Hxy
+    pub vibrato: bool,
+    /// Exx and Fxx
+    pub portamento: bool,
+    /// Gxx
+    pub tone_portamento: bool,
+}
+
+impl PitchEffectOptions {
+    pub fn all() -> Self {
+        Self {
+            arpeggio: true,
+            vibrato: true,
+            portamento: true,
+            tone_portamento: true,
+        }
+    }
+}
+
+/// Note names as trackers show them, from C.
+pub const NOTE_NAMES: [&str;12] = ["C-", "C#", "D-", "D#", "E-", "F-", "F#", "G-", "G#", "A-", "A#", "B-"];
+
+/// ST3 period of middle C at the default C4 rate.
+const MIDDLE_C_PERIOD: f64 = 1712.0;
+
+/// ST3 period of `semitone` (above C-0) for an instrument playing middle C at `c4speed` Hz.
+pub fn period(semitone: f64, c4speed: u32) -> f64 {
+    MIDDLE_C_PERIOD * 8363.0 / c4speed as f64 * 2f64.powf((48.0 - semitone) / 12.0)
+}
+
+/// Inverse of [`period`], in fractional semitones.
+pub fn semitone(period: f64, c4speed: u32) -> f64 {
+    48.0 + 12.0 * (MIDDLE_C_PERIOD * 8363.0 / (c4speed as f64 * period)).log2()
+}
+
+/// Period offset of Hxy at `position` (0-63 per cycle) with depth `depth`.
+/// Amiga trackers scale a 255 peak sine by depth / 128, ST3 periods are four
+/// times finer.
+pub fn vibrato_offset(position: u8, depth: u8) -> f64 {
+    let sine = (255.0 * (2.0 * PI * (position % 64) as f64 / 64.0).sin()).trunc();
+    sine * depth as f64 / 128.0 * 4.0
+}
@@ -0,0 +1,464 @@
+use crate::{adlib_drums::Drum, diagnostics::{Category, Diagnostic, Diagnostics, Severity}, flow::walk_orders, format_s3m::{S3MColumn, S3MInstrument, S3MModule, S3MRow, S3MSample, Tracker, TrackerVersion, EFFECT_ARPEGGIO, EFFECT_BREAK, EFFECT_JUMP, EFFECT_PORTAMENTO_DOWN, EFFECT_PORTAMENTO_UP, EFFECT_PORTAMENTO_VOLUME_SLIDE, EFFECT_RETRIGGER, EFFECT_SAMPLE_OFFSET, EFFECT_SET_SPEED, EFFECT_SET_TEMPO, EFFECT_SPECIAL, EFFECT_TONE_PORTAMENTO, EFFECT_TREMOLO, EFFECT_VIBRATO, EFFECT_VIBRATO_VOLUME_SLIDE, EFFECT_VOLUME_SLIDE}, pitch::{self, PitchEffectOptions}, song::{Event, FMPatch, Instrument, Order, Pattern, Sample, Song, TimedEvent}, timing::TempoMap};
+
+impl From<&S3MModule> for Song {
+    fn from(module: &S3MModule) -> Self {
+        play(module, PitchEffectOptions::default()).0
+    }
+}
+
+/// Plays an S3M through the way ST3 does, reading the notes, volumes, volume
+/// slides, note cuts and delays and the chosen pitch effects of every row.
+/// Notes ST3 would not sound, such as samples on AdLib channels, are left out.
+/// The diagnostics list the effects that were not played.
+pub fn play(module: &S3MModule, pitch_effects: PitchEffectOptions) -> (Song, Diagnostics) {
+    let instruments: Vec<Instrument> = module.instruments.iter().map(instrument).collect();
+    let mut diagnostics = Diagnostics::default();
+
+    let mut state = PlaybackState::new(module);
+    let mut tempo_map = TempoMap::new(state.tempo);
+    let mut orders: Vec<Order> = Vec::new();
+    let mut patterns: Vec<Pattern> = Vec::new();
+    let mut tick = 0u32;
+    let flow = walk_orders(module);
+    for segment in &flow.segments {
+        let start = tick;
+        let mut pattern = Pattern {
+            channels: vec![Vec::new();32],
+            ..Default::default()
+        };
+
+        for row_index in segment.rows.clone() {
+            let row = &module.patterns[segment.pattern][row_index];
+            if state.apply_global_effects(row) {
+                tempo_map.set_tempo(tick, state.tempo);
+            }
+            pattern.row_ticks.push(tick - start);
+
+            for (channel, col) in row.iter().enumerate() {
+                if module.channel_settings[channel] & 0x80 != 0 {
+                    continue;
+                }
+                if let Some((severity, message)) = ignored_effect(col, pitch_effects) {
+                    diagnostics.tally(
+                        Diagnostic::new(severity, Category::UnsupportedEffect, message)
+                            .at(segment.order, segment.pattern, row_index)
+                            .on_channel(channel)
+                    );
+                }
+            }
+
+            for (offset, channel, event) in state.convert_row(&instruments, row, pitch_effects) {
+                pattern.channels[channel].push(TimedEvent { tick: tick - start + offset, row: row_index, event });
+            }
+            tick += state.speed as u32;
+        }
+        pattern.length = tick - start;
+
+        // Orders playing out the same way share a pattern
+        let index = match patterns.iter().position(|existing| *existing == pattern) {
+            Some(index) => index,
+            None => {
+                patterns.push(pattern);
+                patterns.len() - 1
+            },
+        };
+        orders.push(Order { pattern: index, source_order: segment.order, source_pattern: segment.pattern });
+    }
+
+    let title_length = module.song_name.iter().position(|byte| *byte == 0).unwrap_or(module.song_name.len());
+    let song = Song {
+        title: String::from_utf8_lossy(&module.song_name[..title_length]).into_owned(),
+        instruments,
+        orders,
+        patterns,
+        loop_order: flow.loop_segment,
+        tempo_map,
+    };
+
+    (song, diagnostics)
+}
+
+/// Why the effect in `col` is not played, if it is not: either it is not
+/// supported at all, or it is a pitch effect left out by `pitch_effects`.
+fn ignored_effect(col: &S3MColumn, pitch_effects: PitchEffectOptions) -> Option<(Severity, String)> {
+    let unsupported = |name: &str| Some((Severity::Warning, format!("{} is not supported, ignoring it", name)));
+    let left_out = |name: &str| Some((Severity::Info, format!("{} is left out, pitch effects are off", name)));
+
+    match col.effect {
+        0 | EFFECT_SET_SPEED | EFFECT_JUMP | EFFECT_BREAK | EFFECT_VOLUME_SLIDE | EFFECT_SET_TEMPO => None,
+        EFFECT_PORTAMENTO_DOWN | EFFECT_PORTAMENTO_UP if !pitch_effects.portamento => left_out("Portamento (E/F)"),
+        EFFECT_TONE_PORTAMENTO | EFFECT_PORTAMENTO_VOLUME_SLIDE if !pitch_effects.tone_portamento => left_out("Tone portamento (G/L)"),
+        EFFECT_VIBRATO | EFFECT_VIBRATO_VOLUME_SLIDE if !pitch_effects.vibrato => left_out("Vibrato (H/K)"),
+        EFFECT_ARPEGGIO if !pitch_effects.arpeggio => left_out("Arpeggio (J)"),
+        EFFECT_PORTAMENTO_DOWN | EFFECT_PORTAMENTO_UP | EFFECT_TONE_PORTAMENTO | EFFECT_PORTAMENTO_VOLUME_SLIDE
+            | EFFECT_VIBRATO | EFFECT_VIBRATO_VOLUME_SLIDE | EFFECT_ARPEGGIO => None,
+        EFFECT_SAMPLE_OFFSET => unsupported("Sample offset (O)"),
+        EFFECT_RETRIGGER => unsupported("Retrigger (Q)"),
+        EFFECT_TREMOLO => unsupported("Tremolo (R)"),
+        EFFECT_SPECIAL => match col.effect_value >> 4 {
+            0xC | 0xD => None,
+            0x8 => unsupported("Panning (S8)"),
+            0xB => unsupported("Pattern loop (SB)"),
+            0xE => unsupported("Pattern delay (SE)"),
+            command => unsupported(&format!("Effect S{:X}", command)),
+        },
+        effect @ 1..=26 => unsupported(&format!("Effect {}", (b'@' + effect) as char)),
+        effect => unsupported(&format!("Effect {}", effect)),
+    }
+}
+
+pub(crate) fn instrument(instrument: &S3MInstrument) -> Instrument {
+    match instrument {
+        S3MInstrument::Sample(sample) => Instrument::Sample(Sample {
+            filename: sample.filename,
+            volume: sample.volume.min(64),
+            c4speed: if sample.c4speed == 0 { 8363 } else { sample.c4speed },
+            audio: sample.audio.clone(),
+            sample_loop: sample_loop(sample),
+        }),
+        S3MInstrument::Adlib(instrument) => Instrument::FM(FMPatch {
+            filename: instrument.filename,
+            volume: instrument.volume.min(64),
+            registers: [
+                instrument.d00, instrument.d01, instrument.d02, instrument.d03,
+                instrument.d04, instrument.d05, instrument.d06, instrument.d07,
+                instrument.d08, instrument.d09, instrument.d0a,
+            ],
+            drum: Drum::from_instrument_type(instrument.instrument_type),
+        }),
+    }
+}
+
+/// Loop start and end of a sample, if it loops.
+fn sample_loop(sample: &S3MSample) -> Option<(usize, usize)> {
+    let (start, end) = (sample.loop_begin as usize, sample.loop_end as usize);
+    if sample.flags & 1 != 0 && start < end && end <= sample.audio.len() {
+        Some((start, end))
+    } else {
+        None
+    }
+}
+
+/// Speed, tempo and per-channel memory while playing the song through.
+struct PlaybackState {
+    speed: u8,
+    tempo: u8,
+    /// ST3.00 slides volume on the first tick of a row too.
+    fast_volume_slides: bool,
+    channel_settings: [u8;32],
+    channels: [ChannelState;32],
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct ChannelState {
+    instrument: u8,
+    volume: u8,
+    /// The instrument and semitone last played, while a note sounds.
+    sounding: Option<(u8, i32)>,
+    /// Whether the note sounding can be heard on this channel.
+    audible: bool,
+    /// ST3 period the note has been slid to.
+    period: f64,
+    portamento_target: f64,
+    vibrato_position: u8,
+
+    // Effect memory for parameters of 0
+    volume_slide: u8,
+    portamento: u8,
+    tone_portamento: u8,
+    vibrato: u8,
+    arpeggio: u8,
+}
+
+impl PlaybackState {
+    fn new(module: &S3MModule) -> Self {
+        let (speed, tempo) = initial_speed_and_tempo(module);
+        Self {
+            speed,
+            tempo,
+            fast_volume_slides: fast_volume_slides(module),
+            channel_settings: module.channel_settings,
+            channels: [ChannelState::default();32],
+        }
+    }
+
+    /// Reads the events of a row. Each event comes with the tick within the
+    /// row it happens on and its channel.
+    fn convert_row(&mut self, instruments: &[Instrument], row: &S3MRow, pitch_effects: PitchEffectOptions) -> Vec<(u32, usize, Event)> {
+        let mut events: Vec<(u32, usize, Event)> = Vec::new();
+        let speed = self.speed as u32;
+
+        for (channel_index, col) in row.iter().enumerate() {
+            let channel_setting = self.channel_settings[channel_index];
+            if channel_setting & 0x80 != 0 {
+                // Muted or unused channel
+                continue;
+            }
+
+            let (delay, cut) = match (col.effect, col.effect_value >> 4) {
+                (EFFECT_SPECIAL, 0xC) if col.effect_value & 0xF != 0 => (0, Some((col.effect_value & 0xF) as u32)),
+                (EFFECT_SPECIAL, 0xD) => ((col.effect_value & 0xF) as u32, None),
+                _ => (0, None),
+            };
+            if delay >= speed {
+                // Notes delayed past the end of the row never play
+                continue;
+            }
+
+            let channel = &mut self.channels[channel_index];
+            if col.instrument != 0 {channel.instrument = col.instrument;}
+            let saved_instrument = channel.instrument;
+            let instrument = instruments.get((saved_instrument as usize).wrapping_sub(1));
+            let default_volume = instrument.map(|instrument| match instrument {
+                Instrument::Sample(sample) => sample.volume,
+                Instrument::FM(patch) => patch.volume,
+            });
+            let c4speed = match instrument {
+                Some(Instrument::Sample(sample)) => sample.c4speed,
+                _ => 8363,
+            };
+
+            // Tone portamento slides towards a note instead of playing it
+            let mut note = col.note;
+            let glides = matches!(col.effect, EFFECT_TONE_PORTAMENTO | EFFECT_PORTAMENTO_VOLUME_SLIDE)
+                && pitch_effects.tone_portamento
+                && channel.sounding.is_some();
+            if note < 254 && glides {
+                channel.portamento_target = pitch::period(semitone(note) as f64, c4speed);
+                note = 255;
+            }
+
+            let event = if note < 254 {
+                let (Some(default_volume), Some(instrument)) = (default_volume, instrument) else {
+                    continue;
+                };
+                let volume = if col.vol <= 64 { col.vol } else { default_volume };
+                channel.volume = volume;
+                channel.period = pitch::period(semitone(note) as f64, c4speed);
+                channel.sounding = Some((saved_instrument, semitone(note)));
+                channel.vibrato_position = 0;
+                channel.audible = audible(instrument, channel_setting);
+
+                channel.audible.then_some(Event::Note {
+                    instrument: saved_instrument as usize - 1,
+                    semitone: semitone(note),
+                    volume,
+                })
+            } else if note == 254 {
+                channel.sounding = None;
+                Some(Event::Off)
+            } else if let Some(volume) = (col.vol <= 64).then_some(col.vol)
+                // An instrument without a note resets the volume
+                .or(default_volume.filter(|_| col.instrument != 0)) {
+                channel.volume = volume;
+                Some(Event::Volume(volume))
+            } else {
+                None
+            };
+            if let Some(event) = event {
+                events.push((delay, channel_index, event));
+            }
+
+            if matches!(col.effect, EFFECT_VOLUME_SLIDE | EFFECT_VIBRATO_VOLUME_SLIDE | EFFECT_PORTAMENTO_VOLUME_SLIDE) {
+                for (tick, volume) in self.volume_slide(channel_index, col.effect_value) {
+                    events.push((tick, channel_index, Event::Volume(volume)));
+                }
+            }
+
+            let pitch_changes = self.pitch_slide(channel_index, col, c4speed, pitch_effects);
+            if self.channels[channel_index].audible {
+                for (tick, semitone) in pitch_changes {
+                    events.push((tick, channel_index, Event::Pitch(semitone)));
+                }
+            }
+
+            if let Some(cut) = cut.filter(|cut| *cut < speed) {
+                // Nothing after the cut is heard
+                events.retain(|(tick, channel, _)| *channel != channel_index || *tick < cut);
+                events.push((cut, channel_index, Event::Off));
+
+                let channel = &mut self.channels[channel_index];
+                channel.volume = 0;
+                channel.sounding = None;
+            }
+        }
+
+        events.sort_by_key(|(tick, _, _)| *tick);
+        events
+    }
+
+    /// Runs a Dxy volume slide through one row, returning the ticks the
+    /// channel's volume changes on along with the new volume.
+    fn volume_slide(&mut self, channel: usize, parameter: u8) -> Vec<(u32, u8)> {
+        let state = &mut self.channels[channel];
+        // D00 continues the last slide
+        if parameter != 0 {
+            state.volume_slide = parameter;
+        }
+        let parameter = state.volume_slide;
+
+        let mut changes: Vec<(u32, u8)> = Vec::new();
+        let mut volume = state.volume as i32;
+        for tick in 0..self.speed as u32 {
+            let slid = (volume + volume_slide_step(parameter, tick, self.fast_volume_slides)).clamp(0, 64);
+            if slid != volume {
+                volume = slid;
+                changes.push((tick, volume as u8));
+            }
+        }
+        state.volume = volume as u8;
+
+        changes
+    }
+
+    /// Runs the enabled pitch effects of a column through one row, returning
+    /// the ticks the sounding semitone changes on along with the new semitone.
+    /// Pitch an effect bent away returns on the next row without it.
+    fn pitch_slide(&mut self, channel: usize, col: &S3MColumn, c4speed: u32, options: PitchEffectOptions) -> Vec<(u32, i32)> {
+        let speed = self.speed as u32;
+        let state = &mut self.channels[channel];
+        let Some((instrument, mut sounding)) = state.sounding else {
+            return Vec::new();
+        };
+
+        let parameter = col.effect_value;
+        match col.effect {
+            EFFECT_PORTAMENTO_DOWN | EFFECT_PORTAMENTO_UP if parameter != 0 => state.portamento = parameter,
+            EFFECT_TONE_PORTAMENTO if parameter != 0 => state.tone_portamento = parameter,
+            EFFECT_VIBRATO => {
+                // Each nibble is remembered on its own
+                if parameter & 0xF0 != 0 {
+                    state.vibrato = (state.vibrato & 0x0F) | (parameter & 0xF0);
+                }
+                if parameter & 0x0F != 0 {
+                    state.vibrato = (state.vibrato & 0xF0) | (parameter & 0x0F);
+                }
+            },
+            EFFECT_ARPEGGIO if parameter != 0 => state.arpeggio = parameter,
+            _ => {},
+        }
+
+        let (highest, lowest) = (pitch::period(95.0, c4speed), pitch::period(0.0, c4speed));
+        let mut changes: Vec<(u32, i32)> = Vec::new();
+        for tick in 0..speed {
+            let mut period_offset = 0.0;
+            let mut semitone_offset = 0;
+
+            match col.effect {
+                EFFECT_PORTAMENTO_DOWN | EFFECT_PORTAMENTO_UP if options.portamento => {
+                    let step = portamento_step(state.portamento, tick);
+                    state.period += if col.effect == EFFECT_PORTAMENTO_DOWN { step } else { -step };
+                },
+                EFFECT_TONE_PORTAMENTO | EFFECT_PORTAMENTO_VOLUME_SLIDE if options.tone_portamento && tick > 0 => {
+                    let step = 4.0 * state.tone_portamento as f64;
+                    state.period = if state.period < state.portamento_target {
+                        (state.period + step).min(state.portamento_target)
+                    } else {
+                        (state.period - step).max(state.portamento_target)
+                    };
+                },
+                EFFECT_VIBRATO | EFFECT_VIBRATO_VOLUME_SLIDE if options.vibrato && tick > 0 => {
+                    period_offset = pitch::vibrato_offset(state.vibrato_position, state.vibrato & 0xF);
+                    state.vibrato_position = (state.vibrato_position + (state.vibrato >> 4)) % 64;
+                },
+                EFFECT_ARPEGGIO if options.arpeggio => {
+                    semitone_offset = match tick % 3 {
+                        0 => 0,
+                        1 => (state.arpeggio >> 4) as i32,
+                        _ => (state.arpeggio & 0xF) as i32,
+                    };
+                },
+                _ => {},
+            }
+            state.period = state.period.clamp(highest, lowest);
+
+            let semitone = pitch::semitone(state.period + period_offset, c4speed).round() as i32;
+            let semitone = (semitone + semitone_offset).clamp(0, 95);
+            if semitone != sounding {
+                sounding = semitone;
+                changes.push((tick, semitone));
+            }
+        }
+        state.sounding = Some((instrument, sounding));
+
+        changes
+    }
+
+    /// Applies Axx and Txx from a row, returning whether the tempo changed.
+    fn apply_global_effects(&mut self, row: &S3MRow) -> bool {
+        let previous_tempo = self.tempo;
+
+        for col in row {
+            match col.effect {
+                EFFECT_SET_SPEED if col.effect_value != 0 => self.speed = col.effect_value,
+                EFFECT_SET_TEMPO if col.effect_value > 32 => self.tempo = col.effect_value,
+                _ => {},
+            }
+        }
+
+        self.tempo != previous_tempo
+    }
+}
+
+/// Speed and tempo a song starts at. ST3 falls back to speed 6 and tempo
+/// 125 for invalid header values.
+pub(crate) fn initial_speed_and_tempo(module: &S3MModule) -> (u8, u8) {
+    let speed = match module.initial_speed {
+        0 | 255 => 6,
+        speed => speed,
+    };
+    let tempo = match module.initial_tempo {
+        0..=32 => 125,
+        tempo => tempo,
+    };
+    (speed, tempo)
+}
+
+/// Whether volume slides also happen on the first tick of a row, as in ST3.00.
+pub(crate) fn fast_volume_slides(module: &S3MModule) -> bool {
+    module.flags & 64 != 0 || module.tracker() == Tracker::ScreamTracker(TrackerVersion { major: 3, minor: 0 })
+}
+
+/// Volume change Dxy makes on `tick` of a row.
+pub(crate) fn volume_slide_step(parameter: u8, tick: u32, fast_volume_slides: bool) -> i32 {
+    let (up, down) = ((parameter >> 4) as i32, (parameter & 0xF) as i32);
+    if down == 0xF && up != 0 {
+        // DxF, fine slide up on the first tick only
+        if tick == 0 { up } else { 0 }
+    } else if up == 0xF && down != 0 {
+        // DFy, fine slide down on the first tick only
+        if tick == 0 { -down } else { 0 }
+    } else if tick == 0 && !fast_volume_slides {
+        0
+    } else if down != 0 {
+        // Sliding down wins when both nibbles are set
+        -down
+    } else {
+        up
+    }
+}
+
+/// Period change Exx or Fxx makes on `tick` of a row, before its direction.
+pub(crate) fn portamento_step(parameter: u8, tick: u32) -> f64 {
+    match parameter {
+        // EFx and EEx, fine and extra fine slides on the first tick only
+        0xF0.. => if tick == 0 { 4.0 * (parameter & 0xF) as f64 } else { 0.0 },
+        0xE0.. => if tick == 0 { (parameter & 0xF) as f64 } else { 0.0 },
+        portamento => if tick > 0 { 4.0 * portamento as f64 } else { 0.0 },
+    }
+}
+
+/// Whether ST3 sounds `instrument` on a channel with `channel_setting`.
+/// Samples are silent on AdLib channels and AdLib instruments on sample
+/// channels, melodic ones on drum channels too.
+pub(crate) fn audible(instrument: &Instrument, channel_setting: u8) -> bool {
+    match instrument {
+        Instrument::Sample(_) => channel_setting <= 15,
+        Instrument::FM(patch) => channel_setting >= 16 && (channel_setting <= 24 || patch.drum.is_some()),
+    }
+}
+
+/// Semitones above C-0 of an S3M note.
+pub(crate) fn semitone(note: u8) -> i32 {
+    (note >> 4) as i32 * 12 + (note & 0xF) as i32
+}
@@ -0,0 +1,612 @@
+use std::io;
+
+use byteorder::{LittleEndian, WriteBytesExt};
+
+use crate::{adlib_drums::melodic_patch, flow::walk_orders, format_c67::{s3m_volume, C67Module, C67PatternCommand, Channel, PatternDecodeError, NO_LOOP, PCM_MIDDLE_C_OCTAVE, PCM_MIDDLE_C_RATE, TICKS_PER_SECOND}, format_s3m::{S3MColumn, S3MModule, S3MRow, EFFECT_ARPEGGIO, EFFECT_PORTAMENTO_DOWN, EFFECT_PORTAMENTO_UP, EFFECT_PORTAMENTO_VOLUME_SLIDE, EFFECT_SAMPLE_OFFSET, EFFECT_SET_SPEED, EFFECT_SET_TEMPO, EFFECT_SPECIAL, EFFECT_TONE_PORTAMENTO, EFFECT_VIBRATO, EFFECT_VIBRATO_VOLUME_SLIDE, EFFECT_VOLUME_SLIDE}, opl::{block_and_f_number, Opl2, F_NUMBERS}, pitch, playback::{self, audible, portamento_step, volume_slide_step}, song::Instrument};
+
+/// Share of full scale a sample voice playing at full volume takes up, so
+/// that 4 of them fit.
+const SAMPLE_GAIN: f32 = 0.25;
+/// Share of full scale an FM operator peaks at, as on the OPL2's output.
+const FM_GAIN: f32 = 0.125;
+
+/// ST3 period of a sample played at 1 Hz, 8363 Hz being period 1712.
+const PERIOD_RATE: f64 = 1712.0 * 8363.0;
+
+/// Register offsets of the modulators of the 9 OPL channels. Carriers are 3 above.
+const MODULATOR_OFFSETS: [u8;9] = [0x00, 0x01, 0x02, 0x08, 0x09, 0x0A, 0x10, 0x11, 0x12];
+
+/// Stereo audio rendered from a song.
+#[derive(Debug, Clone, Default)]
+pub struct Rendering {
+    pub rate: u32,
+    /// Left and right samples, full scale at 1.
+    pub frames: Vec<[f32;2]>,
+}
+
+impl Rendering {
+    pub fn seconds(&self) -> f64 {
+        self.frames.len() as f64 / self.rate as f64
+    }
+
+    /// Writes the audio as a 16-bit stereo WAV file, clipping anything louder
+    /// than full scale.
+    pub fn write_wav(&self, mut writer: impl io::Write) -> io::Result<()> {
+        let data_length = self.frames.len() as u32 * 4;
+
+        writer.write_all(b"RIFF")?;
+        writer.write_u32::<LittleEndian>(36 + data_length)?;
+        writer.write_all(b"WAVE")?;
+
+        writer.write_all(b"fmt ")?;
+        writer.write_u32::<LittleEndian>(16)?;
+        writer.write_u16::<LittleEndian>(1)?; // Integer PCM
+        writer.write_u16::<LittleEndian>(2)?;
+        writer.write_u32::<LittleEndian>(self.rate)?;
+        writer.write_u32::<LittleEndian>(self.rate * 4)?;
+        writer.write_u16::<LittleEndian>(4)?;
+        writer.write_u16::<LittleEndian>(16)?;
+
+        writer.write_all(b"data")?;
+        writer.write_u32::<LittleEndian>(data_length)?;
+        for sample in self.frames.iter().flatten() {
+            writer.write_i16::<LittleEndian>((sample.clamp(-1.0, 1.0) * 32767.0).round() as i16)?;
+        }
+
+        writer.flush()
+    }
+}
+
+/// Plays a C67 song through once the way CDFM does. Its 4 PCM voices play
+/// the samples at the rates their notes ask for, looping those with loop
+/// points, and its 9 FM voices drive an OPL2. CDFM plays in mono, so both
+/// sides of the rendering are the same.
+pub fn render_c67(module: &C67Module, rate: u32) -> Result<Rendering, PatternDecodeError> {
+    let header = &module.header;
+    let samples: Vec<Vec<f32>> = (0..32)
+        .map(|index| module.sample(index).iter().map(|value| (*value as f32 - 128.0) / 128.0).collect())
+        .collect();
+    let mut mixer = Mixer::new(rate, samples, vec![[1.0, 1.0];4], 1);
+    // Patches sounding on the FM voices, in S3M register order
+    let mut patches: [[u8;11];9] = [[0;11];9];
+
+    let row_length = header.speed as f64 / TICKS_PER_SECOND;
+    let mut rows = 0u32;
+    for pattern in header.playlist.iter().take_while(|pattern| **pattern != 0xFF) {
+        for command in module.pattern_commands(*pattern as usize)? {
+            match command {
+                C67PatternCommand::PlayNote(play_note) => {
+                    let volume = s3m_volume(play_note.channel, play_note.volume);
+                    match play_note.channel {
+                        Channel::PCM(voice) => {
+                            let meta = &header.instrument_meta[play_note.instrument as usize];
+                            let semitone = (play_note.octave as i32 - PCM_MIDDLE_C_OCTAVE as i32) * 12 + play_note.note as i32;
+                            let sample_loop = (meta.loop_end != NO_LOOP && meta.loop_start < meta.loop_end)
+                                .then_some((meta.loop_start as usize, meta.loop_end as usize));
+                            let frequency = PCM_MIDDLE_C_RATE as f64 * 2f64.powf(semitone as f64 / 12.0);
+                            mixer.play_sample(voice as usize, play_note.instrument as usize, frequency, sample_loop, 0, volume);
+                        },
+                        Channel::FM(voice) => {
+                            let Some(f_number) = F_NUMBERS.get(play_note.note as usize) else {
+                                continue;
+                            };
+                            let voice = voice as usize;
+                            patches[voice] = header.adlib_instrument_meta[play_note.instrument as usize].s3m_registers();
+                            load_patch(&mut mixer.opls[0], voice, &patches[voice], volume);
+                            key_on(&mut mixer.opls[0], voice, play_note.octave, *f_number);
+                        },
+                    }
+                },
+                C67PatternCommand::SetVolume(set_volume) => {
+                    let volume = s3m_volume(set_volume.channel, set_volume.volume);
+                    match set_volume.channel {
+                        Channel::PCM(voice) => mixer.set_volume(voice as usize, volume),
+                        Channel::FM(voice) => set_fm_volume(&mut mixer.opls[0], voice as usize, &patches[voice as usize], volume),
+                    }
+                },
+                C67PatternCommand::Delay(count) => {
+                    rows += count as u32;
+                    mixer.render_until(rows as f64 * row_length);
+                },
+                C67PatternCommand::End => break,
+            }
+        }
+    }
+
+    Ok(mixer.rendering)
+}
+
+/// Plays an S3M through once the way ST3 does, following the same orders on
+/// the same clock as the conversion, so that the rendering lines up with
+/// that of the converted C67. Samples play at their C4 speed, panned to
+/// their channel's side when the song is in stereo, and AdLib instruments
+/// drive an OPL2. Drum channels get a second chip, playing the melodic
+/// patches the conversion turns drums into.
+///
+/// Volume slides, portamento, tone portamento, vibrato, arpeggio, sample
+/// offset, note cuts and delays and S8x panning are played. Other effects
+/// are not.
+pub fn render_s3m(module: &S3MModule, rate: u32) -> Rendering {
+    let instruments: Vec<Instrument> = module.instruments.iter().map(playback::instrument).collect();
+    let samples: Vec<Vec<f32>> = instruments.iter()
+        .map(|instrument| match instrument {
+            Instrument::Sample(sample) => sample.audio.iter().map(|value| *value as f32 / 32768.0).collect(),
+            Instrument::FM(_) => Vec::new(),
+        })
+        .collect();
+    let stereo = module.mixing_volume & 0x80 != 0;
+    let panning = (0..32)
+        .map(|channel| if stereo { pan_gains(module.channel_pan(channel)) } else { [1.0, 1.0] })
+        .collect();
+    let mut player = S3MPlayer::new(module, &instruments, Mixer::new(rate, samples, panning, 2));
+
+    let mut seconds = 0.0;
+    for segment in walk_orders(module).segments {
+        for row in &module.patterns[segment.pattern][segment.rows] {
+            player.apply_global_effects(row);
+            for tick in 0..player.speed as u32 {
+                player.play_tick(row, tick);
+                seconds += 2.5 / player.tempo as f64;
+                player.mixer.render_until(seconds);
+            }
+        }
+    }
+
+    player.mixer.rendering
+}
+
+/// Left and right gain of S3M panning `pan` (0-15). Both are full in the
+/// middle, as when playing in mono.
+fn pan_gains(pan: u8) -> [f32;2] {
+    let right = pan.min(15) as f32 / 15.0;
+    [(2.0 * (1.0 - right)).min(1.0), (2.0 * right).min(1.0)]
+}
+
+/// Chip and channel of the OPL voice behind an S3M AdLib channel setting.
+/// Melodic channels use the first chip, drum channels the second.
+fn fm_voice(channel_setting: u8) -> Option<(usize, usize)> {
+    match channel_setting {
+        16..=24 => Some((0, channel_setting as usize - 16)),
+        25..=29 => Some((1, channel_setting as usize - 25)),
+        _ => None,
+    }
+}
+
+/// An S3M being rendered, tick by tick.
+struct S3MPlayer<'a> {
+    instruments: &'a [Instrument],
+    channel_settings: [u8;32],
+    stereo: bool,
+    /// 0-64
+    global_volume: u8,
+    speed: u8,
+    tempo: u8,
+    /// ST3.00 slides volume on the first tick of a row too.
+    fast_volume_slides: bool,
+    channels: [S3MChannel;32],
+    mixer: Mixer,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct S3MChannel {
+    instrument: u8,
+    volume: u8,
+    /// Whether a note is sounding that can be heard on this channel.
+    sounding: bool,
+    c4speed: u32,
+    /// ST3 period the note has been slid to.
+    period: f64,
+    portamento_target: f64,
+    vibrato_position: u8,
+    /// The AdLib patch playing, in S3M register order.
+    patch: [u8;11],
+    /// Block and F-number last written for the AdLib note.
+    f_number: (u8, u16),
+
+    // Effect memory for parameters of 0
+    volume_slide: u8,
+    portamento: u8,
+    tone_portamento: u8,
+    vibrato: u8,
+    arpeggio: u8,
+    sample_offset: u8,
+}
+
+impl<'a> S3MPlayer<'a> {
+    fn new(module: &S3MModule, instruments: &'a [Instrument], mixer: Mixer) -> Self {
+        let (speed, tempo) = playback::initial_speed_and_tempo(module);
+        Self {
+            instruments,
+            channel_settings: module.channel_settings,
+            stereo: module.mixing_volume & 0x80 != 0,
+            global_volume: module.global_volume.min(64),
+            speed,
+            tempo,
+            fast_volume_slides: playback::fast_volume_slides(module),
+            channels: [S3MChannel::default();32],
+            mixer,
+        }
+    }
+
+    /// Applies Axx and Txx from a row.
+    fn apply_global_effects(&mut self, row: &S3MRow) {
+        for col in row {
+            match col.effect {
+                EFFECT_SET_SPEED if col.effect_value != 0 => self.speed = col.effect_value,
+                EFFECT_SET_TEMPO if col.effect_value > 32 => self.tempo = col.effect_value,
+                _ => {},
+            }
+        }
+    }
+
+    /// Plays one tick of a row on every channel.
+    fn play_tick(&mut self, row: &S3MRow, tick: u32) {
+        for (channel, col) in row.iter().enumerate() {
+            if self.channel_settings[channel] & 0x80 != 0 {
+                // Muted or unused channel
+                continue;
+            }
+
+            if tick == 0 {
+                self.remember_parameters(channel, col);
+            }
+            let (delay, cut) = match (col.effect, col.effect_value >> 4) {
+                (EFFECT_SPECIAL, 0xC) if col.effect_value & 0xF != 0 => (0, Some((col.effect_value & 0xF) as u32)),
+                (EFFECT_SPECIAL, 0xD) => ((col.effect_value & 0xF) as u32, None),
+                _ => (0, None),
+            };
+            if tick == delay {
+                self.play_column(channel, col);
+            }
+            if tick == 0 && col.effect == EFFECT_SPECIAL && col.effect_value >> 4 == 0x8 && self.stereo {
+                self.mixer.panning[channel] = pan_gains(col.effect_value & 0xF);
+            }
+
+            let (period_offset, semitone_offset) = self.apply_effects(channel, col, tick);
+            if cut == Some(tick) {
+                self.channels[channel].volume = 0;
+                self.note_off(channel);
+            }
+            self.update_voice(channel, period_offset, semitone_offset);
+        }
+    }
+
+    fn remember_parameters(&mut self, channel: usize, col: &S3MColumn) {
+        let state = &mut self.channels[channel];
+        let parameter = col.effect_value;
+        match col.effect {
+            EFFECT_VOLUME_SLIDE | EFFECT_VIBRATO_VOLUME_SLIDE | EFFECT_PORTAMENTO_VOLUME_SLIDE if parameter != 0 => state.volume_slide = parameter,
+            EFFECT_PORTAMENTO_DOWN | EFFECT_PORTAMENTO_UP if parameter != 0 => state.portamento = parameter,
+            EFFECT_TONE_PORTAMENTO if parameter != 0 => state.tone_portamento = parameter,
+            EFFECT_VIBRATO => {
+                // Each nibble is remembered on its own
+                if parameter & 0xF0 != 0 {
+                    state.vibrato = (state.vibrato & 0x0F) | (parameter & 0xF0);
+                }
+                if parameter & 0x0F != 0 {
+                    state.vibrato = (state.vibrato & 0xF0) | (parameter & 0x0F);
+                }
+            },
+            EFFECT_ARPEGGIO if parameter != 0 => state.arpeggio = parameter,
+            EFFECT_SAMPLE_OFFSET if parameter != 0 => state.sample_offset = parameter,
+            _ => {},
+        }
+    }
+
+    /// Plays the note, instrument and volume of a column.
+    fn play_column(&mut self, channel: usize, col: &S3MColumn) {
+        let channel_setting = self.channel_settings[channel];
+        let state = &mut self.channels[channel];
+        if col.instrument != 0 {
+            state.instrument = col.instrument;
+        }
+        let instrument_index = (state.instrument as usize).wrapping_sub(1);
+        let instrument = self.instruments.get(instrument_index);
+        let default_volume = instrument.map(|instrument| match instrument {
+            Instrument::Sample(sample) => sample.volume,
+            Instrument::FM(patch) => patch.volume,
+        });
+
+        // Tone portamento slides towards a note instead of playing it
+        let glides = matches!(col.effect, EFFECT_TONE_PORTAMENTO | EFFECT_PORTAMENTO_VOLUME_SLIDE) && state.sounding;
+        if col.note < 254 && glides {
+            state.portamento_target = pitch::period(playback::semitone(col.note) as f64, state.c4speed);
+        } else if col.note < 254 {
+            let (Some(default_volume), Some(instrument)) = (default_volume, instrument) else {
+                return;
+            };
+            state.volume = if col.vol <= 64 { col.vol } else { default_volume };
+            state.c4speed = match instrument {
+                Instrument::Sample(sample) => sample.c4speed,
+                Instrument::FM(_) => 8363,
+            };
+            state.period = pitch::period(playback::semitone(col.note) as f64, state.c4speed);
+            state.vibrato_position = 0;
+            state.sounding = audible(instrument, channel_setting);
+            if !state.sounding {
+                return;
+            }
+
+            match (instrument, fm_voice(channel_setting)) {
+                (Instrument::Sample(sample), None) => {
+                    let start = state.sample_offset as usize * 256;
+                    let start = if col.effect == EFFECT_SAMPLE_OFFSET { start } else { 0 };
+                    let frequency = PERIOD_RATE / state.period;
+                    self.mixer.play_sample(channel, instrument_index, frequency, sample.sample_loop, start, state.volume);
+                },
+                (Instrument::FM(patch), Some((chip, voice))) => {
+                    state.patch = match patch.drum {
+                        Some(drum) => melodic_patch(patch, drum).registers,
+                        None => patch.registers,
+                    };
+                    state.f_number = block_and_f_number(pitch::semitone(state.period, state.c4speed));
+                    load_patch(&mut self.mixer.opls[chip], voice, &state.patch, state.volume);
+                    key_on(&mut self.mixer.opls[chip], voice, state.f_number.0, state.f_number.1);
+                },
+                _ => state.sounding = false,
+            }
+            return;
+        } else if col.note == 254 {
+            self.note_off(channel);
+        }
+
+        let state = &mut self.channels[channel];
+        if col.vol <= 64 {
+            state.volume = col.vol;
+        } else if let Some(volume) = default_volume.filter(|_| col.instrument != 0) {
+            // An instrument without a note resets the volume
+            state.volume = volume;
+        }
+    }
+
+    /// Runs the volume slides and pitch effects of a column for one tick,
+    /// returning the period and semitones the pitch is bent by this tick only.
+    fn apply_effects(&mut self, channel: usize, col: &S3MColumn, tick: u32) -> (f64, i32) {
+        let state = &mut self.channels[channel];
+        if matches!(col.effect, EFFECT_VOLUME_SLIDE | EFFECT_VIBRATO_VOLUME_SLIDE | EFFECT_PORTAMENTO_VOLUME_SLIDE) {
+            let step = volume_slide_step(state.volume_slide, tick, self.fast_volume_slides);
+            state.volume = (state.volume as i32 + step).clamp(0, 64) as u8;
+        }
+        if !state.sounding {
+            return (0.0, 0);
+        }
+
+        let mut period_offset = 0.0;
+        let mut semitone_offset = 0;
+        match col.effect {
+            EFFECT_PORTAMENTO_DOWN | EFFECT_PORTAMENTO_UP => {
+                let step = portamento_step(state.portamento, tick);
+                state.period += if col.effect == EFFECT_PORTAMENTO_DOWN { step } else { -step };
+            },
+            EFFECT_TONE_PORTAMENTO | EFFECT_PORTAMENTO_VOLUME_SLIDE if tick > 0 => {
+                let step = 4.0 * state.tone_portamento as f64;
+                state.period = if state.period < state.portamento_target {
+                    (state.period + step).min(state.portamento_target)
+                } else {
+                    (state.period - step).max(state.portamento_target)
+                };
+            },
+            EFFECT_VIBRATO | EFFECT_VIBRATO_VOLUME_SLIDE if tick > 0 => {
+                period_offset = pitch::vibrato_offset(state.vibrato_position, state.vibrato & 0xF);
+                state.vibrato_position = (state.vibrato_position + (state.vibrato >> 4)) % 64;
+            },
+            EFFECT_ARPEGGIO => {
+                semitone_offset = match tick % 3 {
+                    0 => 0,
+                    1 => (state.arpeggio >> 4) as i32,
+                    _ => (state.arpeggio & 0xF) as i32,
+                };
+            },
+            _ => {},
+        }
+        let (highest, lowest) = (pitch::period(95.0, state.c4speed), pitch::period(0.0, state.c4speed));
+        state.period = state.period.clamp(highest, lowest);
+
+        (period_offset, semitone_offset)
+    }
+
+    /// Stops the note sounding on a channel, letting AdLib notes release.
+    fn note_off(&mut self, channel: usize) {
+        let state = &mut self.channels[channel];
+        state.sounding = false;
+        match fm_voice(self.channel_settings[channel]) {
+            Some((chip, voice)) => key_off(&mut self.mixer.opls[chip], voice, state.f_number.0, state.f_number.1),
+            None => self.mixer.stop(channel),
+        }
+    }
+
+    /// Brings the voice of a channel up to date with its pitch and volume.
+    fn update_voice(&mut self, channel: usize, period_offset: f64, semitone_offset: i32) {
+        let state = &mut self.channels[channel];
+        if !state.sounding {
+            return;
+        }
+
+        let volume = (state.volume as u32 * self.global_volume as u32 / 64) as u8;
+        let period = state.period + period_offset;
+        match fm_voice(self.channel_settings[channel]) {
+            Some((chip, voice)) => {
+                state.f_number = block_and_f_number(pitch::semitone(period, state.c4speed) + semitone_offset as f64);
+                let opl = &mut self.mixer.opls[chip];
+                set_f_number(opl, voice, state.f_number.0, state.f_number.1);
+                set_fm_volume(opl, voice, &state.patch, volume);
+            },
+            None => {
+                let frequency = PERIOD_RATE / period * 2f64.powf(semitone_offset as f64 / 12.0);
+                self.mixer.set_frequency(channel, frequency);
+                self.mixer.set_volume(channel, volume);
+            },
+        }
+    }
+}
+
+/// Sample voices and OPL2 chips mixed into a rendering as time passes.
+struct Mixer {
+    /// Audio of every sample, full scale at 1.
+    samples: Vec<Vec<f32>>,
+    voices: Vec<Option<SampleVoice>>,
+    /// Left and right gain of each sample voice.
+    panning: Vec<[f32;2]>,
+    opls: Vec<Opl2>,
+    rendering: Rendering,
+}
+
+/// A sample playing on a voice.
+#[derive(Debug, Clone, Copy)]
+struct SampleVoice {
+    sample: usize,
+    position: f64,
+    /// Sample frames per output frame.
+    step: f64,
+    sample_loop: Option<(usize, usize)>,
+    /// 0-1
+    volume: f32,
+}
+
+impl Mixer {
+    fn new(rate: u32, samples: Vec<Vec<f32>>, panning: Vec<[f32;2]>, chips: usize) -> Self {
+        let opls = (0..chips)
+            .map(|_| {
+                let mut opl = Opl2::new(rate);
+                // Let instruments pick waveforms other than sine
+                opl.write(0x01, 0x20);
+                opl
+            })
+            .collect();
+
+        Self {
+            samples,
+            voices: vec![None; panning.len()],
+            panning,
+            opls,
+            rendering: Rendering { rate, frames: Vec::new() },
+        }
+    }
+
+    /// Starts `sample` from frame `start` on `voice`, playing `frequency`
+    /// sample frames a second at `volume` (0-64).
+    fn play_sample(&mut self, voice: usize, sample: usize, frequency: f64, sample_loop: Option<(usize, usize)>, start: usize, volume: u8) {
+        self.voices[voice] = Some(SampleVoice {
+            sample,
+            position: start as f64,
+            step: frequency / self.rendering.rate as f64,
+            sample_loop,
+            volume: volume.min(64) as f32 / 64.0,
+        });
+    }
+
+    fn set_volume(&mut self, voice: usize, volume: u8) {
+        if let Some(playing) = &mut self.voices[voice] {
+            playing.volume = volume.min(64) as f32 / 64.0;
+        }
+    }
+
+    fn set_frequency(&mut self, voice: usize, frequency: f64) {
+        if let Some(playing) = &mut self.voices[voice] {
+            playing.step = frequency / self.rendering.rate as f64;
+        }
+    }
+
+    fn stop(&mut self, voice: usize) {
+        self.voices[voice] = None;
+    }
+
+    /// Renders on to `seconds` from the start of the song.
+    fn render_until(&mut self, seconds: f64) {
+        let end = (seconds * self.rendering.rate as f64).round() as usize;
+        while self.rendering.frames.len() < end {
+            let mut frame = [0.0f32;2];
+            for (voice, panning) in self.voices.iter_mut().zip(&self.panning) {
+                let Some(playing) = voice else {
+                    continue;
+                };
+                match playing.next(&self.samples[playing.sample]) {
+                    Some(value) => {
+                        frame[0] += value * panning[0] * SAMPLE_GAIN;
+                        frame[1] += value * panning[1] * SAMPLE_GAIN;
+                    },
+                    None => *voice = None,
+                }
+            }
+
+            let fm = self.opls.iter_mut().map(|opl| opl.sample()).sum::<f32>() * FM_GAIN;
+            self.rendering.frames.push([frame[0] + fm, frame[1] + fm]);
+        }
+    }
+}
+
+impl SampleVoice {
+    /// The next output sample, interpolated between sample frames, or `None`
+    /// once a sample without a loop has played to its end.
+    fn next(&mut self, audio: &[f32]) -> Option<f32> {
+        let index = self.position as usize;
+        let end = self.sample_loop.map_or(audio.len(), |(_, end)| end.min(audio.len()));
+        if index >= end {
+            return None;
+        }
+
+        let following = match self.sample_loop {
+            Some((start, _)) if index + 1 >= end => start,
+            _ => index + 1,
+        };
+        let (current, following) = (audio[index], audio.get(following).copied().unwrap_or(0.0));
+        let value = current + (following - current) * self.position.fract() as f32;
+
+        self.position += self.step;
+        if let Some((start, _)) = self.sample_loop.filter(|(start, _)| *start < end) {
+            while self.position >= end as f64 {
+                self.position -= (end - start) as f64;
+            }
+        }
+
+        Some(value * self.volume)
+    }
+}
+
+/// Sets up OPL channel `channel` to play a patch, given as S3M's d00-d0a
+/// registers, at `volume` (0-64). Any note sounding is cut off.
+fn load_patch(opl: &mut Opl2, channel: usize, registers: &[u8;11], volume: u8) {
+    let modulator = MODULATOR_OFFSETS[channel];
+    let carrier = modulator + 3;
+
+    opl.write(0xB0 + channel as u8, 0);
+    for (base, index) in [(0x20, 0), (0x60, 4), (0x80, 6), (0xE0, 8)] {
+        opl.write(base + modulator, registers[index]);
+        opl.write(base + carrier, registers[index + 1]);
+    }
+    opl.write(0xC0 + channel as u8, registers[10]);
+    set_fm_volume(opl, channel, registers, volume);
+}
+
+/// Scales a patch's output levels to `volume` (0-64) the way ST3 does: the
+/// carrier is turned down, and the modulator too when both are heard.
+fn set_fm_volume(opl: &mut Opl2, channel: usize, registers: &[u8;11], volume: u8) {
+    let modulator = MODULATOR_OFFSETS[channel];
+    let scaled = |level: u8| {
+        let attenuation = 63 - (63 - (level & 0x3F) as u32) * volume.min(64) as u32 / 64;
+        (level & 0xC0) | attenuation as u8
+    };
+
+    let additive = registers[10] & 1 != 0;
+    opl.write(0x40 + modulator, if additive { scaled(registers[2]) } else { registers[2] });
+    opl.write(0x43 + modulator, scaled(registers[3]));
+}
+
+/// Plays `f_number` in `block` on an OPL channel, restarting its envelopes.
+fn key_on(opl: &mut Opl2, channel: usize, block: u8, f_number: u16) {
+    opl.write(0xB0 + channel as u8, 0);
+    set_f_number(opl, channel, block, f_number);
+}
+
+/// Moves the note held on an OPL channel to `f_number` in `block`, keying it
+/// on if it is not already.
+fn set_f_number(opl: &mut Opl2, channel: usize, block: u8, f_number: u16) {
+    opl.write(0xA0 + channel as u8, f_number as u8);
+    opl.write(0xB0 + channel as u8, 0x20 | ((block & 7) << 2) | (f_number >> 8) as u8);
+}
+
+/// Releases the note held on an OPL channel, which keeps sounding at
+/// `f_number` in `block` as it fades.
+fn key_off(opl: &mut Opl2, channel: usize, block: u8, f_number: u16) {
+    opl.write(0xB0 + channel as u8, ((block & 7) << 2) | (f_number >> 8) as u8);
+}
@@ -0,0 +1,80 @@
+use std::f64::consts::PI;
+
+/// Interpolation used when changing a sample's rate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ResampleQuality {
+    Nearest,
+    Linear,
+    /// Windowed sinc, band-limited when shrinking samples.
+    #[default]
+    Sinc,
+}
+
+/// Half the width of the sinc kernel, in input samples at unity ratio.
+const SINC_HALF_WIDTH: f64 = 16.0;
+
+/// Resamples `audio` to `ratio` output samples per input sample. Reads past
+/// the end of a loop wrap around to its start, so looped samples stay seamless.
+pub fn resample(audio: &[i16], ratio: f64, sample_loop: Option<(usize, usize)>, quality: ResampleQuality) -> Vec<i16> {
+    let length = (audio.len() as f64 * ratio).round() as usize;
+    let at = |index: isize| -> f64 {
+        let index = match sample_loop {
+            Some((start, end)) if index >= end as isize => {
+                start as isize + (index - end as isize) % (end - start) as isize
+            },
+            _ => index,
+        };
+        if index < 0 || index as usize >= audio.len() {
+            0.0
+        } else {
+            audio[index as usize] as f64
+        }
+    };
+
+    // Shrinking must filter out what the new rate can no longer carry
+    let cutoff = ratio.min(1.0);
+    let half_width = SINC_HALF_WIDTH / cutoff;
+
+    (0..length)
+        .map(|index| {
+            let position = index as f64 / ratio;
+            let value = match quality {
+                ResampleQuality::Nearest => at(position.round() as isize),
+                ResampleQuality::Linear => {
+                    let base = position.floor();
+                    let fraction = position - base;
+                    at(base as isize) * (1.0 - fraction) + at(base as isize + 1) * fraction
+                },
+                ResampleQuality::Sinc => {
+                    let first = (position - half_width).ceil() as isize;
+                    let last = (position + half_width).floor() as isize;
+                    (first..=last)
+                        .map(|source| {
+                            let distance = position - source as f64;
+                            at(source) * cutoff * sinc(cutoff * distance) * blackman(distance / half_width)
+                        })
+                        .sum()
+                },
+            };
+
+            value.round().clamp(i16::MIN as f64, i16::MAX as f64) as i16
+        })
+        .collect()
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+/// Blackman window over -1..1.
+fn blackman(x: f64) -> f64 {
+    if x.abs() >= 1.0 {
+        return 0.0;
+    }
+    let phase = PI * (x + 1.0);
+    0.42 - 0.5 * phase.cos() + 0.08 * (2.0 * phase).cos()
+}
@@ -0,0 +1,369 @@
+use std::{collections::HashMap, fmt};
+
+use crate::{format_c67::{s3m_volume, swap_key_scale_level, C67FMRegisters, C67Module, C67PatternCommand, Channel, PatternDecodeError, NO_LOOP, PCM_MIDDLE_C_OCTAVE, PCM_MIDDLE_C_RATE}, format_s3m::{S3MAdlibInstrument, S3MInstrument, S3MModule, S3MPattern, S3MRow, S3MSample, EFFECT_BREAK, EFFECT_JUMP, MAX_ORDERS, MAX_PATTERNS}};
+
+/// S3M channels 0-3 play the C67 PCM voices, 4-12 the FM voices.
+const FM_CHANNEL_BASE: usize = 4;
+/// ST3 tempo whose ticks match CDFM's player.
+const C67_TEMPO: u8 = 143;
+
+/// Builds an S3M that plays a CDFM song the same way. Every C67 row becomes
+/// an S3M row at the C67 speed and tempo 143, patterns longer than 64 rows
+/// are split across several S3M patterns, and the loop becomes a Bxx.
+pub fn c67_to_s3m(module: &C67Module) -> Result<S3MModule, ReverseConversionError> {
+    let header = &module.header;
+
+    let mut channel_settings = [0xFF;32];
+    // PCM voices alternate left and right, FM voices are A1-A9
+    channel_settings[..FM_CHANNEL_BASE].copy_from_slice(&[0, 8, 1, 9]);
+    for (index, setting) in channel_settings[FM_CHANNEL_BASE..FM_CHANNEL_BASE+9].iter_mut().enumerate() {
+        *setting = 16 + index as u8;
+    }
+
+    // PCM instruments come first, the FM ones follow the last used PCM slot
+    let pcm_amount = header.instrument_meta.iter()
+        .rposition(|meta| meta.sample_length != 0)
+        .map_or(0, |index| index + 1);
+    let fm_amount = header.adlib_instrument_meta.iter()
+        .rposition(|registers| *registers != C67FMRegisters::default())
+        .map_or(0, |index| index + 1);
+
+    let mut instruments: Vec<S3MInstrument> = Vec::new();
+    for (index, meta) in header.instrument_meta.iter().enumerate().take(pcm_amount) {
+        let filename = filename(&header.instrument_filenames, index);
+        if meta.sample_length == 0 {
+            instruments.push(S3MInstrument::Sample(S3MSample { filename, ..Default::default() }));
+            continue;
+        }
+
+        let audio: Vec<i16> = module.sample(index).iter().map(|x| (*x as i16 - 128) * 256).collect();
+        let looped = meta.loop_end != NO_LOOP;
+        instruments.push(S3MInstrument::Sample(S3MSample {
+            sample_type: 1,
+            filename,
+            length: audio.len() as u32,
+            loop_begin: if looped { meta.loop_start } else { 0 },
+            loop_end: if looped { meta.loop_end } else { 0 },
+            volume: 64,
+            flags: looped as u8,
+            c4speed: PCM_MIDDLE_C_RATE,
+            sample_name: sample_name(&filename),
+            audio,
+            ..Default::default()
+        }));
+    }
+    for (index, registers) in header.adlib_instrument_meta.iter().enumerate().take(fm_amount) {
+        let filename = filename(&header.adlib_instrument_filenames, index);
+        instruments.push(S3MInstrument::Adlib(adlib_instrument(registers, filename)));
+    }
+
+    // Patterns are converted once and shared by every order playing them
+    let mut patterns: Vec<S3MPattern> = Vec::new();
+    let mut converted: HashMap<u8, Vec<usize>> = HashMap::new();
+    let mut orders: Vec<usize> = Vec::new();
+    let mut order_starts: Vec<usize> = Vec::new();
+    for pattern in header.playlist.iter().take_while(|pattern| **pattern != 0xFF) {
+        if !converted.contains_key(pattern) {
+            let mut rows = expand_rows(&module.pattern_commands(*pattern as usize)?, pcm_amount as u8 + 1);
+            if rows.is_empty() {
+                rows.push(S3MRow::default());
+            }
+            let mut indices: Vec<usize> = Vec::new();
+            for chunk in rows.chunks(64) {
+                let mut s3m_pattern = [S3MRow::default();64];
+                s3m_pattern[..chunk.len()].copy_from_slice(chunk);
+                if chunk.len() < 64 {
+                    if let Some(col) = s3m_pattern[chunk.len() - 1].iter_mut().find(|col| col.effect == 0) {
+                        col.effect = EFFECT_BREAK;
+                    }
+                }
+                indices.push(patterns.len());
+                patterns.push(s3m_pattern);
+            }
+            converted.insert(*pattern, indices);
+        }
+
+        order_starts.push(orders.len());
+        orders.extend_from_slice(&converted[pattern]);
+    }
+
+    // ST3 restarts from the first order by itself, other loops need a jump
+    // at the end of a copy of the last pattern
+    let loop_order = header.loop_order as usize;
+    if loop_order != 0 && loop_order < order_starts.len() {
+        if let Some(last) = orders.last_mut() {
+            let mut s3m_pattern = patterns[*last];
+            // The jump replaces the pattern's break, or takes a free column
+            // of its last row
+            let last_row = s3m_pattern.iter()
+                .position(|row| row.iter().any(|col| col.effect == EFFECT_BREAK))
+                .unwrap_or(63);
+            let row = &mut s3m_pattern[last_row];
+            let column = row.iter().position(|col| col.effect == EFFECT_BREAK)
+                .or_else(|| row.iter().position(|col| col.effect == 0));
+            if let Some(column) = column {
+                row[column].effect = EFFECT_JUMP;
+                row[column].effect_value = order_starts[loop_order] as u8;
+            }
+
+            *last = patterns.len();
+            patterns.push(s3m_pattern);
+        }
+    }
+    // ST3 wants the order list ended by a marker and padded to an even length
+    let order_amount = (orders.len() + 2) & !1;
+    if order_amount > MAX_ORDERS || patterns.len() > MAX_PATTERNS {
+        return Err(ReverseConversionError::TooManyPatterns { patterns: patterns.len(), orders: order_amount });
+    }
+    let mut orders: Vec<u8> = orders.into_iter().map(|index| index as u8).collect();
+    orders.resize(order_amount, 0xFF);
+
+    Ok(S3MModule {
+        order_amount: orders.len() as u16,
+        sample_amount: instruments.len() as u16,
+        pattern_amount: patterns.len() as u16,
+        // Scream Tracker 3.20, unsigned samples
+        tracker_metadata: 0x1320,
+        ffi: 2,
+        _scrm: 0x4D524353,
+        global_volume: 64,
+        initial_speed: header.speed,
+        initial_tempo: C67_TEMPO,
+        // Stereo
+        mixing_volume: 0xB0,
+        channel_settings,
+        orders,
+        instruments,
+        patterns,
+        ..Default::default()
+    })
+}
+
+#[derive(Debug)]
+pub enum ReverseConversionError {
+    Pattern(PatternDecodeError),
+    /// The song splits into more S3M patterns or orders than ST3 can hold.
+    TooManyPatterns { patterns: usize, orders: usize },
+}
+
+impl fmt::Display for ReverseConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReverseConversionError::Pattern(error) => write!(f, "{}", error),
+            ReverseConversionError::TooManyPatterns { patterns, orders } => write!(
+                f,
+                "Song needs {} patterns and {} orders, more than ST3 can hold ({} and {})",
+                patterns, orders, MAX_PATTERNS, MAX_ORDERS
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ReverseConversionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ReverseConversionError::Pattern(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<PatternDecodeError> for ReverseConversionError {
+    fn from(error: PatternDecodeError) -> Self {
+        ReverseConversionError::Pattern(error)
+    }
+}
+
+/// Lays a C67 pattern out row by row. Of several notes on one voice in a
+/// row, the last one wins. FM instrument numbers start at `fm_instrument_base`.
+fn expand_rows(commands: &[C67PatternCommand], fm_instrument_base: u8) -> Vec<S3MRow> {
+    let mut rows: Vec<S3MRow> = Vec::new();
+    let mut row = S3MRow::default();
+
+    for command in commands {
+        match command {
+            C67PatternCommand::PlayNote(play_note) => {
+                let col = &mut row[s3m_channel(play_note.channel)];
+                let (octave, instrument) = match play_note.channel {
+                    Channel::PCM(_) => (play_note.octave + 4 - PCM_MIDDLE_C_OCTAVE, play_note.instrument + 1),
+                    Channel::FM(_) => (play_note.octave, play_note.instrument + fm_instrument_base),
+                };
+                col.note = (octave << 4) | play_note.note;
+                col.instrument = instrument;
+                col.vol = s3m_volume(play_note.channel, play_note.volume);
+            },
+            C67PatternCommand::SetVolume(set_volume) => {
+                row[s3m_channel(set_volume.channel)].vol = s3m_volume(set_volume.channel, set_volume.volume);
+            },
+            C67PatternCommand::Delay(count) => {
+                for _ in 0..*count {
+                    rows.push(std::mem::take(&mut row));
+                }
+            },
+            C67PatternCommand::End => break,
+        }
+    }
+
+    rows
+}
+
+fn s3m_channel(channel: Channel) -> usize {
+    match channel {
+        Channel::PCM(index) => index as usize,
+        Channel::FM(index) => FM_CHANNEL_BASE + index as usize,
+    }
+}
+
+fn adlib_instrument(registers: &C67FMRegisters, filename: [u8;12]) -> S3MAdlibInstrument {
+    S3MAdlibInstrument {
+        instrument_type: 2,
+        filename,
+        d00: registers.modulator_characteristics,
+        d01: registers.carrier_characteristics,
+        d02: swap_key_scale_level(registers.modulator_scale_and_output_level),
+        d03: swap_key_scale_level(registers.carrier_scale_and_output_level),
+        d04: registers.modulator_attack_decay_level,
+        d05: registers.carrier_attack_decay_level,
+        d06: registers.modulator_sustain_release_level,
+        d07: registers.carrier_sustain_release_level,
+        d08: registers.modulator_wave_select,
+        d09: registers.carrier_wave_select,
+        d0a: registers.feedback_connection,
+        volume: 64,
+        c4freq: 8363,
+        sample_name: sample_name(&filename),
+        _scri: *b"SCRI",
+        ..Default::default()
+    }
+}
+
+/// The 12 character name of an instrument in a C67 filename table.
+fn filename(table: &[u8], index: usize) -> [u8;12] {
+    table[index*13..index*13+12].try_into().unwrap()
+}
+
+fn sample_name(filename: &[u8;12]) -> [u8;28] {
+    let mut name = [0u8;28];
+    name[..12].copy_from_slice(filename);
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{convert, format_c67::{serialize_pattern, PlayNoteCommand}, format_s3m::S3MColumn, ConvertOptions};
+
+    /// Adds a pattern holding `commands` to `module`.
+    fn add_pattern(module: &mut C67Module, index: usize, commands: &[C67PatternCommand]) {
+        let data = serialize_pattern(commands);
+        module.header.pattern_pointers.list[index] = module.pattern_data.len() as u32;
+        module.header.pattern_lengths.list[index] = data.len() as u32;
+        module.pattern_data.extend_from_slice(&data);
+    }
+
+    /// Rows played through `orders` until the first pattern break or jump.
+    fn rows_played(module: &S3MModule) -> usize {
+        module.orders.iter()
+            .take_while(|pattern| **pattern != 0xFF)
+            .map(|pattern| {
+                module.patterns[*pattern as usize].iter()
+                    .position(|row| row.iter().any(|col| col.effect == EFFECT_BREAK || col.effect == EFFECT_JUMP))
+                    .map_or(64, |row| row + 1)
+            })
+            .sum()
+    }
+
+    #[test]
+    fn long_patterns_split_and_the_loop_jumps_back() {
+        let mut module = C67Module::default();
+        module.header.speed = 6;
+        module.header.loop_order = 1;
+        module.header.instrument_meta[0].sample_length = 10;
+        module.sample_data = vec![0x80;10];
+        module.header.adlib_instrument_meta[0].carrier_characteristics = 0x21;
+        module.header.playlist = [0xFF;256];
+        module.header.playlist[..2].copy_from_slice(&[0, 1]);
+        add_pattern(&mut module, 0, &[
+            C67PatternCommand::PlayNote(PlayNoteCommand { channel: Channel::PCM(0), octave: PCM_MIDDLE_C_OCTAVE, note: 0, instrument: 0, volume: 15 }),
+            C67PatternCommand::Delay(100),
+            C67PatternCommand::End,
+        ]);
+        add_pattern(&mut module, 1, &[
+            C67PatternCommand::PlayNote(PlayNoteCommand { channel: Channel::FM(0), octave: 4, note: 5, instrument: 0, volume: 15 }),
+            C67PatternCommand::Delay(10),
+            C67PatternCommand::End,
+        ]);
+
+        let converted = c67_to_s3m(&module).unwrap();
+        // C67 pattern 0 takes S3M patterns 0 and 1, pattern 1 plays from a
+        // copy of pattern 2 that jumps back to it
+        assert_eq!(converted.orders, vec![0, 1, 3, 0xFF]);
+        assert_eq!(converted.patterns.len(), 4);
+        assert_eq!(rows_played(&converted), 100 + 10);
+        assert_eq!((converted.initial_speed, converted.initial_tempo), (6, C67_TEMPO));
+
+        // PCM octaves move to where ST3 plays the same pitch, FM
+        // instruments follow the PCM ones
+        assert_eq!(converted.patterns[0][0][0], S3MColumn { note: 0x40, instrument: 1, vol: 64, ..Default::default() });
+        assert_eq!((converted.patterns[2][0][4].note, converted.patterns[2][0][4].instrument), (0x45, 2));
+
+        assert!(converted.patterns[0][63].iter().all(|col| col.effect == 0));
+        assert!(converted.patterns[1][35].iter().any(|col| col.effect == EFFECT_BREAK && col.effect_value == 0));
+        assert!(converted.patterns[2][9].iter().any(|col| col.effect == EFFECT_BREAK));
+        assert!(converted.patterns[3][9].iter().any(|col| col.effect == EFFECT_JUMP && col.effect_value == 2));
+        assert!(converted.patterns[3][9].iter().all(|col| col.effect != EFFECT_BREAK));
+    }
+
+    #[test]
+    fn songs_st3_cannot_hold_are_rejected() {
+        let mut module = C67Module::default();
+        module.header.speed = 6;
+        module.header.playlist = [0xFF;256];
+        for order in 0..128 {
+            module.header.playlist[order] = order as u8;
+            add_pattern(&mut module, order, &[C67PatternCommand::Delay(200), C67PatternCommand::End]);
+        }
+
+        assert!(matches!(
+            c67_to_s3m(&module),
+            Err(ReverseConversionError::TooManyPatterns { patterns: 512, orders: 514 })
+        ));
+    }
+
+    #[test]
+    fn s3m_to_c67_and_back_plays_the_same_orders() {
+        let mut patterns = vec![[S3MRow::default();64];2];
+        patterns[0][0][0] = S3MColumn { note: 0x40, instrument: 1, vol: 64, ..Default::default() };
+        patterns[1][16][0] = S3MColumn { note: 0x47, instrument: 1, vol: 64, ..Default::default() };
+        patterns[1][63][1] = S3MColumn { effect: EFFECT_JUMP, effect_value: 1, ..Default::default() };
+        let mut channel_settings = [0xFF;32];
+        channel_settings[..2].copy_from_slice(&[0, 8]);
+        let s3m = S3MModule {
+            initial_speed: 6,
+            initial_tempo: C67_TEMPO,
+            global_volume: 64,
+            channel_settings,
+            orders: vec![0, 1, 0xFF, 0xFF],
+            instruments: vec![S3MInstrument::Sample(S3MSample {
+                sample_type: 1,
+                length: 1000,
+                volume: 64,
+                c4speed: PCM_MIDDLE_C_RATE,
+                audio: vec![1000;1000],
+                ..Default::default()
+            })],
+            patterns,
+            ..Default::default()
+        };
+
+        let (c67, _) = convert(&s3m, &ConvertOptions::default());
+        assert_eq!((c67.header.speed, c67.header.loop_order), (6, 1));
+        let converted = c67_to_s3m(&c67).unwrap();
+
+        assert_eq!(converted.orders, vec![0, 2, 0xFF, 0xFF]);
+        assert_eq!(rows_played(&converted), 128);
+        assert!(converted.patterns[2][63].iter().any(|col| col.effect == EFFECT_JUMP && col.effect_value == 1));
+        assert_eq!(converted.patterns[0][0][0], S3MColumn { note: 0x40, instrument: 1, vol: 64, ..Default::default() });
+        assert_eq!(converted.patterns[1][16][0].note, 0x47);
+    }
+}
@@ -0,0 +1,123 @@
+use crate::{adlib_drums::Drum, timing::TempoMap};
+
+/// A song as it plays, independent of the format it is written to. Every
+/// input format loads as an S3M first, and playing that through resolves
+/// effects and effect memory into plain events on a tick clock. Writers only
+/// need to place those events.
+#[derive(Debug)]
+pub struct Song {
+    pub title: String,
+    /// Instruments, referred to by index from note events.
+    pub instruments: Vec<Instrument>,
+    /// Patterns in playing order, each played exactly once per pass.
+    pub orders: Vec<Order>,
+    pub patterns: Vec<Pattern>,
+    /// The order playback returns to once the last one has played.
+    pub loop_order: usize,
+    /// Wall-clock time of every tick from the start of the song.
+    pub tempo_map: TempoMap,
+}
+
+impl Song {
+    /// Ticks each order starts on, followed by the tick the song ends on.
+    pub fn order_ticks(&self) -> Vec<u32> {
+        let mut ticks = vec![0u32];
+        for order in &self.orders {
+            let length = self.patterns[order.pattern].length;
+            ticks.push(ticks.last().unwrap() + length);
+        }
+        ticks
+    }
+
+    /// Length of one pass through the song, in ticks.
+    pub fn length(&self) -> u32 {
+        *self.order_ticks().last().unwrap()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Order {
+    pub pattern: usize,
+    /// Order in the source song the pattern was played from, for reporting.
+    pub source_order: usize,
+    /// Pattern that order plays in the source song, for reporting.
+    pub source_pattern: usize,
+}
+
+/// Rows played from one order, as events on each channel.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Pattern {
+    /// Tick each row starts on, from the start of the pattern.
+    pub row_ticks: Vec<u32>,
+    pub length: u32,
+    /// Events of each source channel, ordered by tick.
+    pub channels: Vec<Vec<TimedEvent>>,
+}
+
+impl Pattern {
+    /// Events of all channels ordered by tick, then channel, with their channel.
+    pub fn events(&self) -> Vec<(usize, &TimedEvent)> {
+        let mut events: Vec<(usize, &TimedEvent)> = self.channels.iter()
+            .enumerate()
+            .flat_map(|(channel, events)| events.iter().map(move |event| (channel, event)))
+            .collect();
+        events.sort_by_key(|(channel, timed)| (timed.tick, *channel));
+        events
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimedEvent {
+    /// Tick the event happens on, from the start of the pattern.
+    pub tick: u32,
+    /// Source row the event comes from, for reporting.
+    pub row: usize,
+    pub event: Event,
+}
+
+/// What happens on a channel. Volumes run from 0 to 64 and pitches are
+/// semitones above C-0, where C-4 plays an instrument at its C4 rate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    Note {
+        instrument: usize,
+        semitone: i32,
+        volume: u8,
+    },
+    Volume(u8),
+    /// The sounding note moves to another pitch without restarting.
+    Pitch(i32),
+    /// Note off or note cut
+    Off,
+}
+
+#[derive(Debug, Clone)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum Instrument {
+    Sample(Sample),
+    FM(FMPatch),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Sample {
+    pub filename: [u8;12],
+    /// Volume notes play at unless given one, 0-64.
+    pub volume: u8,
+    /// Rate that plays the sample at C-4, in Hz.
+    pub c4speed: u32,
+    pub audio: Vec<i16>,
+    /// Loop start and end, if the sample loops.
+    pub sample_loop: Option<(usize, usize)>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FMPatch {
+    pub filename: [u8;12],
+    /// Volume notes play at unless given one, 0-64.
+    pub volume: u8,
+    /// OPL2 registers 0x20, 0x23, 0x40, 0x43, 0x60, 0x63, 0x80, 0x83, 0xE0,
+    /// 0xE3 and 0xC0, in that order.
+    pub registers: [u8;11],
+    /// Rhythm mode drum the patch is meant for, if any.
+    pub drum: Option<Drum>,
+}
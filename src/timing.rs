@@ -0,0 +1,195 @@
+use std::fmt;
+
+use crate::format_c67::TICKS_PER_SECOND;
+
+/// Largest difference between two candidate speeds' deviations that is still
+/// considered equal, so the coarser (smaller) encoding wins.
+const DEVIATION_TOLERANCE: f64 = 0.001;
+
+/// Maps source ticks to wall-clock time across tempo changes.
+#[derive(Debug)]
+pub struct TempoMap {
+    // (first tick, time of that tick in seconds, seconds per tick)
+    changes: Vec<(u32, f64, f64)>,
+}
+
+impl TempoMap {
+    pub fn new(tempo: u8) -> Self {
+        Self {
+            changes: vec![(0, 0.0, tick_length(tempo))],
+        }
+    }
+
+    /// Changes the tempo from `tick` onwards. Ticks must be passed in ascending order.
+    pub fn set_tempo(&mut self, tick: u32, tempo: u8) {
+        let time = self.seconds(tick);
+        let last = self.changes.last_mut().unwrap();
+        if last.0 == tick {
+            last.2 = tick_length(tempo);
+        } else {
+            self.changes.push((tick, time, tick_length(tempo)));
+        }
+    }
+
+    pub fn seconds(&self, tick: u32) -> f64 {
+        let index = self.changes.partition_point(|change| change.0 <= tick) - 1;
+        let (start, time, length) = self.changes[index];
+        time + (tick - start) as f64 * length
+    }
+}
+
+/// ST3 ticks last 2.5 / tempo seconds.
+fn tick_length(tempo: u8) -> f64 {
+    2.5 / tempo as f64
+}
+
+/// How source time is quantized to C67 rows.
+#[derive(Debug, Clone, Copy)]
+pub struct TimingPlan {
+    /// C67 speed, i.e. player ticks per C67 row.
+    pub speed: u8,
+    row_length: f64,
+}
+
+impl TimingPlan {
+    /// Picks the C67 speed that places every time in `boundaries` (row starts, in
+    /// seconds, ascending) closest to where it belongs, preferring the coarsest
+    /// speed among equally good ones. Speeds that would merge two boundaries
+    /// into one C67 row are never chosen.
+    pub fn choose(boundaries: &[f64]) -> Self {
+        let mut candidates: Vec<(TimingPlan, f64)> = Vec::new();
+
+        for speed in 1..=15u8 {
+            let plan = TimingPlan {
+                speed,
+                row_length: speed as f64 / TICKS_PER_SECOND,
+            };
+
+            let collapses = boundaries
+                .windows(2)
+                .any(|pair| pair[0] < pair[1] && plan.rows(pair[0]) == plan.rows(pair[1]));
+            if collapses {
+                continue;
+            }
+
+            candidates.push((plan, plan.max_deviation(boundaries)));
+        }
+
+        let best = candidates
+            .iter()
+            .map(|(_, deviation)| *deviation)
+            .fold(f64::INFINITY, f64::min);
+
+        candidates
+            .into_iter()
+            .filter(|(_, deviation)| *deviation <= best + DEVIATION_TOLERANCE)
+            .map(|(plan, _)| plan)
+            .next_back()
+            .unwrap_or(TimingPlan {
+                speed: 1,
+                row_length: 1.0 / TICKS_PER_SECOND,
+            })
+    }
+
+    /// Number of whole C67 rows closest to `seconds` from the start of the song.
+    pub fn rows(&self, seconds: f64) -> u32 {
+        (seconds / self.row_length).round() as u32
+    }
+
+    pub fn seconds(&self, rows: u32) -> f64 {
+        rows as f64 * self.row_length
+    }
+
+    pub fn max_deviation(&self, times: &[f64]) -> f64 {
+        times
+            .iter()
+            .map(|time| (self.seconds(self.rows(*time)) - time).abs())
+            .fold(0.0, f64::max)
+    }
+}
+
+/// How far the converted song's timing strays from the source.
+#[derive(Debug, Default, Clone, serde::Serialize)]
+pub struct TimingReport {
+    pub speed: u8,
+    /// Length of one pass through the song, in seconds.
+    pub source_duration: f64,
+    pub converted_duration: f64,
+    /// Largest distance between a source row and its C67 position, in seconds.
+    pub max_deviation: f64,
+}
+
+impl fmt::Display for TimingReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "C67 speed {}, song length {:.3}s (source {:.3}s), rows off by at most {:.1}ms",
+            self.speed,
+            self.converted_duration,
+            self.source_duration,
+            self.max_deviation * 1000.0
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Start of every row, in seconds, for rows of `speed` ticks played
+    /// at each (tempo, speed, rows) in turn.
+    fn row_starts(sections: &[(u8, u32, u32)]) -> Vec<f64> {
+        let mut tempo_map = TempoMap::new(sections[0].0);
+        let mut starts: Vec<f64> = Vec::new();
+        let mut tick = 0;
+        for (tempo, speed, rows) in sections {
+            tempo_map.set_tempo(tick, *tempo);
+            for _ in 0..*rows {
+                starts.push(tempo_map.seconds(tick));
+                tick += speed;
+            }
+        }
+        starts.push(tempo_map.seconds(tick));
+        starts
+    }
+
+    #[test]
+    fn tempo_map_follows_tempo_changes() {
+        let mut tempo_map = TempoMap::new(125);
+        tempo_map.set_tempo(12, 250);
+        assert!((tempo_map.seconds(12) - 0.24).abs() < 1e-9);
+        assert!((tempo_map.seconds(18) - 0.30).abs() < 1e-9);
+        // A second change on the same tick replaces the first
+        tempo_map.set_tempo(18, 125);
+        tempo_map.set_tempo(18, 50);
+        assert!((tempo_map.seconds(20) - 0.40).abs() < 1e-9);
+    }
+
+    #[test]
+    fn tempo_changes_that_fit_the_player_quantize_exactly() {
+        // Tempo 143 speed 4 rows last 4 player ticks, tempo 78 speed 12
+        // rows 22, so speed 2 places every row exactly
+        let starts = row_starts(&[(143, 4, 8), (78, 12, 8)]);
+        let plan = TimingPlan::choose(&starts);
+        assert_eq!(plan.speed, 2);
+        assert!(plan.max_deviation(&starts) < 1e-9);
+        assert_eq!(plan.rows(*starts.last().unwrap()), 8 * 2 + 8 * 11);
+    }
+
+    #[test]
+    fn tempo_changes_that_do_not_fit_stay_within_half_a_row() {
+        let starts = row_starts(&[(125, 6, 16), (150, 3, 16)]);
+        let plan = TimingPlan::choose(&starts);
+        let deviation = plan.max_deviation(&starts);
+        assert!(deviation > 0.0);
+        assert!(deviation <= plan.seconds(1) / 2.0);
+        // No two rows share a C67 row
+        assert!(starts.windows(2).all(|pair| plan.rows(pair[0]) < plan.rows(pair[1])));
+    }
+
+    #[test]
+    fn rows_too_close_for_any_speed_fall_back_to_speed_1() {
+        let plan = TimingPlan::choose(&[0.0, 0.001, 0.002]);
+        assert_eq!(plan.speed, 1);
+    }
+}
@@ -0,0 +1,266 @@
+use crate::format_c67::Channel;
+
+/// Decides which note wins when more notes sound than there are voices.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VoicePriority {
+    /// Notes on earlier S3M channels win.
+    #[default]
+    ChannelOrder,
+    /// Louder notes win.
+    Loudest,
+}
+
+/// Which voice a winning note takes over when none are free.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VoiceStealing {
+    /// Never cut a sounding note, drop the new one instead.
+    Never,
+    /// Cut the note that has been playing the longest.
+    #[default]
+    Oldest,
+    /// Cut the quietest note.
+    Quietest,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct VoiceAllocationOptions {
+    pub priority: VoicePriority,
+    pub stealing: VoiceStealing,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum VoicePool {
+    PCM,
+    FM,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Voice {
+    owner: Option<u8>,
+    last_owner: Option<u8>,
+    started: f64,
+    /// When the note stops by itself, e.g. a sample without a loop running out.
+    busy_until: f64,
+    volume: u8,
+}
+
+impl Voice {
+    fn is_free(&self, time: f64) -> bool {
+        self.owner.is_none() || time >= self.busy_until || self.volume == 0
+    }
+}
+
+/// Assigns S3M channels to the C67 PCM and FM voices note by note.
+/// Events must be fed in playing order.
+pub struct VoiceAllocator {
+    options: VoiceAllocationOptions,
+    pcm: [Voice;4],
+    fm: [Voice;9],
+    channel_voices: [Option<(VoicePool, usize)>;32],
+}
+
+impl VoiceAllocator {
+    pub fn new(options: VoiceAllocationOptions) -> Self {
+        let idle = Voice {
+            owner: None,
+            last_owner: None,
+            started: 0.0,
+            busy_until: 0.0,
+            volume: 0,
+        };
+
+        Self {
+            options,
+            pcm: [idle;4],
+            fm: [idle;9],
+            channel_voices: [None;32],
+        }
+    }
+
+    /// Finds a voice for a note starting on `channel` at `time` seconds,
+    /// returning `None` if the note has to be dropped. Along with the voice
+    /// comes the channel whose sounding note was cut off to free it, if any.
+    pub fn note_on(&mut self, pool: VoicePool, channel: u8, time: f64, busy_until: f64, volume: u8) -> Option<(Channel, Option<u8>)> {
+        let index = match self.channel_voices[channel as usize] {
+            // Keep playing on the channel's own voice
+            Some((current_pool, index)) if current_pool == pool => Some(index),
+            _ => self.free_voice(pool, channel, time)
+                .or_else(|| self.stolen_voice(pool, channel, volume)),
+        };
+        let Some(index) = index else {
+            self.release(channel);
+            return None;
+        };
+
+        if self.channel_voices[channel as usize] != Some((pool, index)) {
+            self.release(channel);
+        }
+        let voice = &mut self.voices_mut(pool)[index];
+        let stolen_from = voice.owner.filter(|owner| *owner != channel && !voice.is_free(time));
+        if let Some(owner) = voice.owner {
+            if owner != channel {
+                self.channel_voices[owner as usize] = None;
+            }
+        }
+
+        let voice = &mut self.voices_mut(pool)[index];
+        voice.owner = Some(channel);
+        voice.last_owner = Some(channel);
+        voice.started = time;
+        voice.busy_until = busy_until;
+        voice.volume = volume;
+        self.channel_voices[channel as usize] = Some((pool, index));
+
+        Some((voice_channel(pool, index), stolen_from))
+    }
+
+    /// The voice a channel is currently playing on.
+    pub fn voice(&self, channel: u8) -> Option<Channel> {
+        self.channel_voices[channel as usize].map(|(pool, index)| voice_channel(pool, index))
+    }
+
+    pub fn set_volume(&mut self, channel: u8, volume: u8) {
+        if let Some((pool, index)) = self.channel_voices[channel as usize] {
+            self.voices_mut(pool)[index].volume = volume;
+        }
+    }
+
+    /// Frees the channel's voice after a note off.
+    pub fn release(&mut self, channel: u8) {
+        if let Some((pool, index)) = self.channel_voices[channel as usize].take() {
+            self.voices_mut(pool)[index].owner = None;
+        }
+    }
+
+    fn voices_mut(&mut self, pool: VoicePool) -> &mut [Voice] {
+        match pool {
+            VoicePool::PCM => &mut self.pcm,
+            VoicePool::FM => &mut self.fm,
+        }
+    }
+
+    fn voices(&self, pool: VoicePool) -> &[Voice] {
+        match pool {
+            VoicePool::PCM => &self.pcm,
+            VoicePool::FM => &self.fm,
+        }
+    }
+
+    /// A silent voice, preferably one this channel used before, otherwise
+    /// the one that has been idle the longest.
+    fn free_voice(&self, pool: VoicePool, channel: u8, time: f64) -> Option<usize> {
+        let voices = self.voices(pool);
+        let free = || voices.iter().enumerate().filter(|(_, voice)| voice.is_free(time));
+
+        free()
+            .find(|(_, voice)| voice.last_owner == Some(channel))
+            .or_else(|| free().min_by(|a, b| a.1.started.total_cmp(&b.1.started)))
+            .map(|(index, _)| index)
+    }
+
+    /// A voice playing a note the new one outranks, picked by the stealing policy.
+    fn stolen_voice(&self, pool: VoicePool, channel: u8, volume: u8) -> Option<usize> {
+        let outranks = |voice: &Voice| match self.options.priority {
+            VoicePriority::ChannelOrder => voice.owner.is_some_and(|owner| owner > channel),
+            VoicePriority::Loudest => voice.volume <= volume,
+        };
+        let candidates = self.voices(pool).iter().enumerate().filter(|(_, voice)| outranks(voice));
+
+        let victim = match self.options.stealing {
+            VoiceStealing::Never => None,
+            VoiceStealing::Oldest => candidates.min_by(|a, b| a.1.started.total_cmp(&b.1.started)),
+            VoiceStealing::Quietest => candidates.min_by_key(|(_, voice)| voice.volume),
+        };
+
+        victim.map(|(index, _)| index)
+    }
+}
+
+fn voice_channel(pool: VoicePool, index: usize) -> Channel {
+    match pool {
+        VoicePool::PCM => Channel::PCM(index as u8),
+        VoicePool::FM => Channel::FM(index as u8),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Plays four long PCM notes on channels 4, 2, 6 and 1 at volumes 60,
+    /// 30, 40 and 10, one second apart, then a fifth at second 4.
+    fn fifth_note(priority: VoicePriority, stealing: VoiceStealing, channel: u8, volume: u8) -> (VoiceAllocator, Option<(Channel, Option<u8>)>) {
+        let mut allocator = VoiceAllocator::new(VoiceAllocationOptions { priority, stealing });
+        for (time, (channel, volume)) in [(4, 60), (2, 30), (6, 40), (1, 10)].into_iter().enumerate() {
+            let voice = allocator.note_on(VoicePool::PCM, channel, time as f64, 100.0, volume);
+            assert_eq!(voice, Some((Channel::PCM(time as u8), None)));
+        }
+        let voice = allocator.note_on(VoicePool::PCM, channel, 4.0, 100.0, volume);
+        (allocator, voice)
+    }
+
+    #[test]
+    fn channel_order_steals_from_later_channels() {
+        let (allocator, voice) = fifth_note(VoicePriority::ChannelOrder, VoiceStealing::Never, 3, 20);
+        assert_eq!(voice, None);
+        assert_eq!(allocator.voice(3), None);
+        assert_eq!(allocator.voice(4), Some(Channel::PCM(0)));
+
+        // Channels 4 and 6 come after 3, 4 started first and 6 is quieter
+        let (allocator, voice) = fifth_note(VoicePriority::ChannelOrder, VoiceStealing::Oldest, 3, 20);
+        assert_eq!(voice, Some((Channel::PCM(0), Some(4))));
+        assert_eq!(allocator.voice(4), None);
+        let (allocator, voice) = fifth_note(VoicePriority::ChannelOrder, VoiceStealing::Quietest, 3, 20);
+        assert_eq!(voice, Some((Channel::PCM(2), Some(6))));
+        assert_eq!(allocator.voice(6), None);
+
+        // Nothing comes after channel 7
+        let (_, voice) = fifth_note(VoicePriority::ChannelOrder, VoiceStealing::Oldest, 7, 64);
+        assert_eq!(voice, None);
+    }
+
+    #[test]
+    fn loudest_steals_from_quieter_notes() {
+        let (_, voice) = fifth_note(VoicePriority::Loudest, VoiceStealing::Never, 3, 35);
+        assert_eq!(voice, None);
+
+        // Channels 2 and 1 are at most as loud, 2 started first and 1 is quieter
+        let (allocator, voice) = fifth_note(VoicePriority::Loudest, VoiceStealing::Oldest, 3, 35);
+        assert_eq!(voice, Some((Channel::PCM(1), Some(2))));
+        assert_eq!(allocator.voice(2), None);
+        let (allocator, voice) = fifth_note(VoicePriority::Loudest, VoiceStealing::Quietest, 3, 35);
+        assert_eq!(voice, Some((Channel::PCM(3), Some(1))));
+        assert_eq!(allocator.voice(1), None);
+
+        // Quieter than everything playing
+        let (_, voice) = fifth_note(VoicePriority::Loudest, VoiceStealing::Quietest, 0, 5);
+        assert_eq!(voice, None);
+    }
+
+    #[test]
+    fn finished_and_released_notes_free_their_voice() {
+        let mut allocator = VoiceAllocator::new(VoiceAllocationOptions::default());
+        for channel in 0..4 {
+            allocator.note_on(VoicePool::PCM, channel, 0.0, 1.0, 64);
+        }
+        // The first notes have run out, the voice channel 2 had is reused
+        // without cutting anything off
+        assert_eq!(allocator.note_on(VoicePool::PCM, 9, 2.0, 3.0, 64), Some((Channel::PCM(0), None)));
+        assert_eq!(allocator.note_on(VoicePool::PCM, 2, 2.0, 3.0, 64), Some((Channel::PCM(2), None)));
+
+        allocator.release(2);
+        assert_eq!(allocator.voice(2), None);
+        assert_eq!(allocator.note_on(VoicePool::PCM, 10, 2.5, 3.0, 64), Some((Channel::PCM(1), None)));
+
+        // The last voice that ran out, then the released one
+        assert_eq!(allocator.note_on(VoicePool::PCM, 11, 2.6, 3.0, 64), Some((Channel::PCM(3), None)));
+        assert_eq!(allocator.note_on(VoicePool::PCM, 12, 2.6, 3.0, 64), Some((Channel::PCM(2), None)));
+
+        // Silenced notes don't hold on to their voice either
+        allocator.set_volume(10, 0);
+        assert_eq!(allocator.note_on(VoicePool::PCM, 13, 2.7, 3.0, 64), Some((Channel::PCM(1), None)));
+        // FM voices are a separate pool
+        assert_eq!(allocator.note_on(VoicePool::FM, 14, 2.7, 3.0, 64), Some((Channel::FM(0), None)));
+    }
+}